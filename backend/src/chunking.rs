@@ -0,0 +1,152 @@
+// documents are split into chunks of about this many characters, so a long document
+// is remembered as many entries that can match separately
+pub const CHUNK_SIZE: usize = 1000;
+// end of the previous chunk is repeated at the start of the next one, so text at a boundary is found in both
+pub const CHUNK_OVERLAP: usize = 200;
+
+fn is_heading(line: &str) -> bool {
+    let hashes = line.chars().take_while(|c| *c == '#').count();
+    (1..=6).contains(&hashes) && line[hashes..].starts_with(' ')
+}
+
+// byte index after at most max characters of text
+fn char_boundary(text: &str, max: usize) -> usize {
+    text.char_indices().nth(max).map(|(i, _)| i).unwrap_or(text.len())
+}
+
+// paragraphs longer than max are split at the last sentence end, line break or space before max
+fn split_long(paragraph: &str, max: usize) -> Vec<String> {
+    let mut pieces = Vec::new();
+    let mut rest = paragraph.trim();
+    while rest.chars().count() > max {
+        let window = &rest[..char_boundary(rest, max)];
+        let cut = [". ", "! ", "? ", "\n", " "].iter()
+            .filter_map(|separator| window.rfind(separator).map(|i| i + separator.len()))
+            .find(|i| *i > window.len() / 2)
+            .unwrap_or(window.len());
+        pieces.push(rest[..cut].trim().to_string());
+        rest = rest[cut..].trim_start();
+    }
+    if !rest.is_empty() {
+        pieces.push(rest.to_string());
+    }
+    pieces
+}
+
+// last characters of the chunk, starting at a word
+fn overlap(chunk: &str) -> &str {
+    let count = chunk.chars().count();
+    if count <= CHUNK_OVERLAP {
+        return chunk;
+    }
+    let tail = &chunk[char_boundary(chunk, count - CHUNK_OVERLAP)..];
+    match tail.find(char::is_whitespace) {
+        Some(i) => tail[i..].trim_start(),
+        None => tail,
+    }
+}
+
+// splits text at blank lines into paragraphs, and in markdown also into sections at headings.
+// paragraphs are joined into chunks of up to CHUNK_SIZE characters that overlap by CHUNK_OVERLAP,
+// chunks of a markdown section start with its heading and don't overlap with other sections
+pub fn split_document(text: &str, markdown: bool) -> Vec<String> {
+    let text = text.replace("\r\n", "\n");
+    let mut sections: Vec<(Option<String>, Vec<String>)> = vec![(None, Vec::new())];
+    let mut paragraph = String::new();
+    for line in text.lines() {
+        let heading = markdown && is_heading(line.trim_start());
+        if line.trim().is_empty() || heading {
+            if !paragraph.trim().is_empty() {
+                if let Some((_, paragraphs)) = sections.last_mut() {
+                    paragraphs.push(paragraph.trim().to_string());
+                }
+            }
+            paragraph.clear();
+        }
+        if heading {
+            sections.push((Some(line.trim().to_string()), Vec::new()));
+        } else if !line.trim().is_empty() {
+            paragraph.push_str(line);
+            paragraph.push('\n');
+        }
+    }
+    if !paragraph.trim().is_empty() {
+        if let Some((_, paragraphs)) = sections.last_mut() {
+            paragraphs.push(paragraph.trim().to_string());
+        }
+    }
+
+    let mut chunks: Vec<String> = Vec::new();
+    for (heading, paragraphs) in sections {
+        let prefix = heading.map(|h| format!("{}\n", h)).unwrap_or_default();
+        let size = CHUNK_SIZE.saturating_sub(prefix.chars().count()).max(CHUNK_OVERLAP * 2);
+        // a piece always fits after the overlap and the blank line
+        let pieces: Vec<String> = paragraphs.iter().flat_map(|p| split_long(p, size - CHUNK_OVERLAP - 2)).collect();
+        let mut chunk = String::new();
+        for piece in pieces {
+            if !chunk.is_empty() && chunk.chars().count() + piece.chars().count() + 2 > size {
+                chunks.push(format!("{}{}", prefix, chunk));
+                chunk = overlap(&chunk).to_string();
+            }
+            if !chunk.is_empty() {
+                chunk.push_str("\n\n");
+            }
+            chunk.push_str(&piece);
+        }
+        if !chunk.is_empty() {
+            chunks.push(format!("{}{}", prefix, chunk));
+        }
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_within_size(chunks: &[String]) {
+        for chunk in chunks {
+            assert!(chunk.chars().count() <= CHUNK_SIZE, "chunk of {} characters", chunk.chars().count());
+        }
+    }
+
+    #[test]
+    fn multibyte_text_without_spaces_is_cut_at_char_boundaries() {
+        let text = "żółć".repeat(750);
+        let chunks = split_document(&text, false);
+        assert!(chunks.len() > 1);
+        assert_within_size(&chunks);
+        assert!(chunks.iter().all(|c| c.chars().all(|ch| "żółć\n".contains(ch))));
+        // every chunk after the first starts with the end of the previous one
+        for pair in chunks.windows(2) {
+            assert!(pair[0].ends_with(pair[1].split("\n\n").next().unwrap()));
+        }
+    }
+
+    #[test]
+    fn multibyte_words_at_chunk_boundaries() {
+        let text = "zażółć gęślą jaźń 🙂 ".repeat(200);
+        let chunks = split_document(&text, false);
+        assert!(chunks.len() > 1);
+        assert_within_size(&chunks);
+        let words: usize = text.split_whitespace().count();
+        assert!(chunks.iter().map(|c| c.split_whitespace().count()).sum::<usize>() >= words);
+        assert!(chunks.iter().all(|c| c.split_whitespace().all(|w| ["zażółć", "gęślą", "jaźń", "🙂"].contains(&w))));
+    }
+
+    #[test]
+    fn markdown_sections_start_with_their_heading() {
+        let text = format!("intro\n\n# First\n{}\n\n## Second\nshort", "word ".repeat(400));
+        let chunks = split_document(&text, true);
+        assert_eq!(chunks[0], "intro");
+        assert!(chunks[1..chunks.len() - 1].iter().all(|c| c.starts_with("# First\n")));
+        assert_eq!(chunks.last().unwrap(), "## Second\nshort");
+        assert_within_size(&chunks);
+    }
+
+    #[test]
+    fn headings_are_plain_text_when_not_markdown() {
+        assert_eq!(split_document("# Title\ntext\r\n\r\nmore", false), vec!["# Title\ntext\n\nmore"]);
+        assert!(split_document(" \n\n ", true).is_empty());
+    }
+}
@@ -0,0 +1,255 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use chrono::Local;
+use rusqlite::{params, Connection, Error, Result};
+use serde::Serialize;
+
+use crate::database::{Database, UserView, get_current_date, io_error};
+use crate::llm::{count_tokens, generate, remember_embedding, template_format};
+use crate::long_term_mem::{LongTermMem, MemoryEntry, MemorySource};
+use crate::model_manager::ModelManager;
+
+// raw chat entries of the companion are summarized by the loaded model in batches,
+// into short facts that are saved as "summary" entries linked to the messages they came from
+
+const INSTRUCTION: &str = "Below is a part of a conversation between {{user}} and {{char}}. Write down the facts worth remembering from it for a long time: things about {{user}} and {{char}}, their relationship, plans, preferences and important events. Skip greetings and small talk. Write one short fact per line, starting with \"- \". If there is nothing worth remembering, write \"- nothing\".";
+
+// tokens kept free for the written facts
+const SUMMARY_BUDGET: usize = 512;
+
+pub struct ConsolidationOptions {
+    // only entries older than this are summarized
+    pub older_than_days: Option<u32>,
+    // maximum number of chat entries summarized together
+    pub batch_size: Option<usize>,
+    // raw chat entries are removed after their facts are saved
+    pub prune: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub struct ConsolidationResult {
+    pub batches: usize,
+    // new summary entries
+    pub facts: usize,
+    // chat entries that were summarized
+    pub consolidated: usize,
+    // chat entries that were removed
+    pub pruned: usize,
+    // chat entries left to summarize, when cancelled
+    pub remaining: usize,
+}
+
+// messages of every summarized batch, also of batches the model found nothing worth remembering in,
+// so they are not sent to the model again while their chat entries are kept
+pub struct ConsolidatedMessages { }
+
+impl ConsolidatedMessages {
+    pub fn create() -> Result<usize, Error> {
+        let con = Connection::open("companion_database.db")?;
+        con.execute("CREATE TABLE IF NOT EXISTS consolidated_messages (
+            message_id INTEGER PRIMARY KEY,
+            companion_id INTEGER,
+            consolidated_at TEXT
+        )", [])
+    }
+
+    pub fn add(companion_id: i32, message_ids: &[u64]) -> Result<(), Error> {
+        let mut con = Connection::open("companion_database.db")?;
+        let transaction = con.transaction()?;
+        let date = get_current_date();
+        for id in message_ids {
+            transaction.execute(
+                "INSERT OR IGNORE INTO consolidated_messages (message_id, companion_id, consolidated_at) VALUES (?, ?, ?)",
+                params![*id as i64, companion_id, date],
+            )?;
+        }
+        transaction.commit()
+    }
+
+    pub fn get_ids(companion_id: i32) -> Result<HashSet<u64>, Error> {
+        let con = Connection::open("companion_database.db")?;
+        let mut stmt = con.prepare("SELECT message_id FROM consolidated_messages WHERE companion_id = ?")?;
+        let rows = stmt.query_map([companion_id], |row| row.get::<_, i64>(0).map(|id| id as u64))?;
+        rows.collect()
+    }
+
+    pub fn delete_companion(companion_id: i32) -> Result<usize, Error> {
+        let con = Connection::open("companion_database.db")?;
+        con.execute("DELETE FROM consolidated_messages WHERE companion_id = ?", [companion_id])
+    }
+}
+
+// only whole words are replaced, so a companion called "Al" doesn't change "Also"
+fn replace_word(text: &str, word: &str, replacement: &str) -> String {
+    let is_word_char = |c: Option<char>| c.is_some_and(|c| c.is_alphanumeric() || c == '_');
+    let mut replaced = String::with_capacity(text.len());
+    let mut last = 0;
+    for (pos, _) in text.match_indices(word) {
+        let end = pos + word.len();
+        if is_word_char(text[..pos].chars().next_back()) || is_word_char(text[end..].chars().next()) {
+            continue;
+        }
+        replaced.push_str(&text[last..pos]);
+        replaced.push_str(replacement);
+        last = end;
+    }
+    replaced.push_str(&text[last..]);
+    replaced
+}
+
+// names are put back as placeholders like in other entries, so facts stay right when a name is changed
+pub fn with_placeholders(text: &str, user_name: &str, companion_name: &str) -> String {
+    let mut text = text.to_string();
+    for (name, placeholder) in [(companion_name, "{{char}}"), (user_name, "{{user}}")] {
+        if !name.is_empty() {
+            text = replace_word(&text, name, placeholder);
+        }
+    }
+    text
+}
+
+// facts are lines starting with a list marker.
+// None if the model didn't write a list, an empty list if it found nothing worth remembering
+fn parse_facts(text: &str, user: &UserView, companion_name: &str) -> Option<Vec<String>> {
+    let items: Vec<&str> = text.lines()
+        .map(|line| line.trim())
+        .filter_map(|line| line.strip_prefix("- ").or_else(|| line.strip_prefix("* ")).or_else(|| line.strip_prefix("• ")))
+        .map(|fact| fact.trim())
+        .filter(|fact| !fact.is_empty())
+        .collect();
+    if items.is_empty() {
+        return None;
+    }
+    Some(items.into_iter()
+        .filter(|fact| !fact.trim_end_matches('.').eq_ignore_ascii_case("nothing"))
+        .map(|fact| with_placeholders(fact, &user.name, companion_name))
+        .collect())
+}
+
+pub fn consolidate_memory(companion_id: i32, options: ConsolidationOptions, model_manager: &ModelManager, cancel: &AtomicBool) -> Result<ConsolidationResult, std::io::Error> {
+    let older_than_days = options.older_than_days.unwrap_or(7);
+    let batch_size = options.batch_size.unwrap_or(10).clamp(1, 50);
+    let prune = options.prune.unwrap_or(false);
+    let mut config = Database::get_config().map_err(io_error)?;
+    let user = Database::get_user_data().map_err(io_error)?;
+    let companion = Database::get_companion(companion_id).map_err(io_error)?;
+    let template = template_format(&config).map_err(io_error)?;
+    let llama = model_manager.get_for_config(&config)?;
+    let long_term_memory = LongTermMem::connect().map_err(io_error)?;
+
+    // entries that are linked to a message and not summarized yet, oldest first.
+    // summary entries made before batches were recorded still mark their messages as summarized
+    let cutoff = Local::now().timestamp() - older_than_days as i64 * 86400;
+    let mut summarized = long_term_memory.summarized_message_ids(companion_id).map_err(io_error)?;
+    summarized.extend(ConsolidatedMessages::get_ids(companion_id).map_err(io_error)?);
+    let mut pending: Vec<MemoryEntry> = long_term_memory.export_entries(companion_id).map_err(io_error)?
+        .into_iter()
+        .filter(|e| e.source == MemorySource::Chat.name())
+        .filter(|e| e.date.is_some_and(|date| date < cutoff))
+        .filter(|e| e.message_id.is_some_and(|id| !summarized.contains(&id)))
+        .collect();
+    pending.sort_by_key(|e| e.date);
+    if !pending.is_empty() {
+        println!("Consolidating {} long-term memory entries", pending.len());
+    }
+
+    if config.max_new_tokens == 0 || config.max_new_tokens > SUMMARY_BUDGET {
+        config.max_new_tokens = SUMMARY_BUDGET;
+    }
+    let system = template.render_system(&INSTRUCTION.replace("{{user}}", &user.name).replace("{{char}}", &companion.name), &user.name, &companion.name);
+    let prefix = template.generation_prefix(&user.name, &companion.name);
+    let stop_sequences = template.stop_sequences(&user.name, &companion.name);
+    let max_prompt_tokens = config.context_size.saturating_sub(SUMMARY_BUDGET);
+
+    let mut result = ConsolidationResult { batches: 0, facts: 0, consolidated: 0, pruned: 0, remaining: 0 };
+    let mut rest: &[MemoryEntry] = &pending;
+    while !rest.is_empty() {
+        if cancel.load(Ordering::Relaxed) {
+            break;
+        }
+        // batch grows while the prompt fits in context, at least one entry is always taken
+        let mut count = 0;
+        let mut conversation = String::new();
+        while count < batch_size.min(rest.len()) {
+            let text = rest[count].text.replace("{{user}}", &user.name).replace("{{char}}", &companion.name);
+            let longer = format!("{}{}\n", conversation, text.trim_end());
+            let prompt_text = format!("{}{}{}", system, template.render_message(false, &longer, &user.name, &companion.name), prefix);
+            if count > 0 && count_tokens(llama.as_ref(), &prompt_text) > max_prompt_tokens {
+                break;
+            }
+            conversation = longer;
+            count += 1;
+        }
+        let (batch, next) = rest.split_at(count);
+        let prompt_text = format!("{}{}{}", system, template.render_message(false, &conversation, &user.name, &companion.name), prefix);
+        let generation = generate(llama.as_ref(), &config, &prompt_text, &stop_sequences, cancel, |_| {})?;
+        if generation.cancelled {
+            break;
+        }
+        rest = next;
+        result.batches += 1;
+        // entries are kept when the response can't be read, nothing from them would be remembered otherwise
+        let facts = match parse_facts(&generation.text, &user, &companion.name) {
+            Some(facts) => facts,
+            None => {
+                eprintln!("Consolidation response has no facts, {} entries are kept: {}", batch.len(), generation.text);
+                continue;
+            }
+        };
+        let source_message_ids: Vec<u64> = batch.iter().filter_map(|e| e.message_id).collect();
+        let date = batch.iter().filter_map(|e| e.date).max().unwrap_or(cutoff);
+        let fact_ids = long_term_memory.add_summary(companion_id, date, &facts, &source_message_ids).map_err(io_error)?;
+        for (id, fact) in fact_ids.iter().zip(&facts) {
+            remember_embedding(llama.as_ref(), &config, id, companion_id, fact);
+        }
+        ConsolidatedMessages::add(companion_id, &source_message_ids).map_err(io_error)?;
+        result.facts += facts.len();
+        result.consolidated += batch.len();
+        // with prune, batches with nothing worth remembering are removed too, that small talk is what consolidation removes
+        if prune {
+            let ids: Vec<String> = batch.iter().map(|e| e.id.clone()).collect();
+            long_term_memory.delete_entries(&ids).map_err(io_error)?;
+            result.pruned += ids.len();
+        }
+    }
+    result.remaining = rest.len();
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn with_placeholders_replaces_whole_names_only() {
+        assert_eq!(with_placeholders("Also, Al told Ann about Alan", "Ann", "Al"), "Also, {{char}} told {{user}} about Alan");
+        assert_eq!(with_placeholders("Al's cat likes Ann.", "Ann", "Al"), "{{char}}'s cat likes {{user}}.");
+        assert_eq!(with_placeholders("Zoë met Zoëlla", "Zoë", "Bot"), "{{user}} met Zoëlla");
+    }
+
+    fn user() -> UserView {
+        UserView { name: "Ann".to_string(), persona: String::new() }
+    }
+
+    #[test]
+    fn nothing_is_an_empty_list() {
+        assert_eq!(parse_facts("- nothing", &user(), "Bot"), Some(Vec::new()));
+        assert_eq!(parse_facts("Facts:\n- Nothing.\n", &user(), "Bot"), Some(Vec::new()));
+    }
+
+    #[test]
+    fn text_without_list_is_not_parsed() {
+        assert_eq!(parse_facts("Ann likes tea and Bot likes coffee", &user(), "Bot"), None);
+        assert_eq!(parse_facts("-\n- \n", &user(), "Bot"), None);
+    }
+
+    #[test]
+    fn facts_are_read_from_list_items_with_placeholders() {
+        let text = "Here are the facts:\n- Ann likes tea\n  * Bot met Ann in 2020\n• Ann has a cat\n- nothing";
+        assert_eq!(parse_facts(text, &user(), "Bot"), Some(vec![
+            "{{user}} likes tea".to_string(),
+            "{{char}} met {{user}} in 2020".to_string(),
+            "{{user}} has a cat".to_string(),
+        ]));
+    }
+}
@@ -0,0 +1,272 @@
+use serde::Serialize;
+
+use crate::prompt_template::{SystemParts, TemplateFormat};
+
+// parts of a prompt before they are formatted with a template, names are already substituted
+pub struct PromptParts {
+    pub user_name: String,
+    pub companion_name: String,
+    // personas of the user and the companion, never dropped
+    pub user_persona: String,
+    pub companion_persona: String,
+    pub roleplay: bool,
+    // what the companion knows about the user, never dropped
+    pub known_facts: String,
+    pub example_dialogue: String,
+    pub tuned_dialogue: String,
+    // long-term memory entries, most relevant first
+    pub memories: Vec<String>,
+    // (ai, text), oldest first, the last message is never dropped
+    pub messages: Vec<(bool, String)>,
+    // current date noted at the last message, when it asks about time
+    pub current_date: Option<String>,
+    // generated turn is written as the user instead of the companion
+    pub reply_as_user: bool,
+    // beginning of the generated turn, the model continues it
+    pub prefill: String,
+}
+
+impl PromptParts {
+    fn system(&self) -> SystemParts<'_> {
+        SystemParts {
+            user_persona: &self.user_persona,
+            companion_persona: &self.companion_persona,
+            roleplay: self.roleplay,
+            known_facts: &self.known_facts,
+            example_dialogue: &self.example_dialogue,
+            tuned_dialogue: &self.tuned_dialogue,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct PromptSection {
+    // "system", "memory", "message" or "prefix"
+    pub kind: &'static str,
+    pub text: String,
+    pub tokens: usize,
+}
+
+// what had to be left out of the prompt to fit in the context
+#[derive(Serialize, Default)]
+pub struct ContextReport {
+    pub context_size: usize,
+    pub response_budget: usize,
+    pub prompt_tokens: usize,
+    pub dropped_messages: usize,
+    pub dropped_memories: usize,
+    pub dropped_tuning: bool,
+    pub truncated_example_dialogue: bool,
+    // false if the prompt is too long even after dropping everything that can be dropped
+    pub fits: bool,
+}
+
+impl ContextReport {
+    pub fn trimmed(&self) -> bool {
+        self.dropped_messages > 0 || self.dropped_memories > 0 || self.dropped_tuning || self.truncated_example_dialogue
+    }
+}
+
+pub struct AssembledPrompt {
+    pub text: String,
+    pub sections: Vec<PromptSection>,
+    pub stop_sequences: Vec<String>,
+    pub report: ContextReport,
+}
+
+// formats the parts with template and drops the lowest priority parts until the prompt
+// and response budget fit in the context: oldest messages first, then least relevant memories,
+// then tuning dialogue and at last lines from the end of example dialogue
+pub fn assemble(template: &TemplateFormat, mut parts: PromptParts, context_size: usize, response_budget: usize, count_tokens: impl Fn(&str) -> usize) -> AssembledPrompt {
+    let user_name = parts.user_name.clone();
+    let companion_name = parts.companion_name.clone();
+    let budget = context_size.saturating_sub(response_budget);
+    let turn_prefix = if parts.reply_as_user {
+        template.user_prefix(&user_name, &companion_name)
+    } else {
+        template.generation_prefix(&user_name, &companion_name)
+    };
+    let prefix = format!("{}{}", turn_prefix, parts.prefill);
+    // one more token for bos
+    let prefix_tokens = count_tokens(&prefix) + 1;
+    let mut system = template.render_companion_system(&parts.system(), &user_name, &companion_name);
+    let mut system_tokens = count_tokens(&system);
+    let mut memories: Vec<(String, usize)> = parts.memories.iter()
+        .map(|m| template.render_memory(m, &user_name, &companion_name))
+        .map(|m| { let tokens = count_tokens(&m); (m, tokens) })
+        .collect();
+    let last = parts.messages.len().saturating_sub(1);
+    let mut messages: Vec<(String, usize)> = parts.messages.iter().enumerate()
+        .map(|(i, (ai, text))| match &parts.current_date {
+            Some(date) if i == last => template.render_message_at(*ai, text, date, &user_name, &companion_name),
+            _ => template.render_message(*ai, text, &user_name, &companion_name),
+        })
+        .map(|m| { let tokens = count_tokens(&m); (m, tokens) })
+        .collect();
+
+    let mut report = ContextReport { context_size, response_budget, ..Default::default() };
+    loop {
+        let total = prefix_tokens + system_tokens
+            + memories.iter().map(|(_, t)| t).sum::<usize>()
+            + messages.iter().map(|(_, t)| t).sum::<usize>();
+        report.prompt_tokens = total;
+        report.fits = total <= budget;
+        if report.fits {
+            break;
+        }
+        if messages.len() > 1 {
+            messages.remove(0);
+            report.dropped_messages += 1;
+        } else if !memories.is_empty() {
+            memories.pop();
+            report.dropped_memories += 1;
+        } else if !parts.tuned_dialogue.is_empty() {
+            parts.tuned_dialogue.clear();
+            report.dropped_tuning = true;
+        } else if !parts.example_dialogue.is_empty() {
+            match parts.example_dialogue.rfind('\n') {
+                Some(i) => parts.example_dialogue.truncate(i),
+                None => parts.example_dialogue.clear(),
+            }
+            report.truncated_example_dialogue = true;
+        } else {
+            break;
+        }
+        if report.dropped_tuning || report.truncated_example_dialogue {
+            system = template.render_companion_system(&parts.system(), &user_name, &companion_name);
+            system_tokens = count_tokens(&system);
+        }
+    }
+
+    let mut sections = vec![PromptSection { kind: "system", text: system, tokens: system_tokens }];
+    sections.extend(memories.into_iter().map(|(text, tokens)| PromptSection { kind: "memory", text, tokens }));
+    sections.extend(messages.into_iter().map(|(text, tokens)| PromptSection { kind: "message", text, tokens }));
+    sections.push(PromptSection { kind: "prefix", text: prefix, tokens: prefix_tokens });
+    AssembledPrompt {
+        text: sections.iter().map(|s| s.text.as_str()).collect(),
+        sections,
+        stop_sequences: template.stop_sequences(&user_name, &companion_name),
+        report,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prompt_template::PRESETS;
+
+    const ROLEPLAY: &str = "gestures and other non-verbal actions are written between asterisks (for example, *waves hello* or *moves closer*)";
+    const DATE: &str = "Monday 01.01.2024 10:00";
+
+    fn preset(name: &str) -> TemplateFormat {
+        PRESETS.iter().find(|p| p.name == name).unwrap().format()
+    }
+
+    fn parts() -> PromptParts {
+        PromptParts {
+            user_name: "Ann".to_string(),
+            companion_name: "Bot".to_string(),
+            user_persona: "Ann likes tea".to_string(),
+            companion_persona: "Bot is a helpful bot".to_string(),
+            roleplay: true,
+            known_facts: String::new(),
+            example_dialogue: "Ann: hi\nBot: hello".to_string(),
+            tuned_dialogue: "Ann: how are you?\nBot: fine".to_string(),
+            memories: vec!["* at Sunday 31.12.2023 09:00 *\nAnn: tea?\nBot: sure".to_string()],
+            messages: vec![(false, "hello".to_string()), (true, "hi there".to_string()), (false, "what time is it?".to_string())],
+            current_date: Some(DATE.to_string()),
+            reply_as_user: false,
+            prefill: String::new(),
+        }
+    }
+
+    fn text_of(template: &str, parts: PromptParts) -> String {
+        assemble(&preset(template), parts, 100_000, 0, |text| text.len()).text
+    }
+
+    // prompts built the same way as before prompt templates were stored in database
+    #[test]
+    fn default_preset_matches_original_prompt() {
+        let expected = format!("Text transcript of a conversation between {} and {}. {}\n{}'s Persona: {}\n{}'s Persona: {}\n<START>\n{}\n<START>\n{}\n<START>\n",
+            "Ann", "Bot", ROLEPLAY, "Ann", "Ann likes tea", "Bot", "Bot is a helpful bot", "Ann: hi\nBot: hello", "Ann: how are you?\nBot: fine")
+            + "* at Sunday 31.12.2023 09:00 *\nAnn: tea?\nBot: sure\n"
+            + "Ann: hello\n"
+            + "Bot: hi there\n"
+            + &format!("\n* it's currently {} *\n{}", DATE, "Ann: what time is it?\n")
+            + "Bot: ";
+        assert_eq!(text_of("Default", parts()), expected);
+    }
+
+    #[test]
+    fn llama2_preset_matches_original_prompt() {
+        let expected = format!("<<SYS>>\nYou are {}, {}\nyou are talking with {}, {} is {}\n{}\n[INST]\n{}\n{}\n[/INST]",
+            "Bot", "Bot is a helpful bot", "Ann", "Ann", "Ann likes tea", ROLEPLAY, "Ann: hi\nBot: hello", "Ann: how are you?\nBot: fine")
+            + &format!("[INST]{}[/INST]\n", "* at Sunday 31.12.2023 09:00 *\nAnn: tea?\nBot: sure\n")
+            + &format!("[INST]{}", "Ann: hello\n")
+            + &format!("{}[/INST]\n", "Bot: hi there\n")
+            + &format!("[INST]\n* it's currently {} *\n{}", DATE, "Ann: what time is it?\n")
+            + "Bot: ";
+        assert_eq!(text_of("Llama2", parts()), expected);
+    }
+
+    #[test]
+    fn templates_without_time_placeholder_note_the_date_in_the_message() {
+        let text = text_of("ChatML", parts());
+        assert!(text.contains(&format!("<|im_start|>user\n* it's currently {} *\nwhat time is it?<|im_end|>", DATE)));
+    }
+
+    fn report_of(parts: PromptParts, context_size: usize) -> ContextReport {
+        assemble(&preset("ChatML"), parts, context_size, 0, |text| text.len()).report
+    }
+
+    #[test]
+    fn oldest_message_is_dropped_first() {
+        let full = report_of(parts(), 100_000).prompt_tokens;
+        let assembled = assemble(&preset("ChatML"), parts(), full - 1, 0, |text| text.len());
+        assert_eq!(assembled.report.dropped_messages, 1);
+        assert_eq!(assembled.report.dropped_memories, 0);
+        assert!(!assembled.text.contains("<|im_start|>user\nhello<|im_end|>"));
+        assert!(assembled.text.contains("<|im_start|>assistant\nhi there<|im_end|>"));
+    }
+
+    #[test]
+    fn memories_are_dropped_after_all_messages_but_the_last() {
+        let template = preset("ChatML");
+        let full = report_of(parts(), 100_000).prompt_tokens;
+        let old_messages: usize = parts().messages[..2].iter()
+            .map(|(ai, text)| template.render_message(*ai, text, "Ann", "Bot").len())
+            .sum();
+        let report = report_of(parts(), full - old_messages - 1);
+        assert_eq!(report.dropped_messages, 2);
+        assert_eq!(report.dropped_memories, 1);
+        assert!(!report.dropped_tuning);
+        assert!(!report.truncated_example_dialogue);
+        assert!(report.fits);
+    }
+
+    #[test]
+    fn tuning_is_dropped_before_example_dialogue_is_truncated() {
+        let assembled = assemble(&preset("ChatML"), parts(), 0, 0, |text| text.len());
+        let report = assembled.report;
+        assert_eq!((report.dropped_messages, report.dropped_memories), (2, 1));
+        assert!(report.dropped_tuning);
+        assert!(report.truncated_example_dialogue);
+        assert!(!report.fits);
+        // the last message stays even if the prompt doesn't fit
+        assert!(assembled.text.contains("what time is it?"));
+        assert!(!assembled.text.contains("Ann: hi"));
+    }
+
+    #[test]
+    fn empty_history_only_trims_the_system_part() {
+        let empty = || PromptParts { memories: Vec::new(), messages: Vec::new(), current_date: None, ..parts() };
+        let assembled = assemble(&preset("ChatML"), empty(), 100_000, 0, |text| text.len());
+        assert!(assembled.report.fits);
+        assert!(!assembled.report.trimmed());
+        assert!(assembled.text.ends_with("<|im_start|>assistant\n"));
+        let report = report_of(empty(), 0);
+        assert_eq!((report.dropped_messages, report.dropped_memories), (0, 0));
+        assert!(report.dropped_tuning && report.truncated_example_dialogue);
+        assert!(!report.fits);
+    }
+}
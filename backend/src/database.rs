@@ -1,18 +1,70 @@
 
-use rusqlite::{Connection, Error, Result, ToSql};
+use rusqlite::{Connection, Error, OptionalExtension, Result, ToSql};
 use rusqlite::types::{FromSql, FromSqlError, ValueRef, ToSqlOutput};
 use serde::{Serialize, Deserialize};
-use chrono::{DateTime, Local};
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
 
 use crate::character_card::CharacterCard;
+use crate::prompt_template::{TemplateFormat, PRESETS};
 
 
 #[derive(Serialize, Deserialize)]
 pub struct Message {
     pub id: i32,
     pub ai: bool,
+    // text of the selected alternative
     pub content: String,
     pub created_at: String,
+    pub selected_alternative: usize,
+    // number of alternatives, at least 1
+    pub alternatives: usize,
+    // message this one replies to, messages with the same parent are branches of the conversation
+    pub parent_id: Option<i32>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Branch {
+    // last message of the branch
+    pub message_id: i32,
+    pub content: String,
+    pub created_at: String,
+    // number of messages from the first message of the conversation
+    pub length: usize,
+    pub active: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MessageAlternative {
+    pub index: usize,
+    pub content: String,
+    pub created_at: String,
+    pub selected: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Conversation {
+    pub id: i32,
+    pub companion_id: i32,
+    pub title: String,
+    pub archived: bool,
+    pub created_at: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct NewConversation {
+    pub companion_id: Option<i32>,
+    pub title: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ConversationModify {
+    pub title: Option<String>,
+    pub archived: Option<bool>,
+}
+
+// errors of sqlite, tantivy and others for functions that return io errors
+pub fn io_error(e: impl std::fmt::Display) -> std::io::Error {
+    std::io::Error::other(e.to_string())
 }
 
 pub fn get_current_date() -> String {
@@ -20,6 +72,12 @@ pub fn get_current_date() -> String {
     local.format("%A %d.%m.%Y %H:%M").to_string()
 }
 
+// reads a date written by get_current_date
+pub fn parse_date(text: &str) -> Option<DateTime<Local>> {
+    let naive = NaiveDateTime::parse_from_str(text.trim(), "%A %d.%m.%Y %H:%M").ok()?;
+    Local.from_local_datetime(&naive).earliest()
+}
+
 pub fn contains_time_question(text: &str) -> bool {
     let time_related_keywords = ["time", "date", "hour", "day", "month", "year", "minute", "second", "morning", "afternoon", "evening", "night"];
     for keyword in &time_related_keywords {
@@ -48,6 +106,11 @@ pub struct Companion {
     pub roleplay: bool,
     pub dialogue_tuning: bool,
     pub avatar_path: String,
+    pub active: bool,
+    // long-term memory entries scoring lower are not used in prompts, 0 uses all matches
+    pub memory_min_score: f32,
+    // score of an entry is halved every this many days since its date, 0 turns decay off
+    pub memory_half_life_days: f32,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -61,13 +124,28 @@ pub struct CompanionView {
     pub roleplay: bool,
     pub dialogue_tuning: bool,
     pub avatar_path: String,
+    // optional, fields that are not sent keep their current value, or 0 for a new companion
+    pub memory_min_score: Option<f32>,
+    pub memory_half_life_days: Option<f32>,
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct User {
-    pub id: i32,
-    pub name: String,
-    pub persona: String,
+impl CompanionView {
+    // new companion with default memory settings and avatar, used for the first companion and imported characters
+    pub fn new(name: &str, persona: &str, example_dialogue: &str, first_message: &str) -> Self {
+        CompanionView {
+            name: name.to_string(),
+            persona: persona.to_string(),
+            example_dialogue: example_dialogue.to_string(),
+            first_message: first_message.to_string(),
+            long_term_mem: 2,
+            short_term_mem: 5,
+            roleplay: true,
+            dialogue_tuning: true,
+            avatar_path: String::from("/assets/companion_avatar-4rust.jpg"),
+            memory_min_score: None,
+            memory_half_life_days: None,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -76,7 +154,8 @@ pub struct UserView {
     pub persona: String,
 }
 
-#[derive(PartialEq, Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Device {
     CPU,
     GPU,
@@ -114,11 +193,21 @@ impl ToSql for Device {
     }
 }
 
-#[derive(PartialEq, Serialize, Deserialize)]
+#[derive(PartialEq, Clone, Serialize, Deserialize)]
 pub enum PromptTemplate {
     Default,
     Llama2,
-    Mistral
+    Mistral,
+    ChatML,
+    Llama3,
+    Alpaca,
+    Vicuna,
+    Zephyr,
+    Gemma,
+    // user-defined template from prompt_templates table, selected with custom_template_id
+    Custom,
+    // built-in template suggested by metadata of the gguf model, Default if none matches
+    Auto,
 }
 
 impl FromSql for PromptTemplate {
@@ -131,6 +220,14 @@ impl FromSql for PromptTemplate {
                             "Default" => Ok(PromptTemplate::Default),
                             "Llama2" => Ok(PromptTemplate::Llama2),
                             "Mistral" => Ok(PromptTemplate::Mistral),
+                            "ChatML" => Ok(PromptTemplate::ChatML),
+                            "Llama3" => Ok(PromptTemplate::Llama3),
+                            "Alpaca" => Ok(PromptTemplate::Alpaca),
+                            "Vicuna" => Ok(PromptTemplate::Vicuna),
+                            "Zephyr" => Ok(PromptTemplate::Zephyr),
+                            "Gemma" => Ok(PromptTemplate::Gemma),
+                            "Custom" => Ok(PromptTemplate::Custom),
+                            "Auto" => Ok(PromptTemplate::Auto),
                             _ => Err(FromSqlError::OutOfRange(0)),
                         }
                     }
@@ -142,16 +239,31 @@ impl FromSql for PromptTemplate {
     }
 }
 
-impl ToSql for PromptTemplate {
-    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+impl PromptTemplate {
+    // also the name of the built-in preset in prompt_templates table
+    pub fn name(&self) -> &'static str {
         match self {
-            PromptTemplate::Default => Ok(ToSqlOutput::from("Default")),
-            PromptTemplate::Llama2 => Ok(ToSqlOutput::from("Llama2")),
-            PromptTemplate::Mistral => Ok(ToSqlOutput::from("Mistral")),
+            PromptTemplate::Default => "Default",
+            PromptTemplate::Llama2 => "Llama2",
+            PromptTemplate::Mistral => "Mistral",
+            PromptTemplate::ChatML => "ChatML",
+            PromptTemplate::Llama3 => "Llama3",
+            PromptTemplate::Alpaca => "Alpaca",
+            PromptTemplate::Vicuna => "Vicuna",
+            PromptTemplate::Zephyr => "Zephyr",
+            PromptTemplate::Gemma => "Gemma",
+            PromptTemplate::Custom => "Custom",
+            PromptTemplate::Auto => "Auto",
         }
     }
 }
 
+impl ToSql for PromptTemplate {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.name()))
+    }
+}
+
 
 /*
 struct Config {
@@ -163,25 +275,91 @@ struct Config {
 }
 */
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct ConfigView {
     pub device: Device,
     pub llm_model_path: String,
     pub gpu_layers: usize,
-    pub prompt_template: PromptTemplate
+    pub prompt_template: PromptTemplate,
+    pub custom_template_id: Option<i32>,
+    pub temperature: f32,
+    pub top_k: usize,
+    pub top_p: f32,
+    pub repeat_penalty: f32,
+    pub repeat_last_n: usize,
+    // 0 means no limit, generation ends on a stop sequence or when context is full
+    pub max_new_tokens: usize,
+    // -1 means random seed for every generation
+    pub seed: i64,
+    // context size of the model in tokens, prompt is trimmed to fit in it
+    pub context_size: usize,
+    // tokens of the context reserved for the response
+    pub response_budget: usize,
+    // long-term memory results are ranked by keyword_weight * keyword score + semantic_weight * similarity of embeddings,
+    // semantic_weight 0 turns embeddings off
+    pub keyword_weight: f32,
+    pub semantic_weight: f32,
+    // facts about the user are extracted by the model after every ai reply
+    pub fact_extraction: bool,
+    // facts at least this confident are put in every prompt
+    pub fact_min_confidence: f32,
 }
 
+// sampler fields are optional, fields that are not sent keep their current value
 #[derive(Serialize, Deserialize)]
 pub struct ConfigModify {
     pub device: String,
     pub llm_model_path: String,
     pub gpu_layers: usize,
-    pub prompt_template: String
+    pub prompt_template: String,
+    pub custom_template_id: Option<i32>,
+    pub temperature: Option<f32>,
+    pub top_k: Option<usize>,
+    pub top_p: Option<f32>,
+    pub repeat_penalty: Option<f32>,
+    pub repeat_last_n: Option<usize>,
+    pub max_new_tokens: Option<usize>,
+    pub seed: Option<i64>,
+    pub context_size: Option<usize>,
+    pub response_budget: Option<usize>,
+    pub keyword_weight: Option<f32>,
+    pub semantic_weight: Option<f32>,
+    pub fact_extraction: Option<bool>,
+    pub fact_min_confidence: Option<f32>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PromptTemplateData {
+    pub id: i32,
+    pub name: String,
+    // presets shipped with ai-companion, they can't be edited or deleted
+    pub builtin: bool,
+    #[serde(flatten)]
+    pub format: TemplateFormat,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PromptTemplateView {
+    pub name: String,
+    #[serde(flatten)]
+    pub format: TemplateFormat,
 }
 
 pub struct Database {}
 
+// columns read by Database::message_from_row
+const MESSAGE_COLUMNS: &str = "messages.id, messages.ai, messages.content, messages.created_at, messages.selected_alternative,
+    MAX(1, (SELECT COUNT(*) FROM message_alternatives WHERE message_alternatives.message_id = messages.id)), messages.parent_id";
+
+// ids of messages on the path from ?1 to the first message, depth 0 is ?1
+const PATH_TO_ROOT: &str = "WITH RECURSIVE path(id, depth) AS (
+        SELECT ?1, 0
+        UNION ALL
+        SELECT messages.parent_id, path.depth + 1 FROM messages JOIN path ON messages.id = path.id WHERE messages.parent_id IS NOT NULL
+    )";
+
 impl Database {
+    #[allow(clippy::new_ret_no_self)]
     pub fn new() -> Result<usize> {
         let con = Connection::open("companion_database.db")?;
         con.execute(
@@ -189,9 +367,35 @@ impl Database {
                 id INTEGER PRIMARY KEY AUTOINCREMENT,
                 ai BOOLEAN,
                 content TEXT,
+                created_at TEXT,
+                conversation_id INTEGER
+            )", []
+        )?;
+        Database::add_column_if_missing("messages", "conversation_id", "INTEGER", &con)?;
+        Database::add_column_if_missing("messages", "selected_alternative", "INTEGER DEFAULT 0", &con)?;
+        // alternatives of ai messages, created on regenerate. message without alternatives has only its content
+        con.execute(
+            "CREATE TABLE IF NOT EXISTS message_alternatives (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                message_id INTEGER,
+                content TEXT,
                 created_at TEXT
             )", []
         )?;
+        con.execute(
+            "CREATE TABLE IF NOT EXISTS conversations (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                companion_id INTEGER,
+                title TEXT,
+                archived BOOLEAN DEFAULT 0,
+                created_at TEXT
+            )", []
+        )?;
+        // messages from versions without branches are linked in order of their ids after they get a conversation
+        let link_messages = !Database::column_exists("messages", "parent_id", &con)?;
+        Database::add_column_if_missing("messages", "parent_id", "INTEGER", &con)?;
+        // last message of the active branch
+        Database::add_column_if_missing("conversations", "head_message_id", "INTEGER", &con)?;
         con.execute(
             "CREATE TABLE IF NOT EXISTS companion (
                 id INTEGER PRIMARY KEY AUTOINCREMENT,
@@ -203,9 +407,15 @@ impl Database {
                 short_term_mem INTEGER,
                 roleplay BOOLEAN,
                 dialogue_tuning BOOLEAN,
-                avatar_path TEXT
+                avatar_path TEXT,
+                active BOOLEAN DEFAULT 0,
+                memory_min_score REAL DEFAULT 0,
+                memory_half_life_days REAL DEFAULT 0
             )", []
         )?;
+        Database::add_column_if_missing("companion", "active", "BOOLEAN DEFAULT 0", &con)?;
+        Database::add_column_if_missing("companion", "memory_min_score", "REAL DEFAULT 0", &con)?;
+        Database::add_column_if_missing("companion", "memory_half_life_days", "REAL DEFAULT 0", &con)?;
         con.execute(
             "CREATE TABLE IF NOT EXISTS user (
                 id INTEGER PRIMARY KEY AUTOINCREMENT,
@@ -220,63 +430,101 @@ impl Database {
                 device TEXT,
                 llm_model_path TEXT,
                 gpu_layers INTEGER,
-                prompt_template TEXT
+                prompt_template TEXT,
+                temperature REAL DEFAULT 0.8,
+                top_k INTEGER DEFAULT 40,
+                top_p REAL DEFAULT 0.95,
+                repeat_penalty REAL DEFAULT 1.3,
+                repeat_last_n INTEGER DEFAULT 64,
+                max_new_tokens INTEGER DEFAULT 0,
+                seed INTEGER DEFAULT -1,
+                custom_template_id INTEGER,
+                context_size INTEGER DEFAULT 2048,
+                response_budget INTEGER DEFAULT 512,
+                keyword_weight REAL DEFAULT 1.0,
+                semantic_weight REAL DEFAULT 0.0,
+                fact_extraction BOOLEAN DEFAULT 1,
+                fact_min_confidence REAL DEFAULT 0.7
             )", []
         )?;
+        // databases created by older versions don't have sampler settings yet
+        Database::add_column_if_missing("config", "temperature", "REAL DEFAULT 0.8", &con)?;
+        Database::add_column_if_missing("config", "top_k", "INTEGER DEFAULT 40", &con)?;
+        Database::add_column_if_missing("config", "top_p", "REAL DEFAULT 0.95", &con)?;
+        Database::add_column_if_missing("config", "repeat_penalty", "REAL DEFAULT 1.3", &con)?;
+        Database::add_column_if_missing("config", "repeat_last_n", "INTEGER DEFAULT 64", &con)?;
+        Database::add_column_if_missing("config", "max_new_tokens", "INTEGER DEFAULT 0", &con)?;
+        Database::add_column_if_missing("config", "seed", "INTEGER DEFAULT -1", &con)?;
+        Database::add_column_if_missing("config", "custom_template_id", "INTEGER", &con)?;
+        Database::add_column_if_missing("config", "context_size", "INTEGER DEFAULT 2048", &con)?;
+        Database::add_column_if_missing("config", "response_budget", "INTEGER DEFAULT 512", &con)?;
+        Database::add_column_if_missing("config", "keyword_weight", "REAL DEFAULT 1.0", &con)?;
+        Database::add_column_if_missing("config", "semantic_weight", "REAL DEFAULT 0.0", &con)?;
+        Database::add_column_if_missing("config", "fact_extraction", "BOOLEAN DEFAULT 1", &con)?;
+        Database::add_column_if_missing("config", "fact_min_confidence", "REAL DEFAULT 0.7", &con)?;
+        con.execute(
+            "CREATE TABLE IF NOT EXISTS prompt_templates (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                name TEXT,
+                system_block TEXT,
+                user_turn TEXT,
+                ai_turn TEXT,
+                memory_entry TEXT,
+                stop_sequences TEXT,
+                builtin BOOLEAN DEFAULT 0
+            )", []
+        )?;
+        Database::add_column_if_missing("prompt_templates", "companion_block", "TEXT DEFAULT ''", &con)?;
+        Database::seed_prompt_templates(&con)?;
         if Database::is_table_empty("companion", &con)? {
-            con.execute(
-                "INSERT INTO companion (name, persona, example_dialogue, first_message, long_term_mem, short_term_mem, roleplay, dialogue_tuning, avatar_path) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
-                &[
-                    "Assistant",
-                    "{{char}} is an artificial intelligence chatbot designed to help {{user}}. {{char}} is an artificial intelligence created in ai-companion backend",
-                    "{{user}}: What is ai-companion?\n{{char}}: AI Companion is a open-source project, wrote in Rust, Typescript and React, that aims to provide users with their own personal AI chatbot on their computer. It allows users to engage in friendly and natural conversations with their AI, creating a unique and personalized experience. This software can also be used as a backend or API for other projects that require a personalised AI chatbot. Very light size, simple installation, simple configuration, quick cold start and ease of use are some of the strengths of AI Companion in comparison to other similar projects.\n{{user}}: Can you tell me about the creator of ai-companion?\n{{char}}: the creator of the ai-companion program is 'Hubert Kasperek', he is a young programmer from Poland who is mostly interested in web development and computer science concepts, he has account on GitHub under nickname \"Hukasx0\"",
-                    "Hello {{user}}, how can i help you today?",
-                    "2",
-                    "5",
-                    "1",
-                    "1",
-                    "/assets/companion_avatar-4rust.jpg"
-                ]
-            )?;
+            Database::insert_companion_with(&con, &CompanionView::new(
+                "Assistant",
+                "{{char}} is an artificial intelligence chatbot designed to help {{user}}. {{char}} is an artificial intelligence created in ai-companion backend",
+                "{{user}}: What is ai-companion?\n{{char}}: AI Companion is a open-source project, wrote in Rust, Typescript and React, that aims to provide users with their own personal AI chatbot on their computer. It allows users to engage in friendly and natural conversations with their AI, creating a unique and personalized experience. This software can also be used as a backend or API for other projects that require a personalised AI chatbot. Very light size, simple installation, simple configuration, quick cold start and ease of use are some of the strengths of AI Companion in comparison to other similar projects.\n{{user}}: Can you tell me about the creator of ai-companion?\n{{char}}: the creator of the ai-companion program is 'Hubert Kasperek', he is a young programmer from Poland who is mostly interested in web development and computer science concepts, he has account on GitHub under nickname \"Hukasx0\"",
+                "Hello {{user}}, how can i help you today?",
+            ))?;
         }
+        Database::ensure_active_companion(&con)?;
         if Database::is_table_empty("user", &con)? {
             con.execute(
                 "INSERT INTO user (name, persona, avatar_path) VALUES (?, ?, ?)",
-                &[
+                [
                     "User",
                     "{{user}} is chatting with {{char}} using ai-companion web user interface",
                     "/assets/user_avatar-4rust.jpg"
                 ]
             )?;
         }
-        if Database::is_table_empty("messages", &con)? {
-            struct CompanionReturn {
-                name: String,
-                first_message: String
+        if Database::is_table_empty("conversations", &con)? {
+            let companion_id: i32 = con.query_row("SELECT id FROM companion ORDER BY active DESC, id ASC LIMIT 1", [], |row| row.get(0))?;
+            if Database::is_table_empty("messages", &con)? {
+                Database::create_conversation_with(&con, companion_id, "New conversation")?;
+            } else {
+                // messages from older versions, without conversations, become the first conversation
+                con.execute(
+                    "INSERT INTO conversations (companion_id, title, archived, created_at) VALUES (?, ?, 0, ?)",
+                    rusqlite::params![companion_id, "Conversation", get_current_date()]
+                )?;
+                con.execute(
+                    "UPDATE messages SET conversation_id = ? WHERE conversation_id IS NULL",
+                    [con.last_insert_rowid()]
+                )?;
             }
-            let companion_data = con.query_row("SELECT name, first_message FROM companion", [], |row| {
-                Ok(CompanionReturn {
-                    name: row.get(0)?,
-                    first_message: row.get(1)?
-                   }
-                )
-            })?;
-            let user_name: String = con.query_row("SELECT name, persona FROM user LIMIT 1", [], |row| {
-                Ok(row.get(0)?)
-            })?;
+        }
+        if link_messages {
             con.execute(
-                "INSERT INTO messages (ai, content, created_at) VALUES (?, ?, ?)",
-                &[
-                    "1",
-                    &companion_data.first_message.replace("{{char}}", &companion_data.name).replace("{{user}}", &user_name),
-                    &get_current_date()
-                ]
+                "UPDATE messages SET parent_id = (SELECT MAX(m.id) FROM messages m WHERE m.conversation_id = messages.conversation_id AND m.id < messages.id)",
+                []
             )?;
         }
+        con.execute(
+            "UPDATE conversations SET head_message_id = (SELECT MAX(id) FROM messages WHERE conversation_id = conversations.id) WHERE head_message_id IS NULL",
+            []
+        )?;
         if Database::is_table_empty("config", &con)? {
             con.execute(
                 "INSERT INTO config (device, llm_model_path, gpu_layers, prompt_template) VALUES (?, ?, 20, ?)",
-                &[
+                [
                     &Device::CPU as &dyn ToSql,
                     &"path/to/your/gguf/model.gguf",
                     &PromptTemplate::Default as &dyn ToSql
@@ -286,6 +534,26 @@ impl Database {
         Ok(0)
     }
 
+    // built-in presets are inserted if missing and updated to the current version otherwise
+    fn seed_prompt_templates(con: &Connection) -> Result<()> {
+        for preset in PRESETS {
+            let format = preset.format();
+            let stop_sequences = serde_json::to_string(&format.stop_sequences).unwrap_or(String::from("[]"));
+            let changed = con.execute(
+                "UPDATE prompt_templates SET system_block = ?, companion_block = ?, user_turn = ?, ai_turn = ?, memory_entry = ?, stop_sequences = ?
+                    WHERE builtin = 1 AND name = ?",
+                [&format.system_block, &format.companion_block, &format.user_turn, &format.ai_turn, &format.memory_entry, &stop_sequences, preset.name]
+            )?;
+            if changed == 0 {
+                con.execute(
+                    "INSERT INTO prompt_templates (name, system_block, companion_block, user_turn, ai_turn, memory_entry, stop_sequences, builtin) VALUES (?, ?, ?, ?, ?, ?, ?, 1)",
+                    [preset.name, &format.system_block, &format.companion_block, &format.user_turn, &format.ai_turn, &format.memory_entry, &stop_sequences]
+                )?;
+            }
+        }
+        Ok(())
+    }
+
     pub fn is_table_empty(table_name: &str, con: &Connection) -> Result<bool> {
         let mut stmt = con.prepare(&format!("SELECT COUNT(*) FROM {}", table_name))?;
         let mut rows = stmt.query([])?;
@@ -293,6 +561,35 @@ impl Database {
         Ok(count == 0)
     }
 
+    // there is always exactly one active companion, the one with lowest id if none was selected
+    pub fn ensure_active_companion(con: &Connection) -> Result<()> {
+        con.execute(
+            "UPDATE companion SET active = 1 WHERE id = (SELECT MIN(id) FROM companion)
+                AND NOT EXISTS (SELECT 1 FROM companion WHERE active = 1)",
+            []
+        )?;
+        Ok(())
+    }
+
+    pub fn column_exists(table_name: &str, column_name: &str, con: &Connection) -> Result<bool> {
+        let mut stmt = con.prepare(&format!("PRAGMA table_info({})", table_name))?;
+        let columns = stmt.query_map([], |row| row.get::<_, String>(1))?;
+        for column in columns {
+            if column? == column_name {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    pub fn add_column_if_missing(table_name: &str, column_name: &str, column_definition: &str, con: &Connection) -> Result<()> {
+        if Database::column_exists(table_name, column_name, con)? {
+            return Ok(());
+        }
+        con.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table_name, column_name, column_definition), [])?;
+        Ok(())
+    }
+
    /* pub fn get_messages() -> Result<Vec<Message>> {
         let con = Connection::open("companion_database.db")?;
         let mut stmt = con.prepare("SELECT id, ai, content, created_at FROM messages")?;
@@ -311,17 +608,45 @@ impl Database {
         Ok(messages)
     } */
 
-    pub fn get_x_messages(x: usize, index: usize) -> Result<Vec<Message>> {
+    fn message_from_row(row: &rusqlite::Row) -> Result<Message> {
+        Ok(Message {
+            id: row.get(0)?,
+            ai: row.get(1)?,
+            content: row.get(2)?,
+            created_at: row.get(3)?,
+            selected_alternative: row.get(4)?,
+            alternatives: row.get(5)?,
+            parent_id: row.get(6)?,
+        })
+    }
+
+    fn head_message_id(con: &Connection, conversation_id: i32) -> Result<Option<i32>> {
+        con.query_row("SELECT head_message_id FROM conversations WHERE id = ?", [conversation_id], |row| row.get(0))
+    }
+
+    // newest message without replies that descends from message_id, or the message itself
+    fn newest_leaf(con: &Connection, message_id: i32) -> Result<i32> {
+        con.query_row(
+            "WITH RECURSIVE descendants(id) AS (
+                SELECT ?1
+                UNION ALL
+                SELECT messages.id FROM messages JOIN descendants ON messages.parent_id = descendants.id
+            )
+            SELECT MAX(id) FROM descendants WHERE NOT EXISTS (SELECT 1 FROM messages WHERE messages.parent_id = descendants.id)",
+            [message_id],
+            |row| row.get(0)
+        )
+    }
+
+    // last x messages of the active branch skipping index newest ones, oldest first
+    pub fn get_x_messages(conversation_id: i32, x: usize, index: usize) -> Result<Vec<Message>> {
         let con = Connection::open("companion_database.db")?;
-        let mut stmt = con.prepare("SELECT id, ai, content, created_at FROM messages ORDER BY id DESC LIMIT ? OFFSET ?")?;
-        let rows = stmt.query_map([x, index], |row| {
-            Ok(Message {
-                id: row.get(0)?,
-                ai: row.get(1)?,
-                content: row.get(2)?,
-                created_at: row.get(3)?,
-            })
-        })?;
+        let head = match Database::head_message_id(&con, conversation_id)? {
+            Some(id) => id,
+            None => return Ok(Vec::new()),
+        };
+        let mut stmt = con.prepare(&format!("{} SELECT {} FROM messages JOIN path ON messages.id = path.id ORDER BY path.depth LIMIT ?2 OFFSET ?3", PATH_TO_ROOT, MESSAGE_COLUMNS))?;
+        let rows = stmt.query_map([head as i64, x as i64, index as i64], Database::message_from_row)?;
         let mut messages = Vec::new();
         for row in rows {
             messages.push(row?);
@@ -329,23 +654,18 @@ impl Database {
         Ok(messages.into_iter().rev().collect())
     }
 
-    pub fn get_latest_message() -> Result<Message> {
+    // last message of the active branch
+    pub fn get_latest_message(conversation_id: i32) -> Result<Message> {
         let con = Connection::open("companion_database.db")?;
-        let mut stmt = con.prepare("SELECT id, ai, content, created_at FROM messages ORDER BY id DESC LIMIT 1")?;
-        let row = stmt.query_row([], |row| {
-            Ok(Message {
-                id: row.get(0)?,
-                ai: row.get(1)?,
-                content: row.get(2)?,
-                created_at: row.get(3)?,
-            })
-        })?;
-        Ok(row)
+        match Database::head_message_id(&con, conversation_id)? {
+            Some(id) => Database::get_message(id),
+            None => Err(Error::QueryReturnedNoRows),
+        }
     }
 
     pub fn get_companion_data() -> Result<CompanionView> {
         let con = Connection::open("companion_database.db")?;
-        let mut stmt = con.prepare("SELECT name, persona, example_dialogue, first_message, long_term_mem, short_term_mem, roleplay, dialogue_tuning, avatar_path FROM companion LIMIT 1")?;
+        let mut stmt = con.prepare("SELECT name, persona, example_dialogue, first_message, long_term_mem, short_term_mem, roleplay, dialogue_tuning, avatar_path, memory_min_score, memory_half_life_days FROM companion ORDER BY active DESC, id ASC LIMIT 1")?;
         let row = stmt.query_row([], |row| {
             Ok(CompanionView {
                 name: row.get(0)?,
@@ -357,6 +677,8 @@ impl Database {
                 roleplay: row.get(6)?,
                 dialogue_tuning: row.get(7)?,
                 avatar_path: row.get(8)?,
+                memory_min_score: row.get(9)?,
+                memory_half_life_days: row.get(10)?,
             })
         })?;
         Ok(row)
@@ -364,7 +686,7 @@ impl Database {
 
     pub fn get_companion_card_data() -> Result<CharacterCard> {
         let con = Connection::open("companion_database.db")?;
-        let mut stmt = con.prepare("SELECT name, persona, first_message, example_dialogue FROM companion LIMIT 1")?;
+        let mut stmt = con.prepare("SELECT name, persona, first_message, example_dialogue FROM companion ORDER BY active DESC, id ASC LIMIT 1")?;
         let row = stmt.query_row([], |row| {
             Ok(CharacterCard {
                 name: row.get(0)?,
@@ -376,6 +698,75 @@ impl Database {
         Ok(row)
     }
 
+    pub fn get_active_companion_id() -> Result<i32> {
+        let con = Connection::open("companion_database.db")?;
+        con.query_row("SELECT id FROM companion ORDER BY active DESC, id ASC LIMIT 1", [], |row| row.get(0))
+    }
+
+    pub fn get_companions() -> Result<Vec<Companion>> {
+        let con = Connection::open("companion_database.db")?;
+        let mut stmt = con.prepare("SELECT id, name, persona, example_dialogue, first_message, long_term_mem, short_term_mem, roleplay, dialogue_tuning, avatar_path, active, memory_min_score, memory_half_life_days FROM companion ORDER BY id ASC")?;
+        let rows = stmt.query_map([], |row| {
+            Ok(Companion {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                persona: row.get(2)?,
+                example_dialogue: row.get(3)?,
+                first_message: row.get(4)?,
+                long_term_mem: row.get(5)?,
+                short_term_mem: row.get(6)?,
+                roleplay: row.get(7)?,
+                dialogue_tuning: row.get(8)?,
+                avatar_path: row.get(9)?,
+                active: row.get(10)?,
+                memory_min_score: row.get(11)?,
+                memory_half_life_days: row.get(12)?,
+            })
+        })?;
+        let mut companions = Vec::new();
+        for row in rows {
+            companions.push(row?);
+        }
+        Ok(companions)
+    }
+
+    pub fn get_companion(id: i32) -> Result<Companion> {
+        let con = Connection::open("companion_database.db")?;
+        let mut stmt = con.prepare("SELECT id, name, persona, example_dialogue, first_message, long_term_mem, short_term_mem, roleplay, dialogue_tuning, avatar_path, active, memory_min_score, memory_half_life_days FROM companion WHERE id = ?")?;
+        let row = stmt.query_row([id], |row| {
+            Ok(Companion {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                persona: row.get(2)?,
+                example_dialogue: row.get(3)?,
+                first_message: row.get(4)?,
+                long_term_mem: row.get(5)?,
+                short_term_mem: row.get(6)?,
+                roleplay: row.get(7)?,
+                dialogue_tuning: row.get(8)?,
+                avatar_path: row.get(9)?,
+                active: row.get(10)?,
+                memory_min_score: row.get(11)?,
+                memory_half_life_days: row.get(12)?,
+            })
+        })?;
+        Ok(row)
+    }
+
+    pub fn get_companion_card_data_by_id(id: i32) -> Result<CharacterCard> {
+        let con = Connection::open("companion_database.db")?;
+        let mut stmt = con.prepare("SELECT name, persona, first_message, example_dialogue FROM companion WHERE id = ?")?;
+        let row = stmt.query_row([id], |row| {
+            Ok(CharacterCard {
+                name: row.get(0)?,
+                description: row.get(1)?,
+                first_mes: row.get(2)?,
+                mes_example: row.get(3)?,
+            })
+        })?;
+        Ok(row)
+    }
+
     pub fn get_user_data() -> Result<UserView> {
         let con = Connection::open("companion_database.db")?;
         let mut stmt = con.prepare("SELECT name, persona FROM user LIMIT 1")?;
@@ -390,148 +781,646 @@ impl Database {
 
     pub fn get_message(id: i32) -> Result<Message> {
         let con = Connection::open("companion_database.db")?;
-        let mut stmt = con.prepare("SELECT id, ai, content, created_at FROM messages WHERE id = ?")?;
-        let row = stmt.query_row([id], |row| {
-            Ok(Message {
-                id: row.get(0)?,
-                ai: row.get(1)?,
-                content: row.get(2)?,
-                created_at: row.get(3)?,
-            })
-        })?;
+        let mut stmt = con.prepare(&format!("SELECT {} FROM messages WHERE id = ?", MESSAGE_COLUMNS))?;
+        let row = stmt.query_row([id], Database::message_from_row)?;
+        Ok(row)
+    }
+
+    // message the given one replies to
+    pub fn get_parent_message(id: i32) -> Result<Message> {
+        let con = Connection::open("companion_database.db")?;
+        let mut stmt = con.prepare(&format!("SELECT {} FROM messages WHERE id = (SELECT parent_id FROM messages WHERE id = ?)", MESSAGE_COLUMNS))?;
+        let row = stmt.query_row([id], Database::message_from_row)?;
         Ok(row)
     }
 
-    pub fn insert_message(message: NewMessage) -> Result<(), Error> {
+    // companion of the conversation the message belongs to
+    pub fn get_message_companion_id(id: i32) -> Result<i32> {
+        let con = Connection::open("companion_database.db")?;
+        con.query_row(
+            "SELECT conversations.companion_id FROM messages JOIN conversations ON conversations.id = messages.conversation_id WHERE messages.id = ?",
+            [id],
+            |row| row.get(0),
+        )
+    }
+
+    // adds message at the end of the active branch
+    pub fn insert_message(conversation_id: i32, message: NewMessage) -> Result<i32, Error> {
         let con = Connection::open("companion_database.db")?;
+        let parent_id = Database::head_message_id(&con, conversation_id)?;
+        Database::insert_message_after(conversation_id, parent_id, message)
+    }
+
+    // adds message as a reply to parent_id, if parent_id already has replies a new branch is created.
+    // the branch of the new message becomes active
+    pub fn insert_message_after(conversation_id: i32, parent_id: Option<i32>, message: NewMessage) -> Result<i32, Error> {
+        let con = Connection::open("companion_database.db")?;
+        if let Some(parent_id) = parent_id {
+            let parent_conversation: Option<i32> = con.query_row("SELECT conversation_id FROM messages WHERE id = ?", [parent_id], |row| row.get(0)).optional()?;
+            if parent_conversation != Some(conversation_id) {
+                return Err(rusqlite::Error::InvalidParameterName(format!("Message with id {} is not in conversation {}", parent_id, conversation_id)));
+            }
+        }
         con.execute(
-            &format!("INSERT INTO messages (ai, content, created_at) VALUES ({}, ?, ?)", message.ai),
-            &[
-                &message.content,
-                &get_current_date()
+            "INSERT INTO messages (ai, content, created_at, conversation_id, parent_id) VALUES (?, ?, ?, ?, ?)",
+            rusqlite::params![
+                message.ai,
+                message.content,
+                get_current_date(),
+                conversation_id,
+                parent_id
             ]
         )?;
-        Ok(())
+        let id = con.last_insert_rowid() as i32;
+        con.execute("UPDATE conversations SET head_message_id = ? WHERE id = ?", [id, conversation_id])?;
+        Ok(id)
     }
 
-    pub fn edit_message(id: i32, message: NewMessage) -> Result<(), Error> {
+    // message that already has replies is not changed, the edited copy starts a new branch instead.
+    // returns id of the edited message
+    pub fn edit_message(id: i32, message: NewMessage) -> Result<i32, Error> {
         let con = Connection::open("companion_database.db")?;
+        let replies: i64 = con.query_row("SELECT COUNT(*) FROM messages WHERE parent_id = ?", [id], |row| row.get(0))?;
+        if replies > 0 {
+            let (conversation_id, parent_id): (i32, Option<i32>) = con.query_row("SELECT conversation_id, parent_id FROM messages WHERE id = ?", [id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            return Database::insert_message_after(conversation_id, parent_id, message);
+        }
         con.execute(
-            &format!("UPDATE messages SET ai = {}, content = ? WHERE id = ?", message.ai),
-            &[
-                &message.content,
-                &id.to_string()
-            ]
+            "UPDATE messages SET ai = ?, content = ? WHERE id = ?",
+            rusqlite::params![message.ai, message.content, id]
         )?;
-        Ok(())
+        // edit changes the selected alternative
+        let selected: usize = con.query_row("SELECT selected_alternative FROM messages WHERE id = ?", [id], |row| row.get(0)).optional()?.unwrap_or(0);
+        con.execute(
+            "UPDATE message_alternatives SET content = ? WHERE id = (SELECT id FROM message_alternatives WHERE message_id = ? ORDER BY id LIMIT 1 OFFSET ?)",
+            rusqlite::params![message.content, id, selected]
+        )?;
+        Ok(id)
     }
 
+    // replies of the deleted message are moved to its parent
     pub fn delete_message(id: i32) -> Result<(), Error> {
         let con = Connection::open("companion_database.db")?;
+        let message: Option<(i32, Option<i32>)> = con.query_row("SELECT conversation_id, parent_id FROM messages WHERE id = ?", [id], |row| Ok((row.get(0)?, row.get(1)?))).optional()?;
+        let (conversation_id, parent_id) = match message {
+            Some(m) => m,
+            None => return Ok(()),
+        };
+        con.execute(
+            "UPDATE messages SET parent_id = ? WHERE parent_id = ?",
+            rusqlite::params![parent_id, id],
+        )?;
         con.execute(
             "DELETE FROM messages WHERE id = ?",
             [id],
         )?;
+        if Database::head_message_id(&con, conversation_id)? == Some(id) {
+            let head: Option<i32> = match parent_id {
+                Some(parent_id) => Some(Database::newest_leaf(&con, parent_id)?),
+                None => con.query_row(
+                    "SELECT MAX(id) FROM messages WHERE conversation_id = ? AND NOT EXISTS (SELECT 1 FROM messages m WHERE m.parent_id = messages.id)",
+                    [conversation_id],
+                    |row| row.get(0)
+                )?,
+            };
+            con.execute("UPDATE conversations SET head_message_id = ? WHERE id = ?", rusqlite::params![head, conversation_id])?;
+        }
+        Database::delete_orphan_alternatives(&con)
+    }
+
+    // every message without replies ends a branch
+    pub fn get_branches(conversation_id: i32) -> Result<Vec<Branch>, Error> {
+        let con = Connection::open("companion_database.db")?;
+        Database::get_conversation(conversation_id)?;
+        let head = Database::head_message_id(&con, conversation_id)?;
+        let mut stmt = con.prepare(
+            "SELECT id, content, created_at FROM messages WHERE conversation_id = ?
+                AND NOT EXISTS (SELECT 1 FROM messages m WHERE m.parent_id = messages.id) ORDER BY id"
+        )?;
+        let leaves = stmt.query_map([conversation_id], |row| Ok((row.get::<_, i32>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?)))?;
+        let mut branches = Vec::new();
+        for leaf in leaves {
+            let (message_id, content, created_at) = leaf?;
+            let length: usize = con.query_row(&format!("{} SELECT COUNT(*) FROM path", PATH_TO_ROOT), [message_id], |row| row.get(0))?;
+            branches.push(Branch { message_id, content, created_at, length, active: head == Some(message_id) });
+        }
+        Ok(branches)
+    }
+
+    // the branch with message_id becomes active, if the message has replies its newest branch is used
+    pub fn set_active_branch(conversation_id: i32, message_id: i32) -> Result<i32, Error> {
+        let con = Connection::open("companion_database.db")?;
+        let message_conversation: Option<i32> = con.query_row("SELECT conversation_id FROM messages WHERE id = ?", [message_id], |row| row.get(0)).optional()?;
+        if message_conversation != Some(conversation_id) {
+            return Err(Error::QueryReturnedNoRows);
+        }
+        let head = Database::newest_leaf(&con, message_id)?;
+        con.execute("UPDATE conversations SET head_message_id = ? WHERE id = ?", [head, conversation_id])?;
+        Ok(head)
+    }
+
+    // new conversation with copies of messages from the first one to message_id, returns its id
+    pub fn fork_conversation(message_id: i32) -> Result<i32, Error> {
+        let mut con = Connection::open("companion_database.db")?;
+        let transaction = con.transaction()?;
+        let (companion_id, title): (i32, String) = transaction.query_row(
+            "SELECT conversations.companion_id, conversations.title FROM messages JOIN conversations ON messages.conversation_id = conversations.id WHERE messages.id = ?",
+            [message_id],
+            |row| Ok((row.get(0)?, row.get(1)?))
+        )?;
+        let mut path = Vec::new();
+        {
+            let mut stmt = transaction.prepare(&format!("{} SELECT path.id FROM path ORDER BY path.depth DESC", PATH_TO_ROOT))?;
+            let rows = stmt.query_map([message_id], |row| row.get::<_, i32>(0))?;
+            for row in rows {
+                path.push(row?);
+            }
+        }
+        transaction.execute(
+            "INSERT INTO conversations (companion_id, title, archived, created_at) VALUES (?, ?, 0, ?)",
+            rusqlite::params![companion_id, format!("{} (fork)", title), get_current_date()]
+        )?;
+        let fork_id = transaction.last_insert_rowid() as i32;
+        let mut parent_id: Option<i32> = None;
+        for id in path {
+            transaction.execute(
+                "INSERT INTO messages (ai, content, created_at, conversation_id, selected_alternative, parent_id)
+                    SELECT ai, content, created_at, ?, selected_alternative, ? FROM messages WHERE id = ?",
+                rusqlite::params![fork_id, parent_id, id]
+            )?;
+            let copy_id = transaction.last_insert_rowid() as i32;
+            transaction.execute(
+                "INSERT INTO message_alternatives (message_id, content, created_at)
+                    SELECT ?, content, created_at FROM message_alternatives WHERE message_id = ? ORDER BY id",
+                [copy_id, id]
+            )?;
+            parent_id = Some(copy_id);
+        }
+        transaction.execute("UPDATE conversations SET head_message_id = ? WHERE id = ?", rusqlite::params![parent_id, fork_id])?;
+        transaction.commit()?;
+        Ok(fork_id)
+    }
+
+    fn delete_orphan_alternatives(con: &Connection) -> Result<(), Error> {
+        con.execute(
+            "DELETE FROM message_alternatives WHERE message_id NOT IN (SELECT id FROM messages)",
+            []
+        )?;
         Ok(())
     }
 
-    pub fn delete_latest_message() -> Result<(), rusqlite::Error> {
+    // message without saved alternatives has one, its content
+    pub fn get_message_alternatives(message_id: i32) -> Result<Vec<MessageAlternative>, Error> {
         let con = Connection::open("companion_database.db")?;
-        let last_message_id: i32 = con.query_row(
-            "SELECT id FROM messages ORDER BY id DESC LIMIT 1",
-            [],
+        let message = Database::get_message(message_id)?;
+        let mut stmt = con.prepare("SELECT content, created_at FROM message_alternatives WHERE message_id = ? ORDER BY id")?;
+        let rows = stmt.query_map([message_id], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+        let mut alternatives = Vec::new();
+        for (index, row) in rows.enumerate() {
+            let (content, created_at) = row?;
+            alternatives.push(MessageAlternative { index, content, created_at, selected: index == message.selected_alternative });
+        }
+        if alternatives.is_empty() {
+            alternatives.push(MessageAlternative { index: 0, content: message.content, created_at: message.created_at, selected: true });
+        }
+        Ok(alternatives)
+    }
+
+    // adds alternative to an ai message and selects it, returns its index
+    pub fn add_message_alternative(message_id: i32, content: &str) -> Result<usize, Error> {
+        let con = Connection::open("companion_database.db")?;
+        let message = Database::get_message(message_id)?;
+        if !message.ai {
+            return Err(rusqlite::Error::InvalidParameterName(format!("Message with id {} is not an ai message", message_id)));
+        }
+        let saved: i64 = con.query_row("SELECT COUNT(*) FROM message_alternatives WHERE message_id = ?", [message_id], |row| row.get(0))?;
+        if saved == 0 {
+            con.execute(
+                "INSERT INTO message_alternatives (message_id, content, created_at) VALUES (?, ?, ?)",
+                rusqlite::params![message_id, message.content, message.created_at]
+            )?;
+        }
+        con.execute(
+            "INSERT INTO message_alternatives (message_id, content, created_at) VALUES (?, ?, ?)",
+            rusqlite::params![message_id, content, get_current_date()]
+        )?;
+        let index = message.alternatives;
+        con.execute(
+            "UPDATE messages SET content = ?, selected_alternative = ? WHERE id = ?",
+            rusqlite::params![content, index, message_id]
+        )?;
+        Ok(index)
+    }
+
+    pub fn select_message_alternative(message_id: i32, index: usize) -> Result<(), Error> {
+        let con = Connection::open("companion_database.db")?;
+        let alternatives = Database::get_message_alternatives(message_id)?;
+        let alternative = match alternatives.get(index) {
+            Some(a) => a,
+            None => return Err(rusqlite::Error::InvalidParameterName(format!("Message with id {} has no alternative {}", message_id, index))),
+        };
+        con.execute(
+            "UPDATE messages SET content = ?, selected_alternative = ? WHERE id = ?",
+            rusqlite::params![alternative.content, index, message_id]
+        )?;
+        Ok(())
+    }
+
+    // the only alternative can't be deleted, if the selected one is deleted the next one is selected
+    pub fn delete_message_alternative(message_id: i32, index: usize) -> Result<(), Error> {
+        let con = Connection::open("companion_database.db")?;
+        let message = Database::get_message(message_id)?;
+        if index >= message.alternatives {
+            return Err(rusqlite::Error::InvalidParameterName(format!("Message with id {} has no alternative {}", message_id, index)));
+        }
+        if message.alternatives <= 1 {
+            return Err(rusqlite::Error::InvalidParameterName("Can't delete the only alternative of a message".to_string()));
+        }
+        con.execute(
+            "DELETE FROM message_alternatives WHERE id = (SELECT id FROM message_alternatives WHERE message_id = ? ORDER BY id LIMIT 1 OFFSET ?)",
+            rusqlite::params![message_id, index]
+        )?;
+        let selected = if index < message.selected_alternative {
+            message.selected_alternative - 1
+        } else if index == message.selected_alternative {
+            index.min(message.alternatives - 2)
+        } else {
+            message.selected_alternative
+        };
+        let content: String = con.query_row(
+            "SELECT content FROM message_alternatives WHERE message_id = ? ORDER BY id LIMIT 1 OFFSET ?",
+            rusqlite::params![message_id, selected],
             |row| row.get(0)
         )?;
         con.execute(
-            "DELETE FROM messages WHERE id = ?",
-            [last_message_id]
+            "UPDATE messages SET content = ?, selected_alternative = ? WHERE id = ?",
+            rusqlite::params![content, selected, message_id]
         )?;
         Ok(())
     }
 
-    pub fn erase_messages() -> Result<(), Error> {
+    pub fn erase_messages(conversation_id: i32) -> Result<(), Error> {
         let con = Connection::open("companion_database.db")?;
         con.execute(
-            "DELETE FROM messages",
-            []
+            "DELETE FROM messages WHERE conversation_id = ?",
+            [conversation_id]
         )?;
+        Database::delete_orphan_alternatives(&con)?;
+        let companion_id: i32 = con.query_row("SELECT companion_id FROM conversations WHERE id = ?", [conversation_id], |row| row.get(0))?;
+        Database::seed_conversation(&con, conversation_id, companion_id)
+    }
+
+    // new conversation starts with first message of the companion
+    fn seed_conversation(con: &Connection, conversation_id: i32, companion_id: i32) -> Result<(), Error> {
         struct CompanionReturn {
             name: String,
             first_message: String
         }
-        let companion_data = con.query_row("SELECT name, first_message FROM companion", [], |row| {
+        let companion_data = con.query_row("SELECT name, first_message FROM companion WHERE id = ?", [companion_id], |row| {
             Ok(CompanionReturn {
                 name: row.get(0)?,
                 first_message: row.get(1)?
                }
             )
         })?;
-        let user_name: String = con.query_row("SELECT name, persona FROM user LIMIT 1", [], |row| {
-            Ok(row.get(0)?)
-        })?;
+        let user_name: String = con.query_row("SELECT name, persona FROM user LIMIT 1", [], |row| row.get(0))?;
         con.execute(
-            "INSERT INTO messages (ai, content, created_at) VALUES (?, ?, ?)",
-            &[
+            "INSERT INTO messages (ai, content, created_at, conversation_id) VALUES (?, ?, ?, ?)",
+            rusqlite::params![
                 "1",
-                &companion_data.first_message.replace("{{char}}", &companion_data.name).replace("{{user}}", &user_name),
-                &get_current_date()
+                companion_data.first_message.replace("{{char}}", &companion_data.name).replace("{{user}}", &user_name),
+                get_current_date(),
+                conversation_id
             ]
         )?;
+        con.execute("UPDATE conversations SET head_message_id = ? WHERE id = ?", [con.last_insert_rowid(), conversation_id as i64])?;
         Ok(())
     }
 
-    pub fn edit_companion(companion: CompanionView) -> Result<(), Error> {
+    fn create_conversation_with(con: &Connection, companion_id: i32, title: &str) -> Result<i32, Error> {
+        con.execute(
+            "INSERT INTO conversations (companion_id, title, archived, created_at) VALUES (?, ?, 0, ?)",
+            rusqlite::params![companion_id, title, get_current_date()]
+        )?;
+        let conversation_id = con.last_insert_rowid() as i32;
+        Database::seed_conversation(con, conversation_id, companion_id)?;
+        Ok(conversation_id)
+    }
+
+    pub fn create_conversation(conversation: NewConversation) -> Result<i32, Error> {
+        let companion_id = match conversation.companion_id {
+            Some(id) => id,
+            None => Database::get_active_companion_id()?,
+        };
+        let con = Connection::open("companion_database.db")?;
+        let exists: i64 = con.query_row("SELECT COUNT(*) FROM companion WHERE id = ?", [companion_id], |row| row.get(0))?;
+        if exists == 0 {
+            return Err(rusqlite::Error::InvalidParameterName(format!("Companion with id {} doesn't exist", companion_id)));
+        }
+        Database::create_conversation_with(&con, companion_id, conversation.title.as_deref().unwrap_or("New conversation"))
+    }
+
+    pub fn get_conversations(companion_id: Option<i32>, archived: Option<bool>) -> Result<Vec<Conversation>> {
+        let con = Connection::open("companion_database.db")?;
+        let mut stmt = con.prepare(
+            "SELECT id, companion_id, title, archived, created_at FROM conversations
+                WHERE (?1 IS NULL OR companion_id = ?1) AND (?2 IS NULL OR archived = ?2) ORDER BY id DESC"
+        )?;
+        let rows = stmt.query_map(rusqlite::params![companion_id, archived], |row| {
+            Ok(Conversation {
+                id: row.get(0)?,
+                companion_id: row.get(1)?,
+                title: row.get(2)?,
+                archived: row.get(3)?,
+                created_at: row.get(4)?,
+            })
+        })?;
+        let mut conversations = Vec::new();
+        for row in rows {
+            conversations.push(row?);
+        }
+        Ok(conversations)
+    }
+
+    pub fn get_conversation(id: i32) -> Result<Conversation> {
+        let con = Connection::open("companion_database.db")?;
+        let mut stmt = con.prepare("SELECT id, companion_id, title, archived, created_at FROM conversations WHERE id = ?")?;
+        let row = stmt.query_row([id], |row| {
+            Ok(Conversation {
+                id: row.get(0)?,
+                companion_id: row.get(1)?,
+                title: row.get(2)?,
+                archived: row.get(3)?,
+                created_at: row.get(4)?,
+            })
+        })?;
+        Ok(row)
+    }
+
+    // latest not archived conversation of the active companion, a new one is created if there is none
+    pub fn get_default_conversation() -> Result<Conversation> {
+        let companion_id = Database::get_active_companion_id()?;
+        let con = Connection::open("companion_database.db")?;
+        let conversation_id: Option<i32> = match con.query_row(
+            "SELECT id FROM conversations WHERE companion_id = ? AND archived = 0 ORDER BY id DESC LIMIT 1",
+            [companion_id],
+            |row| row.get(0)
+        ) {
+            Ok(id) => Some(id),
+            Err(Error::QueryReturnedNoRows) => None,
+            Err(e) => return Err(e),
+        };
+        let conversation_id = match conversation_id {
+            Some(id) => id,
+            None => Database::create_conversation_with(&con, companion_id, "New conversation")?,
+        };
+        Database::get_conversation(conversation_id)
+    }
+
+    pub fn edit_conversation(id: i32, conversation: ConversationModify) -> Result<(), Error> {
+        let con = Connection::open("companion_database.db")?;
+        let changed = con.execute(
+            "UPDATE conversations SET title = COALESCE(?, title), archived = COALESCE(?, archived) WHERE id = ?",
+            rusqlite::params![conversation.title, conversation.archived, id]
+        )?;
+        if changed == 0 {
+            return Err(Error::QueryReturnedNoRows);
+        }
+        Ok(())
+    }
+
+    pub fn delete_conversation(id: i32) -> Result<(), Error> {
         let con = Connection::open("companion_database.db")?;
+        let changed = con.execute(
+            "DELETE FROM conversations WHERE id = ?",
+            [id]
+        )?;
+        if changed == 0 {
+            return Err(Error::QueryReturnedNoRows);
+        }
+        con.execute(
+            "DELETE FROM messages WHERE conversation_id = ?",
+            [id]
+        )?;
+        Database::delete_orphan_alternatives(&con)
+    }
+
+    fn prompt_template_from_row(row: &rusqlite::Row) -> Result<PromptTemplateData> {
+        let stop_sequences: String = row.get(6)?;
+        Ok(PromptTemplateData {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            builtin: row.get(7)?,
+            format: TemplateFormat {
+                system_block: row.get(2)?,
+                companion_block: row.get::<_, Option<String>>(8)?.unwrap_or_default(),
+                user_turn: row.get(3)?,
+                ai_turn: row.get(4)?,
+                memory_entry: row.get(5)?,
+                stop_sequences: serde_json::from_str(&stop_sequences).unwrap_or_default(),
+            },
+        })
+    }
+
+    pub fn get_prompt_templates() -> Result<Vec<PromptTemplateData>> {
+        let con = Connection::open("companion_database.db")?;
+        let mut stmt = con.prepare("SELECT id, name, system_block, user_turn, ai_turn, memory_entry, stop_sequences, builtin, companion_block FROM prompt_templates ORDER BY builtin DESC, id ASC")?;
+        let rows = stmt.query_map([], Database::prompt_template_from_row)?;
+        let mut templates = Vec::new();
+        for row in rows {
+            templates.push(row?);
+        }
+        Ok(templates)
+    }
+
+    pub fn get_prompt_template(id: i32) -> Result<PromptTemplateData> {
+        let con = Connection::open("companion_database.db")?;
+        let mut stmt = con.prepare("SELECT id, name, system_block, user_turn, ai_turn, memory_entry, stop_sequences, builtin, companion_block FROM prompt_templates WHERE id = ?")?;
+        stmt.query_row([id], Database::prompt_template_from_row)
+    }
+
+    // parts of a stored template, Custom uses custom_template_id and others use the preset with the same name.
+    // Auto has to be resolved from the model first
+    pub fn get_template_format(template: &PromptTemplate, custom_template_id: Option<i32>) -> Result<TemplateFormat> {
+        let con = Connection::open("companion_database.db")?;
+        let row = match template {
+            PromptTemplate::Custom => {
+                let id = custom_template_id.ok_or(Error::QueryReturnedNoRows)?;
+                let mut stmt = con.prepare("SELECT id, name, system_block, user_turn, ai_turn, memory_entry, stop_sequences, builtin, companion_block FROM prompt_templates WHERE id = ?")?;
+                stmt.query_row([id], Database::prompt_template_from_row)?
+            }
+            PromptTemplate::Auto => return Err(Error::InvalidParameterName("Auto prompt template is not stored in database".to_string())),
+            _ => {
+                let mut stmt = con.prepare("SELECT id, name, system_block, user_turn, ai_turn, memory_entry, stop_sequences, builtin, companion_block FROM prompt_templates WHERE builtin = 1 AND name = ?")?;
+                stmt.query_row([template.name()], Database::prompt_template_from_row)?
+            }
+        };
+        Ok(row.format)
+    }
+
+    pub fn insert_prompt_template(template: PromptTemplateView) -> Result<i32, Error> {
+        template.format.validate().map_err(Error::InvalidParameterName)?;
+        let con = Connection::open("companion_database.db")?;
+        let stop_sequences = serde_json::to_string(&template.format.stop_sequences).unwrap_or(String::from("[]"));
+        con.execute(
+            "INSERT INTO prompt_templates (name, system_block, companion_block, user_turn, ai_turn, memory_entry, stop_sequences, builtin) VALUES (?, ?, ?, ?, ?, ?, ?, 0)",
+            [&template.name, &template.format.system_block, &template.format.companion_block, &template.format.user_turn, &template.format.ai_turn, &template.format.memory_entry, &stop_sequences]
+        )?;
+        Ok(con.last_insert_rowid() as i32)
+    }
+
+    pub fn edit_prompt_template(id: i32, template: PromptTemplateView) -> Result<(), Error> {
+        template.format.validate().map_err(Error::InvalidParameterName)?;
+        if Database::get_prompt_template(id)?.builtin {
+            return Err(Error::InvalidParameterName("Built-in prompt templates can't be edited, create a new template instead".to_string()));
+        }
+        let con = Connection::open("companion_database.db")?;
+        let stop_sequences = serde_json::to_string(&template.format.stop_sequences).unwrap_or(String::from("[]"));
+        con.execute(
+            "UPDATE prompt_templates SET name = ?, system_block = ?, companion_block = ?, user_turn = ?, ai_turn = ?, memory_entry = ?, stop_sequences = ? WHERE id = ?",
+            rusqlite::params![template.name, template.format.system_block, template.format.companion_block, template.format.user_turn, template.format.ai_turn, template.format.memory_entry, stop_sequences, id]
+        )?;
+        Ok(())
+    }
+
+    pub fn delete_prompt_template(id: i32) -> Result<(), Error> {
+        if Database::get_prompt_template(id)?.builtin {
+            return Err(Error::InvalidParameterName("Built-in prompt templates can't be deleted".to_string()));
+        }
+        let con = Connection::open("companion_database.db")?;
+        let in_use: bool = con.query_row(
+            "SELECT COUNT(*) > 0 FROM config WHERE prompt_template = 'Custom' AND custom_template_id = ?",
+            [id],
+            |row| row.get(0)
+        )?;
+        if in_use {
+            return Err(Error::InvalidParameterName("Prompt template is used in config, select another template first".to_string()));
+        }
+        con.execute(
+            "DELETE FROM prompt_templates WHERE id = ?",
+            [id]
+        )?;
+        Ok(())
+    }
+
+    fn validate_memory_settings(companion: &CompanionView) -> Result<(), Error> {
+        if companion.memory_min_score.is_some_and(|s| !(0.0..=1.0).contains(&s)) {
+            return Err(rusqlite::Error::InvalidParameterName("Minimum memory score must be between 0 and 1".to_string()));
+        }
+        if companion.memory_half_life_days.is_some_and(|d| d < 0.0) {
+            return Err(rusqlite::Error::InvalidParameterName("Memory half-life can't be negative".to_string()));
+        }
+        Ok(())
+    }
+
+    pub fn insert_companion(companion: CompanionView) -> Result<i32, Error> {
+        Database::validate_memory_settings(&companion)?;
+        let con = Connection::open("companion_database.db")?;
+        Database::insert_companion_with(&con, &companion)
+    }
+
+    fn insert_companion_with(con: &Connection, companion: &CompanionView) -> Result<i32, Error> {
         con.execute(
-            &format!("UPDATE companion SET name = ?, persona = ?, example_dialogue = ?, first_message = ?, long_term_mem = {}, short_term_mem = {}, roleplay = {}, dialogue_tuning = {}, avatar_path = ?", companion.long_term_mem, companion.short_term_mem, companion.roleplay, companion.dialogue_tuning),
-            &[
-                &companion.name,
+            "INSERT INTO companion (name, persona, example_dialogue, first_message, long_term_mem, short_term_mem, roleplay, dialogue_tuning, avatar_path, active, memory_min_score, memory_half_life_days) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, 0, COALESCE(?, 0), COALESCE(?, 0))",
+            [
+                &companion.name as &dyn ToSql,
                 &companion.persona,
                 &companion.example_dialogue,
                 &companion.first_message,
+                &companion.long_term_mem,
+                &companion.short_term_mem,
+                &companion.roleplay,
+                &companion.dialogue_tuning,
                 &companion.avatar_path,
+                &companion.memory_min_score,
+                &companion.memory_half_life_days,
             ]
         )?;
-        Ok(())
+        Ok(con.last_insert_rowid() as i32)
+    }
+
+    pub fn edit_companion(companion: CompanionView) -> Result<(), Error> {
+        Database::edit_companion_by_id(Database::get_active_companion_id()?, companion)
     }
 
-    pub fn import_character_json(companion: CharacterCard) -> Result<(), Error> {
+    pub fn edit_companion_by_id(id: i32, companion: CompanionView) -> Result<(), Error> {
+        Database::validate_memory_settings(&companion)?;
         let con = Connection::open("companion_database.db")?;
-        con.execute(
-            "UPDATE companion SET name = ?, persona = ?, example_dialogue = ?, first_message = ?",
-            &[
-                &companion.name,
-                &companion.description,
-                &companion.mes_example,
-                &companion.first_mes
+        let changed = con.execute(
+            "UPDATE companion SET name = ?, persona = ?, example_dialogue = ?, first_message = ?, long_term_mem = ?, short_term_mem = ?, roleplay = ?, dialogue_tuning = ?, avatar_path = ?,
+                memory_min_score = COALESCE(?, memory_min_score), memory_half_life_days = COALESCE(?, memory_half_life_days) WHERE id = ?",
+            [
+                &companion.name as &dyn ToSql,
+                &companion.persona,
+                &companion.example_dialogue,
+                &companion.first_message,
+                &companion.long_term_mem,
+                &companion.short_term_mem,
+                &companion.roleplay,
+                &companion.dialogue_tuning,
+                &companion.avatar_path,
+                &companion.memory_min_score,
+                &companion.memory_half_life_days,
+                &id,
             ]
         )?;
+        if changed == 0 {
+            return Err(Error::QueryReturnedNoRows);
+        }
         Ok(())
     }
 
-    pub fn import_character_card(companion: CharacterCard, image_path: &str) -> Result<(), Error> {
+    // the last companion can't be deleted, if the active one is deleted another one becomes active
+    pub fn delete_companion(id: i32) -> Result<(), Error> {
+        let mut con = Connection::open("companion_database.db")?;
+        let transaction = con.transaction()?;
+        let count: i64 = transaction.query_row("SELECT COUNT(*) FROM companion", [], |row| row.get(0))?;
+        if count <= 1 {
+            return Err(rusqlite::Error::InvalidParameterName("Can't delete the only companion".to_string()));
+        }
+        let changed = transaction.execute(
+            "DELETE FROM companion WHERE id = ?",
+            [id],
+        )?;
+        if changed == 0 {
+            return Err(Error::QueryReturnedNoRows);
+        }
+        transaction.execute(
+            "DELETE FROM messages WHERE conversation_id IN (SELECT id FROM conversations WHERE companion_id = ?)",
+            [id],
+        )?;
+        transaction.execute(
+            "DELETE FROM conversations WHERE companion_id = ?",
+            [id],
+        )?;
+        Database::delete_orphan_alternatives(&transaction)?;
+        Database::ensure_active_companion(&transaction)?;
+        transaction.commit()
+    }
+
+    pub fn set_active_companion(id: i32) -> Result<(), Error> {
         let con = Connection::open("companion_database.db")?;
+        let exists: i64 = con.query_row("SELECT COUNT(*) FROM companion WHERE id = ?", [id], |row| row.get(0))?;
+        if exists == 0 {
+            return Err(Error::QueryReturnedNoRows);
+        }
         con.execute(
-            "UPDATE companion SET name = ?, persona = ?, example_dialogue = ?, first_message = ?, avatar_path = ?",
-            &[
-                &companion.name,
-                &companion.description,
-                &companion.mes_example,
-                &companion.first_mes,
-                image_path
-            ]
+            "UPDATE companion SET active = (id = ?)",
+            [id],
         )?;
         Ok(())
     }
-        
 
-    pub fn change_companion_avatar(avatar_path: &str) -> Result<(), Error> {
+    // imported characters are added as new companions, with default memory settings
+    pub fn import_character_json(companion: CharacterCard) -> Result<i32, Error> {
+        Database::insert_companion(CompanionView::new(&companion.name, &companion.description, &companion.mes_example, &companion.first_mes))
+    }
+
+    pub fn change_companion_avatar(id: i32, avatar_path: &str) -> Result<(), Error> {
         let con = Connection::open("companion_database.db")?;
         con.execute(
-            "UPDATE companion SET avatar_path = ?",
-            &[
-                avatar_path,
-            ]
+            "UPDATE companion SET avatar_path = ? WHERE id = ?",
+            rusqlite::params![avatar_path, id]
         )?;
         Ok(())
     }
@@ -540,7 +1429,7 @@ impl Database {
         let con = Connection::open("companion_database.db")?;
         con.execute(
             "UPDATE user SET name = ?, persona = ?",
-            &[
+            [
                 &user.name,
                 &user.persona,
             ]
@@ -550,13 +1439,27 @@ impl Database {
 
     pub fn get_config() -> Result<ConfigView> {
         let con = Connection::open("companion_database.db")?;
-        let mut stmt = con.prepare("SELECT device, llm_model_path, gpu_layers, prompt_template FROM config LIMIT 1")?;
+        let mut stmt = con.prepare("SELECT device, llm_model_path, gpu_layers, prompt_template, temperature, top_k, top_p, repeat_penalty, repeat_last_n, max_new_tokens, seed, custom_template_id, context_size, response_budget, keyword_weight, semantic_weight, fact_extraction, fact_min_confidence FROM config LIMIT 1")?;
         let row = stmt.query_row([], |row| {
             Ok(ConfigView {
                 device: row.get(0)?,
                 llm_model_path: row.get(1)?,
                 gpu_layers: row.get(2)?,
-                prompt_template: row.get(3)?
+                prompt_template: row.get(3)?,
+                custom_template_id: row.get(11)?,
+                temperature: row.get(4)?,
+                top_k: row.get(5)?,
+                top_p: row.get(6)?,
+                repeat_penalty: row.get(7)?,
+                repeat_last_n: row.get(8)?,
+                max_new_tokens: row.get(9)?,
+                seed: row.get(10)?,
+                context_size: row.get(12)?,
+                response_budget: row.get(13)?,
+                keyword_weight: row.get(14)?,
+                semantic_weight: row.get(15)?,
+                fact_extraction: row.get(16)?,
+                fact_min_confidence: row.get(17)?,
             })
         })?;
         Ok(row)
@@ -574,17 +1477,84 @@ impl Database {
             "Default" => PromptTemplate::Default,
             "Llama2" => PromptTemplate::Llama2,
             "Mistral" => PromptTemplate::Mistral,
+            "ChatML" => PromptTemplate::ChatML,
+            "Llama3" => PromptTemplate::Llama3,
+            "Alpaca" => PromptTemplate::Alpaca,
+            "Vicuna" => PromptTemplate::Vicuna,
+            "Zephyr" => PromptTemplate::Zephyr,
+            "Gemma" => PromptTemplate::Gemma,
+            "Custom" => PromptTemplate::Custom,
+            "Auto" => PromptTemplate::Auto,
             _ => return Err(rusqlite::Error::InvalidParameterName("Invalid prompt template type".to_string())),
         };
+
+        if config.temperature.is_some_and(|t| t < 0.0) {
+            return Err(rusqlite::Error::InvalidParameterName("Temperature can't be negative".to_string()));
+        }
+        if config.top_p.is_some_and(|p| p <= 0.0 || p > 1.0) {
+            return Err(rusqlite::Error::InvalidParameterName("top_p must be greater than 0 and at most 1".to_string()));
+        }
+        if config.repeat_penalty.is_some_and(|p| p <= 0.0) {
+            return Err(rusqlite::Error::InvalidParameterName("Repeat penalty must be greater than 0".to_string()));
+        }
+        if config.seed.is_some_and(|s| s < -1) {
+            return Err(rusqlite::Error::InvalidParameterName("Seed must be -1 (random) or a non-negative number".to_string()));
+        }
+        if config.keyword_weight.is_some_and(|w| w < 0.0) || config.semantic_weight.is_some_and(|w| w < 0.0) {
+            return Err(rusqlite::Error::InvalidParameterName("Memory weights can't be negative".to_string()));
+        }
+        if config.fact_min_confidence.is_some_and(|c| !(0.0..=1.0).contains(&c)) {
+            return Err(rusqlite::Error::InvalidParameterName("Minimum fact confidence must be between 0 and 1".to_string()));
+        }
     
+        let current = Database::get_config()?;
+        let context_size = config.context_size.unwrap_or(current.context_size);
+        let response_budget = config.response_budget.unwrap_or(current.response_budget);
+        if context_size == 0 {
+            return Err(rusqlite::Error::InvalidParameterName("Context size must be greater than 0".to_string()));
+        }
+        if response_budget >= context_size {
+            return Err(rusqlite::Error::InvalidParameterName("Response budget must be smaller than context size".to_string()));
+        }
+
         let con = Connection::open("companion_database.db")?;
+        if prompt_template == PromptTemplate::Custom {
+            let exists: bool = con.query_row(
+                "SELECT COUNT(*) > 0 FROM prompt_templates WHERE id = COALESCE(?, (SELECT custom_template_id FROM config LIMIT 1))",
+                [&config.custom_template_id],
+                |row| row.get(0)
+            )?;
+            if !exists {
+                return Err(rusqlite::Error::InvalidParameterName("Custom prompt template requires custom_template_id of an existing template".to_string()));
+            }
+        }
         con.execute(
-            "UPDATE config SET device = ?, llm_model_path = ?, gpu_layers = ?, prompt_template = ?",
-            &[
+            "UPDATE config SET device = ?, llm_model_path = ?, gpu_layers = ?, prompt_template = ?, custom_template_id = COALESCE(?, custom_template_id),
+                temperature = COALESCE(?, temperature), top_k = COALESCE(?, top_k), top_p = COALESCE(?, top_p),
+                repeat_penalty = COALESCE(?, repeat_penalty), repeat_last_n = COALESCE(?, repeat_last_n),
+                max_new_tokens = COALESCE(?, max_new_tokens), seed = COALESCE(?, seed),
+                context_size = ?, response_budget = ?,
+                keyword_weight = COALESCE(?, keyword_weight), semantic_weight = COALESCE(?, semantic_weight),
+                fact_extraction = COALESCE(?, fact_extraction), fact_min_confidence = COALESCE(?, fact_min_confidence)",
+            [
                 &device as &dyn ToSql,
                 &config.llm_model_path,
                 &config.gpu_layers,
                 &prompt_template as &dyn ToSql,
+                &config.custom_template_id,
+                &config.temperature,
+                &config.top_k,
+                &config.top_p,
+                &config.repeat_penalty,
+                &config.repeat_last_n,
+                &config.max_new_tokens,
+                &config.seed,
+                &context_size,
+                &response_budget,
+                &config.keyword_weight,
+                &config.semantic_weight,
+                &config.fact_extraction,
+                &config.fact_min_confidence,
             ]
         )?;
         Ok(())
@@ -0,0 +1,79 @@
+use rusqlite::{params, Connection, Error, Result};
+
+// embeddings of long-term memory entries, stored next to the tantivy index by entry id.
+// vectors are only comparable when made by the same model, so every vector keeps the path of its model
+pub struct EmbeddingStore { }
+
+fn to_blob(vector: &[f32]) -> Vec<u8> {
+    vector.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+fn from_blob(blob: &[u8]) -> Vec<f32> {
+    blob.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect()
+}
+
+// vectors are normalized when they are made, so the dot product is the cosine similarity
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+pub fn normalize(vector: &mut [f32]) {
+    let length = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if length > 0.0 {
+        vector.iter_mut().for_each(|v| *v /= length);
+    }
+}
+
+impl EmbeddingStore {
+    pub fn create() -> Result<usize, Error> {
+        let con = Connection::open("companion_database.db")?;
+        con.execute("CREATE TABLE IF NOT EXISTS memory_embeddings (
+            entry_id TEXT,
+            model TEXT,
+            companion_id INTEGER,
+            vector BLOB,
+            PRIMARY KEY (entry_id, model)
+        )", [])
+    }
+
+    pub fn set(entry_id: &str, model: &str, companion_id: i32, vector: &[f32]) -> Result<usize, Error> {
+        let con = Connection::open("companion_database.db")?;
+        con.execute(
+            "INSERT OR REPLACE INTO memory_embeddings (entry_id, model, companion_id, vector) VALUES (?, ?, ?, ?)",
+            params![entry_id, model, companion_id, to_blob(vector)],
+        )
+    }
+
+    // (entry id, vector) of all entries of the companion embedded with the model
+    pub fn get_for_companion(companion_id: i32, model: &str) -> Result<Vec<(String, Vec<f32>)>, Error> {
+        let con = Connection::open("companion_database.db")?;
+        let mut stmt = con.prepare("SELECT entry_id, vector FROM memory_embeddings WHERE companion_id = ? AND model = ?")?;
+        let rows = stmt.query_map(params![companion_id, model], |row| {
+            let blob: Vec<u8> = row.get(1)?;
+            Ok((row.get(0)?, from_blob(&blob)))
+        })?;
+        rows.collect()
+    }
+
+    // (entry id, companion id) of entries embedded with the model
+    pub fn get_ids(model: &str) -> Result<Vec<(String, i32)>, Error> {
+        let con = Connection::open("companion_database.db")?;
+        let mut stmt = con.prepare("SELECT entry_id, companion_id FROM memory_embeddings WHERE model = ?")?;
+        let rows = stmt.query_map([model], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        rows.collect()
+    }
+
+    // vectors of the entry made by every model
+    pub fn delete(entry_id: &str) -> Result<usize, Error> {
+        let con = Connection::open("companion_database.db")?;
+        con.execute("DELETE FROM memory_embeddings WHERE entry_id = ?", [entry_id])
+    }
+
+    pub fn delete_companion(companion_id: i32) -> Result<usize, Error> {
+        let con = Connection::open("companion_database.db")?;
+        con.execute("DELETE FROM memory_embeddings WHERE companion_id = ?", [companion_id])
+    }
+}
@@ -0,0 +1,130 @@
+use std::sync::atomic::AtomicBool;
+
+use crate::consolidation::with_placeholders;
+use crate::database::{Database, io_error};
+use crate::facts::Facts;
+use crate::llm::{generate, template_format};
+use crate::model_manager::ModelManager;
+
+// after an ai reply the model reads the last exchange and writes down what it learned about the user,
+// facts are saved with how confident the model is about them
+
+const INSTRUCTION: &str = "Read the last exchange between {{user}} and {{char}} and write down new lasting facts about {{user}}: name, age, job, home, preferences, hobbies, relationships, pets, important dates and plans. Skip anything temporary, like the current mood, and anything already in the known facts. Write one fact per line as \"- [confidence] fact\", where confidence is a number from 0 to 1 telling how sure you are the fact is true, for example \"- [0.9] {{user}} has a dog called Rex\". If there are no new facts, write \"- nothing\".";
+
+// facts are short, a long response means the model is not writing a list
+const EXTRACTION_BUDGET: usize = 256;
+// confidence of facts written without one
+const DEFAULT_CONFIDENCE: f32 = 0.5;
+
+// (fact, confidence) from lines like "- [0.9] fact"
+fn parse_facts(text: &str) -> Vec<(String, f32)> {
+    text.lines()
+        .filter_map(|line| line.trim().strip_prefix("- "))
+        .filter_map(|item| {
+            let item = item.trim();
+            let (confidence, fact) = match item.strip_prefix('[').and_then(|rest| rest.split_once(']')) {
+                Some((number, fact)) => (number.trim().parse::<f32>().ok().filter(|c| c.is_finite()).map(|c| c.clamp(0.0, 1.0)).unwrap_or(DEFAULT_CONFIDENCE), fact.trim()),
+                None => (DEFAULT_CONFIDENCE, item),
+            };
+            if fact.is_empty() || fact.trim_end_matches('.').eq_ignore_ascii_case("nothing") {
+                return None;
+            }
+            Some((fact.to_string(), confidence))
+        })
+        .collect()
+}
+
+fn same_fact(a: &str, b: &str) -> bool {
+    a.trim().trim_end_matches('.').eq_ignore_ascii_case(b.trim().trim_end_matches('.'))
+}
+
+// returns number of new facts, 0 when extraction is turned off in config or the message is not from ai
+pub fn extract_facts(message_id: i32, model_manager: &ModelManager, cancel: &AtomicBool) -> Result<usize, std::io::Error> {
+    let mut config = Database::get_config().map_err(io_error)?;
+    if !config.fact_extraction {
+        return Ok(0);
+    }
+    let message = Database::get_message(message_id).map_err(io_error)?;
+    if !message.ai {
+        return Ok(0);
+    }
+    let previous = Database::get_parent_message(message_id).map(|m| m.content).unwrap_or_default();
+    let companion_id = Database::get_message_companion_id(message_id).map_err(io_error)?;
+    let companion = Database::get_companion(companion_id).map_err(io_error)?;
+    let user = Database::get_user_data().map_err(io_error)?;
+    let template = template_format(&config).map_err(io_error)?;
+    let llama = model_manager.get_for_config(&config)?;
+    let known = Facts::get_facts(companion_id, 0.0).map_err(io_error)?;
+
+    let with_names = |text: &str| text.replace("{{user}}", &user.name).replace("{{char}}", &companion.name);
+    let known_text: String = known.iter().map(|f| format!("- {}\n", with_names(&f.content))).collect();
+    let request = format!("Known facts:\n{}\nLast exchange:\n{}: {}\n{}: {}",
+        if known_text.is_empty() { "- none\n" } else { &known_text }, user.name, previous, companion.name, message.content);
+    let prompt_text = format!("{}{}{}",
+        template.render_system(&with_names(INSTRUCTION), &user.name, &companion.name),
+        template.render_message(false, &request, &user.name, &companion.name),
+        template.generation_prefix(&user.name, &companion.name));
+    // facts should be read from the conversation, not made up
+    config.temperature = config.temperature.min(0.3);
+    if config.max_new_tokens == 0 || config.max_new_tokens > EXTRACTION_BUDGET {
+        config.max_new_tokens = EXTRACTION_BUDGET;
+    }
+    let generation = generate(llama.as_ref(), &config, &prompt_text, &template.stop_sequences(&user.name, &companion.name), cancel, |_| {})?;
+    if generation.cancelled {
+        return Ok(0);
+    }
+
+    let mut learned: Vec<String> = Vec::new();
+    for (fact, confidence) in parse_facts(&generation.text) {
+        let fact = with_placeholders(&fact, &user.name, &companion.name);
+        if learned.iter().any(|f| same_fact(f, &fact)) {
+            continue;
+        }
+        match known.iter().find(|f| same_fact(&f.content, &fact)) {
+            Some(existing) => { Facts::raise_confidence(existing.id, confidence).map_err(io_error)?; }
+            None => {
+                Facts::insert(companion_id, &fact, confidence, Some(message_id)).map_err(io_error)?;
+                println!("Learned fact about {} ({:.2}): {}", user.name, confidence, fact);
+                learned.push(fact);
+            }
+        }
+    }
+    Ok(learned.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nothing_is_not_a_fact() {
+        assert!(parse_facts("- nothing").is_empty());
+        assert!(parse_facts("- Nothing.").is_empty());
+        assert!(parse_facts("- [0.9] nothing").is_empty());
+        assert!(parse_facts("no new facts").is_empty());
+    }
+
+    #[test]
+    fn confidence_is_read_from_brackets() {
+        assert_eq!(parse_facts("- [0.9] {{user}} has a dog called Rex\n- [0.3]{{user}} is 30"), vec![
+            ("{{user}} has a dog called Rex".to_string(), 0.9),
+            ("{{user}} is 30".to_string(), 0.3),
+        ]);
+    }
+
+    #[test]
+    fn missing_or_invalid_confidence() {
+        assert_eq!(parse_facts("- {{user}} likes tea"), vec![("{{user}} likes tea".to_string(), DEFAULT_CONFIDENCE)]);
+        assert_eq!(parse_facts("- [high] {{user}} likes tea"), vec![("{{user}} likes tea".to_string(), DEFAULT_CONFIDENCE)]);
+        assert_eq!(parse_facts("- [1.5] {{user}} likes tea"), vec![("{{user}} likes tea".to_string(), 1.0)]);
+        assert_eq!(parse_facts("- [nan] {{user}} likes tea"), vec![("{{user}} likes tea".to_string(), DEFAULT_CONFIDENCE)]);
+        assert_eq!(parse_facts("- [inf] {{user}} likes tea"), vec![("{{user}} likes tea".to_string(), DEFAULT_CONFIDENCE)]);
+        assert!(parse_facts("- [0.8]").is_empty());
+    }
+
+    #[test]
+    fn same_fact_ignores_case_and_final_dot() {
+        assert!(same_fact("Ann likes tea.", " ann likes TEA"));
+        assert!(!same_fact("Ann likes tea", "Ann likes green tea"));
+    }
+}
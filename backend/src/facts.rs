@@ -0,0 +1,117 @@
+use rusqlite::{params, Connection, Error, Result, ToSql};
+use serde::{Serialize, Deserialize};
+
+use crate::database::get_current_date;
+
+// facts about the user learned by a companion, written with {{user}} and {{char}} in place of names
+#[derive(Serialize)]
+pub struct Fact {
+    pub id: i32,
+    pub companion_id: i32,
+    pub content: String,
+    // 0 to 1, how sure the model was, facts added over the API have 1 by default
+    pub confidence: f32,
+    // ai message the fact was learned from, missing in facts added over the API
+    pub source_message_id: Option<i32>,
+    pub created_at: String,
+}
+
+#[derive(Deserialize)]
+pub struct NewFact {
+    pub content: String,
+    pub confidence: Option<f32>,
+}
+
+// fields that are not sent keep their current value
+#[derive(Deserialize)]
+pub struct FactModify {
+    pub content: Option<String>,
+    pub confidence: Option<f32>,
+}
+
+pub struct Facts { }
+
+impl Facts {
+    pub fn create() -> Result<usize, Error> {
+        let con = Connection::open("companion_database.db")?;
+        con.execute("CREATE TABLE IF NOT EXISTS facts (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            companion_id INTEGER,
+            content TEXT,
+            confidence REAL,
+            source_message_id INTEGER,
+            created_at TEXT
+        )", [])
+    }
+
+    fn validate(content: Option<&str>, confidence: Option<f32>) -> Result<(), Error> {
+        if content.is_some_and(|c| c.trim().is_empty()) {
+            return Err(Error::InvalidParameterName("Fact can't be empty".to_string()));
+        }
+        if confidence.is_some_and(|c| !(0.0..=1.0).contains(&c)) {
+            return Err(Error::InvalidParameterName("Confidence must be between 0 and 1".to_string()));
+        }
+        Ok(())
+    }
+
+    // most confident first
+    pub fn get_facts(companion_id: i32, min_confidence: f32) -> Result<Vec<Fact>, Error> {
+        let con = Connection::open("companion_database.db")?;
+        let mut stmt = con.prepare("SELECT id, companion_id, content, confidence, source_message_id, created_at FROM facts WHERE companion_id = ? AND confidence >= ? ORDER BY confidence DESC, id ASC")?;
+        let rows = stmt.query_map(params![companion_id, min_confidence], |row| {
+            Ok(Fact {
+                id: row.get(0)?,
+                companion_id: row.get(1)?,
+                content: row.get(2)?,
+                confidence: row.get(3)?,
+                source_message_id: row.get(4)?,
+                created_at: row.get(5)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    pub fn insert(companion_id: i32, content: &str, confidence: f32, source_message_id: Option<i32>) -> Result<i32, Error> {
+        Facts::validate(Some(content), Some(confidence))?;
+        let con = Connection::open("companion_database.db")?;
+        con.execute(
+            "INSERT INTO facts (companion_id, content, confidence, source_message_id, created_at) VALUES (?, ?, ?, ?, ?)",
+            params![companion_id, content.trim(), confidence, source_message_id, get_current_date()],
+        )?;
+        Ok(con.last_insert_rowid() as i32)
+    }
+
+    pub fn edit(id: i32, fact: FactModify) -> Result<(), Error> {
+        Facts::validate(fact.content.as_deref(), fact.confidence)?;
+        let con = Connection::open("companion_database.db")?;
+        let content = fact.content.as_deref().map(|c| c.trim());
+        let changed = con.execute(
+            "UPDATE facts SET content = COALESCE(?, content), confidence = COALESCE(?, confidence) WHERE id = ?",
+            [&content as &dyn ToSql, &fact.confidence, &id],
+        )?;
+        if changed == 0 {
+            return Err(Error::QueryReturnedNoRows);
+        }
+        Ok(())
+    }
+
+    // a fact learned again only raises confidence of the one that is already known
+    pub fn raise_confidence(id: i32, confidence: f32) -> Result<usize, Error> {
+        let con = Connection::open("companion_database.db")?;
+        con.execute("UPDATE facts SET confidence = MAX(confidence, ?) WHERE id = ?", params![confidence, id])
+    }
+
+    pub fn delete(id: i32) -> Result<(), Error> {
+        let con = Connection::open("companion_database.db")?;
+        let changed = con.execute("DELETE FROM facts WHERE id = ?", [id])?;
+        if changed == 0 {
+            return Err(Error::QueryReturnedNoRows);
+        }
+        Ok(())
+    }
+
+    pub fn delete_companion(companion_id: i32) -> Result<usize, Error> {
+        let con = Connection::open("companion_database.db")?;
+        con.execute("DELETE FROM facts WHERE companion_id = ?", [companion_id])
+    }
+}
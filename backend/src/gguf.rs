@@ -0,0 +1,465 @@
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind, Read, Result};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use serde::Serialize;
+
+use crate::database::PromptTemplate;
+
+// metadata from the header of a gguf file, only the keys used by ai-companion are kept
+#[derive(Serialize, Clone)]
+pub struct GgufInfo {
+    pub version: u32,
+    pub architecture: Option<String>,
+    pub name: Option<String>,
+    pub context_length: Option<u64>,
+    pub chat_template: Option<String>,
+    pub bos_token: Option<String>,
+    pub eos_token: Option<String>,
+    // built-in prompt template that matches chat_template or special tokens of the model
+    pub suggested_template: Option<PromptTemplate>,
+}
+
+// strings longer than this are treated as a broken file instead of being allocated
+const MAX_STRING_LEN: u64 = 64 * 1024 * 1024;
+// arrays of arrays deeper than this are treated as a broken file
+const MAX_ARRAY_DEPTH: usize = 8;
+
+const TYPE_UINT8: u32 = 0;
+const TYPE_INT8: u32 = 1;
+const TYPE_UINT16: u32 = 2;
+const TYPE_INT16: u32 = 3;
+const TYPE_UINT32: u32 = 4;
+const TYPE_INT32: u32 = 5;
+const TYPE_FLOAT32: u32 = 6;
+const TYPE_BOOL: u32 = 7;
+const TYPE_STRING: u32 = 8;
+const TYPE_ARRAY: u32 = 9;
+const TYPE_UINT64: u32 = 10;
+const TYPE_INT64: u32 = 11;
+const TYPE_FLOAT64: u32 = 12;
+
+enum Value {
+    Int(i128),
+    Text(String),
+    Texts(Vec<String>),
+    Other,
+}
+
+struct GgufReader<R: Read> {
+    reader: R,
+    version: u32,
+}
+
+impl<R: Read> GgufReader<R> {
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut buf = [0u8; N];
+        self.reader.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes()?))
+    }
+
+    // version 1 used 32-bit lengths and counts
+    fn count(&mut self) -> Result<u64> {
+        if self.version == 1 { Ok(self.u32()? as u64) } else { self.u64() }
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.count()?;
+        if len > MAX_STRING_LEN {
+            return Err(Error::new(ErrorKind::InvalidData, "String in gguf metadata is too long"));
+        }
+        // buffer grows with the data that is really there, so a wrong length in a cut file doesn't allocate it
+        let mut buf = Vec::new();
+        (&mut self.reader).take(len).read_to_end(&mut buf)?;
+        if (buf.len() as u64) < len {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Unexpected end of gguf file"));
+        }
+        Ok(String::from_utf8_lossy(&buf).to_string())
+    }
+
+    fn skip(&mut self, n: u64) -> Result<()> {
+        let copied = std::io::copy(&mut (&mut self.reader).take(n), &mut std::io::sink())?;
+        if copied < n {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Unexpected end of gguf file"));
+        }
+        Ok(())
+    }
+
+    fn scalar_size(value_type: u32) -> Option<u64> {
+        match value_type {
+            TYPE_UINT8 | TYPE_INT8 | TYPE_BOOL => Some(1),
+            TYPE_UINT16 | TYPE_INT16 => Some(2),
+            TYPE_UINT32 | TYPE_INT32 | TYPE_FLOAT32 => Some(4),
+            TYPE_UINT64 | TYPE_INT64 | TYPE_FLOAT64 => Some(8),
+            _ => None,
+        }
+    }
+
+    // arrays of strings are kept only when needed, others are skipped
+    fn value(&mut self, value_type: u32, keep_texts: bool) -> Result<Value> {
+        self.value_at(value_type, keep_texts, 0)
+    }
+
+    fn value_at(&mut self, value_type: u32, keep_texts: bool, depth: usize) -> Result<Value> {
+        Ok(match value_type {
+            TYPE_UINT8 => Value::Int(self.bytes::<1>()?[0] as i128),
+            TYPE_INT8 => Value::Int(self.bytes::<1>()?[0] as i8 as i128),
+            TYPE_UINT16 => Value::Int(u16::from_le_bytes(self.bytes()?) as i128),
+            TYPE_INT16 => Value::Int(i16::from_le_bytes(self.bytes()?) as i128),
+            TYPE_UINT32 => Value::Int(self.u32()? as i128),
+            TYPE_INT32 => Value::Int(i32::from_le_bytes(self.bytes()?) as i128),
+            TYPE_UINT64 => Value::Int(self.u64()? as i128),
+            TYPE_INT64 => Value::Int(i64::from_le_bytes(self.bytes()?) as i128),
+            TYPE_FLOAT32 | TYPE_FLOAT64 | TYPE_BOOL => {
+                self.skip(GgufReader::<R>::scalar_size(value_type).unwrap_or(0))?;
+                Value::Other
+            }
+            TYPE_STRING => Value::Text(self.string()?),
+            TYPE_ARRAY => {
+                let item_type = self.u32()?;
+                let count = self.count()?;
+                if let Some(size) = GgufReader::<R>::scalar_size(item_type) {
+                    self.skip(size.saturating_mul(count))?;
+                    Value::Other
+                } else if item_type == TYPE_STRING {
+                    let mut texts = Vec::new();
+                    for _ in 0..count {
+                        let text = self.string()?;
+                        if keep_texts {
+                            texts.push(text);
+                        }
+                    }
+                    if keep_texts { Value::Texts(texts) } else { Value::Other }
+                } else if depth < MAX_ARRAY_DEPTH {
+                    for _ in 0..count {
+                        self.value_at(item_type, false, depth + 1)?;
+                    }
+                    Value::Other
+                } else {
+                    return Err(Error::new(ErrorKind::InvalidData, "Arrays in gguf metadata are nested too deep"));
+                }
+            }
+            _ => return Err(Error::new(ErrorKind::InvalidData, format!("Unknown gguf value type {}", value_type))),
+        })
+    }
+}
+
+pub fn read_gguf_info(path: &str) -> Result<GgufInfo> {
+    read_info(BufReader::new(File::open(path)?))
+}
+
+fn read_info(reader: impl Read) -> Result<GgufInfo> {
+    let mut reader = GgufReader { reader, version: 0 };
+    if &reader.bytes::<4>()? != b"GGUF" {
+        return Err(Error::new(ErrorKind::InvalidData, "File is not a gguf model"));
+    }
+    reader.version = reader.u32()?;
+    let _tensor_count = reader.count()?;
+    let kv_count = reader.count()?;
+
+    let mut info = GgufInfo {
+        version: reader.version,
+        architecture: None,
+        name: None,
+        context_length: None,
+        chat_template: None,
+        bos_token: None,
+        eos_token: None,
+        suggested_template: None,
+    };
+    let mut tokens: Vec<String> = Vec::new();
+    let mut bos_token_id: Option<usize> = None;
+    let mut eos_token_id: Option<usize> = None;
+    let mut context_lengths: Vec<(String, u64)> = Vec::new();
+    for _ in 0..kv_count {
+        let key = reader.string()?;
+        let value_type = reader.u32()?;
+        let value = reader.value(value_type, key == "tokenizer.ggml.tokens")?;
+        match (key.as_str(), value) {
+            ("general.architecture", Value::Text(t)) => info.architecture = Some(t),
+            ("general.name", Value::Text(t)) => info.name = Some(t),
+            ("tokenizer.chat_template", Value::Text(t)) => info.chat_template = Some(t),
+            ("tokenizer.ggml.tokens", Value::Texts(t)) => tokens = t,
+            ("tokenizer.ggml.bos_token_id", Value::Int(i)) => bos_token_id = Some(i as usize),
+            ("tokenizer.ggml.eos_token_id", Value::Int(i)) => eos_token_id = Some(i as usize),
+            (k, Value::Int(i)) if k.ends_with(".context_length") => context_lengths.push((k.to_string(), i as u64)),
+            _ => {}
+        }
+    }
+    // context length key is prefixed with the architecture, for example llama.context_length
+    if let Some(architecture) = &info.architecture {
+        let key = format!("{}.context_length", architecture);
+        info.context_length = context_lengths.iter().find(|(k, _)| *k == key).map(|(_, v)| *v);
+    }
+    info.bos_token = bos_token_id.and_then(|id| tokens.get(id).cloned());
+    info.eos_token = eos_token_id.and_then(|id| tokens.get(id).cloned());
+    info.suggested_template = suggest_template(&info);
+    Ok(info)
+}
+
+// picks the built-in template with the same delimiters as the jinja chat template of the model,
+// or the one the architecture always uses
+pub fn suggest_template(info: &GgufInfo) -> Option<PromptTemplate> {
+    let chat_template = info.chat_template.as_deref().unwrap_or("");
+    let eos_token = info.eos_token.as_deref().unwrap_or("");
+    if chat_template.contains("<|im_start|>") || eos_token == "<|im_end|>" {
+        Some(PromptTemplate::ChatML)
+    } else if chat_template.contains("<|start_header_id|>") || eos_token == "<|eot_id|>" {
+        Some(PromptTemplate::Llama3)
+    } else if chat_template.contains("<start_of_turn>") || eos_token == "<end_of_turn>" {
+        Some(PromptTemplate::Gemma)
+    } else if chat_template.contains("<|user|>") && chat_template.contains("<|assistant|>") {
+        Some(PromptTemplate::Zephyr)
+    } else if chat_template.contains("<<SYS>>") {
+        Some(PromptTemplate::Llama2)
+    } else if chat_template.contains("[INST]") {
+        Some(PromptTemplate::Mistral)
+    } else if chat_template.contains("### Instruction") {
+        Some(PromptTemplate::Alpaca)
+    } else if chat_template.contains("USER:") && chat_template.contains("ASSISTANT:") {
+        Some(PromptTemplate::Vicuna)
+    } else if info.architecture.as_deref().is_some_and(|a| a.starts_with("gemma")) {
+        // gemma models have one chat format, older files don't have a chat template
+        Some(PromptTemplate::Gemma)
+    } else {
+        None
+    }
+}
+
+// metadata of the last read file, read again only when the path or modification time changes
+static CACHE: Mutex<Option<(String, SystemTime, GgufInfo)>> = Mutex::new(None);
+
+pub fn cached_gguf_info(path: &str) -> Result<GgufInfo> {
+    let modified = std::fs::metadata(path)?.modified()?;
+    if let Ok(cache) = CACHE.lock() {
+        if let Some((cached_path, cached_modified, info)) = cache.as_ref() {
+            if cached_path == path && *cached_modified == modified {
+                return Ok(info.clone());
+            }
+        }
+    }
+    let info = read_gguf_info(path)?;
+    if let Ok(mut cache) = CACHE.lock() {
+        *cache = Some((path.to_string(), modified, info.clone()));
+    }
+    Ok(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // metadata part of a gguf file, tensors are not needed
+    struct Gguf {
+        version: u32,
+        kv_count: u64,
+        bytes: Vec<u8>,
+    }
+
+    impl Gguf {
+        fn new(version: u32) -> Self {
+            Gguf { version, kv_count: 0, bytes: Vec::new() }
+        }
+
+        fn count(&mut self, n: u64) -> &mut Self {
+            if self.version == 1 {
+                self.bytes.extend((n as u32).to_le_bytes());
+            } else {
+                self.bytes.extend(n.to_le_bytes());
+            }
+            self
+        }
+
+        fn u32(&mut self, n: u32) -> &mut Self {
+            self.bytes.extend(n.to_le_bytes());
+            self
+        }
+
+        fn string(&mut self, text: &str) -> &mut Self {
+            self.count(text.len() as u64);
+            self.bytes.extend(text.as_bytes());
+            self
+        }
+
+        fn key(&mut self, key: &str, value_type: u32) -> &mut Self {
+            self.kv_count += 1;
+            self.string(key).u32(value_type)
+        }
+
+        fn text(&mut self, key: &str, value: &str) -> &mut Self {
+            self.key(key, TYPE_STRING).string(value)
+        }
+
+        fn texts(&mut self, key: &str, values: &[&str]) -> &mut Self {
+            self.key(key, TYPE_ARRAY).u32(TYPE_STRING).count(values.len() as u64);
+            for value in values {
+                self.string(value);
+            }
+            self
+        }
+
+        fn build(&self) -> Vec<u8> {
+            let mut file = b"GGUF".to_vec();
+            file.extend(self.version.to_le_bytes());
+            let mut counts = Gguf::new(self.version);
+            counts.count(0).count(self.kv_count);
+            file.extend(&counts.bytes);
+            file.extend(&self.bytes);
+            file
+        }
+    }
+
+    fn chatml_model(version: u32) -> Vec<u8> {
+        let mut gguf = Gguf::new(version);
+        gguf.text("general.architecture", "qwen2")
+            .text("general.name", "Test model")
+            .key("qwen2.context_length", TYPE_UINT32).u32(32768)
+            .key("llama.context_length", TYPE_UINT32).u32(4096)
+            .key("general.alignment", TYPE_FLOAT32).u32(0)
+            .key("tokenizer.ggml.scores", TYPE_ARRAY).u32(TYPE_FLOAT32).count(3).u32(0).u32(0).u32(0)
+            .texts("tokenizer.ggml.tokens", &["<unk>", "<|endoftext|>", "<|im_end|>"])
+            .key("tokenizer.ggml.bos_token_id", TYPE_UINT32).u32(1)
+            .key("tokenizer.ggml.eos_token_id", TYPE_UINT32).u32(2)
+            .text("tokenizer.chat_template", "{% for m in messages %}<|im_start|>{{ m.role }}{% endfor %}");
+        gguf.build()
+    }
+
+    fn error_kind(bytes: &[u8]) -> ErrorKind {
+        match read_info(bytes) {
+            Ok(_) => panic!("broken gguf was read"),
+            Err(e) => e.kind(),
+        }
+    }
+
+    #[test]
+    fn reads_metadata_from_valid_header() {
+        for version in [1, 2, 3] {
+            let info = read_info(chatml_model(version).as_slice()).unwrap();
+            assert_eq!(info.version, version);
+            assert_eq!(info.architecture.as_deref(), Some("qwen2"));
+            assert_eq!(info.name.as_deref(), Some("Test model"));
+            assert_eq!(info.context_length, Some(32768));
+            assert_eq!(info.bos_token.as_deref(), Some("<|endoftext|>"));
+            assert_eq!(info.eos_token.as_deref(), Some("<|im_end|>"));
+            assert!(info.suggested_template == Some(PromptTemplate::ChatML));
+        }
+    }
+
+    #[test]
+    fn truncated_file_is_an_error() {
+        let file = chatml_model(3);
+        for len in 0..file.len() {
+            assert!(read_info(&file[..len]).is_err(), "file cut at {} bytes was read", len);
+        }
+    }
+
+    #[test]
+    fn bad_magic_is_an_error() {
+        let mut file = chatml_model(3);
+        file[..4].copy_from_slice(b"GGML");
+        assert_eq!(error_kind(&file), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn oversized_lengths_are_errors() {
+        // longer than the limit
+        let mut gguf = Gguf::new(3);
+        gguf.kv_count = 1;
+        gguf.count(u64::MAX);
+        assert_eq!(error_kind(&gguf.build()), ErrorKind::InvalidData);
+        // within the limit, but longer than the file
+        let mut gguf = Gguf::new(3);
+        gguf.kv_count = 1;
+        gguf.count(MAX_STRING_LEN).string("short");
+        assert_eq!(error_kind(&gguf.build()), ErrorKind::UnexpectedEof);
+        // arrays with more items than the file has
+        let mut gguf = Gguf::new(3);
+        gguf.key("tokenizer.ggml.tokens", TYPE_ARRAY).u32(TYPE_STRING).count(u64::MAX).string("<s>");
+        assert_eq!(error_kind(&gguf.build()), ErrorKind::UnexpectedEof);
+        let mut gguf = Gguf::new(3);
+        gguf.key("tokenizer.ggml.scores", TYPE_ARRAY).u32(TYPE_UINT64).count(u64::MAX);
+        assert_eq!(error_kind(&gguf.build()), ErrorKind::UnexpectedEof);
+        let mut gguf = Gguf::new(3);
+        gguf.kv_count = u64::MAX;
+        assert_eq!(error_kind(&gguf.build()), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn deeply_nested_arrays_are_errors() {
+        let mut gguf = Gguf::new(3);
+        gguf.key("nested", TYPE_ARRAY);
+        for _ in 0..100 {
+            gguf.u32(TYPE_ARRAY).count(1);
+        }
+        assert_eq!(error_kind(&gguf.build()), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn unknown_value_type_is_an_error() {
+        let mut gguf = Gguf::new(3);
+        gguf.key("general.unknown", 99).u32(0);
+        assert_eq!(error_kind(&gguf.build()), ErrorKind::InvalidData);
+    }
+
+    fn suggested(architecture: Option<&str>, chat_template: Option<&str>, eos_token: Option<&str>) -> Option<PromptTemplate> {
+        suggest_template(&GgufInfo {
+            version: 3,
+            architecture: architecture.map(String::from),
+            name: None,
+            context_length: None,
+            chat_template: chat_template.map(String::from),
+            bos_token: None,
+            eos_token: eos_token.map(String::from),
+            suggested_template: None,
+        })
+    }
+
+    #[test]
+    fn chat_template_and_architecture_map_to_presets() {
+        let cases = [
+            (Some("llama"), Some("<|start_header_id|>{{ role }}<|end_header_id|>"), None, Some(PromptTemplate::Llama3)),
+            (Some("llama"), None, Some("<|eot_id|>"), Some(PromptTemplate::Llama3)),
+            (Some("llama"), Some("[INST] <<SYS>>{{ system }}<</SYS>>"), None, Some(PromptTemplate::Llama2)),
+            (Some("llama"), Some("{{ bos_token }}[INST] {{ content }} [/INST]"), None, Some(PromptTemplate::Mistral)),
+            (Some("qwen2"), None, Some("<|im_end|>"), Some(PromptTemplate::ChatML)),
+            (Some("stablelm"), Some("<|user|>\n{{ content }}<|assistant|>"), None, Some(PromptTemplate::Zephyr)),
+            (Some("llama"), Some("### Instruction:\n{{ content }}\n### Response:"), None, Some(PromptTemplate::Alpaca)),
+            (Some("llama"), Some("USER: {{ content }} ASSISTANT:"), None, Some(PromptTemplate::Vicuna)),
+            (Some("gemma2"), Some("<start_of_turn>user"), None, Some(PromptTemplate::Gemma)),
+            (Some("gemma"), None, None, Some(PromptTemplate::Gemma)),
+            (Some("llama"), None, None, None),
+            (None, Some("{{ content }}"), Some("</s>"), None),
+        ];
+        for (architecture, chat_template, eos_token, expected) in cases {
+            assert!(suggested(architecture, chat_template, eos_token) == expected, "{:?} {:?} {:?}", architecture, chat_template, eos_token);
+        }
+    }
+
+    #[test]
+    fn cached_info_is_read_again_after_file_changes() {
+        let path = std::env::temp_dir().join(format!("ai-companion-gguf-test-{}.gguf", std::process::id()));
+        let path_text = path.to_str().unwrap().to_string();
+        std::fs::write(&path, chatml_model(3)).unwrap();
+        assert_eq!(cached_gguf_info(&path_text).unwrap().architecture.as_deref(), Some("qwen2"));
+        assert_eq!(cached_gguf_info(&path_text).unwrap().architecture.as_deref(), Some("qwen2"));
+        let mut gguf = Gguf::new(3);
+        gguf.text("general.architecture", "gemma");
+        let file = File::options().write(true).truncate(true).open(&path).unwrap();
+        std::io::Write::write_all(&mut &file, &gguf.build()).unwrap();
+        file.set_modified(SystemTime::now() + std::time::Duration::from_secs(10)).unwrap();
+        drop(file);
+        let info = cached_gguf_info(&path_text).unwrap();
+        assert_eq!(info.architecture.as_deref(), Some("gemma"));
+        assert!(info.suggested_template == Some(PromptTemplate::Gemma));
+        std::fs::remove_file(&path).unwrap();
+        assert!(cached_gguf_info(&path_text).is_err());
+    }
+}
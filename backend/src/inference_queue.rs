@@ -0,0 +1,335 @@
+use std::collections::VecDeque;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+use serde::Serialize;
+use tokio::sync::oneshot;
+
+// generations waiting in the queue, without the one that is running
+pub const QUEUE_CAPACITY: usize = 16;
+// outcomes of jobs nobody waited for, the oldest are forgotten first
+const FINISHED_CAPACITY: usize = 64;
+// background jobs waiting, they don't take places of generations users wait for
+const BACKGROUND_CAPACITY: usize = 16;
+
+// set to stop a generation, jobs check it while generating
+pub type CancelFlag = Arc<AtomicBool>;
+
+struct Job {
+    id: u64,
+    kind: &'static str,
+    queued_at: Instant,
+    cancel: CancelFlag,
+    run: Box<dyn FnOnce() + Send>,
+}
+
+struct RunningJob {
+    id: u64,
+    kind: &'static str,
+    started_at: Instant,
+    cancel: CancelFlag,
+}
+
+struct QueueState {
+    jobs: VecDeque<Job>,
+    // run only when no other job is waiting
+    background: VecDeque<Job>,
+    running: Option<RunningJob>,
+    next_id: u64,
+}
+
+impl QueueState {
+    // in the order they will run
+    fn queued(&self) -> impl Iterator<Item = &Job> {
+        self.jobs.iter().chain(self.background.iter())
+    }
+}
+
+#[derive(Serialize)]
+pub struct QueuedJobView {
+    pub id: u64,
+    pub kind: &'static str,
+    // 1 is the next job to run
+    pub position: usize,
+    pub waiting_ms: u128,
+}
+
+#[derive(Serialize)]
+pub struct RunningJobView {
+    pub id: u64,
+    pub kind: &'static str,
+    pub running_ms: u128,
+}
+
+#[derive(Serialize)]
+pub struct QueueStatusView {
+    // "Idle" or "Running"
+    pub worker: &'static str,
+    pub capacity: usize,
+    pub running: Option<RunningJobView>,
+    pub queued: Vec<QueuedJobView>,
+}
+
+// state of a single job, "queued", "running", "done" or "failed".
+// result of a done job and error of a failed one are kept only for jobs submitted without waiting for them,
+// and for jobs that panicked
+#[derive(Serialize, Clone)]
+pub struct JobStatusView {
+    pub id: u64,
+    pub kind: &'static str,
+    pub status: &'static str,
+    pub position: Option<usize>,
+    pub result: Option<serde_json::Value>,
+    pub error: Option<String>,
+}
+
+pub struct QueueFull;
+
+pub struct Ticket<T> {
+    // generation id, used to cancel it
+    pub id: u64,
+    pub result: oneshot::Receiver<T>,
+}
+
+// runs generations one at a time on a dedicated thread, in the order they were submitted
+pub struct InferenceQueue {
+    state: Mutex<QueueState>,
+    available: Condvar,
+    capacity: usize,
+    finished: Mutex<VecDeque<JobStatusView>>,
+}
+
+impl InferenceQueue {
+    pub fn start(capacity: usize) -> Arc<InferenceQueue> {
+        let queue = Arc::new(InferenceQueue {
+            state: Mutex::new(QueueState { jobs: VecDeque::new(), background: VecDeque::new(), running: None, next_id: 1 }),
+            available: Condvar::new(),
+            capacity,
+            finished: Mutex::new(VecDeque::new()),
+        });
+        let worker = queue.clone();
+        std::thread::spawn(move || loop {
+            let job = worker.next_job();
+            if catch_unwind(AssertUnwindSafe(job.run)).is_err() {
+                eprintln!("Generation {} ({}) panicked", job.id, job.kind);
+                // detached jobs never reached their own finish, others have no receiver left
+                worker.finish(job.id, job.kind, Err("generation panicked".into()));
+            }
+            worker.lock_state().running = None;
+        });
+        queue
+    }
+
+    fn lock_state(&self) -> MutexGuard<'_, QueueState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn next_job(&self) -> Job {
+        let mut state = self.lock_state();
+        loop {
+            if let Some(job) = state.jobs.pop_front().or_else(|| state.background.pop_front()) {
+                state.running = Some(RunningJob { id: job.id, kind: job.kind, started_at: Instant::now(), cancel: job.cancel.clone() });
+                return job;
+            }
+            state = self.available.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    // adds job at the end of the queue, its result is sent to the returned receiver
+    pub fn submit<T: Send + 'static>(&self, kind: &'static str, job: impl FnOnce(CancelFlag) -> T + Send + 'static) -> Result<Ticket<T>, QueueFull> {
+        self.submit_with_notice(kind, |_, _| {}, job)
+    }
+
+    // same as submit, notice is called with id and position before the job can start,
+    // so anything it sends reaches the client before output of the job
+    pub fn submit_with_notice<T: Send + 'static>(&self, kind: &'static str, notice: impl FnOnce(u64, usize), job: impl FnOnce(CancelFlag) -> T + Send + 'static) -> Result<Ticket<T>, QueueFull> {
+        let (tx, rx) = oneshot::channel();
+        let id = self.push(kind, false, notice, move |_, cancel| {
+            let _ = tx.send(job(cancel));
+        })?;
+        Ok(Ticket { id, result: rx })
+    }
+
+    // nobody waits for the job, its result or error is kept when it ends, so it can be read with job_status
+    pub fn submit_detached<T: Serialize>(self: &Arc<Self>, kind: &'static str, job: impl FnOnce(CancelFlag) -> Result<T, String> + Send + 'static) -> Result<u64, QueueFull> {
+        let queue = self.clone();
+        self.push(kind, false, |_, _| {}, move |id, cancel| {
+            let outcome = job(cancel).and_then(|v| serde_json::to_value(v).map_err(|e| e.to_string()));
+            queue.finish(id, kind, outcome);
+        })
+    }
+
+    // work nobody waits for, like extracting facts after a reply. it runs when no other job is waiting,
+    // so it never delays generations and doesn't count towards the capacity of the queue
+    pub fn submit_background(&self, kind: &'static str, job: impl FnOnce(CancelFlag) + Send + 'static) -> Result<u64, QueueFull> {
+        self.push(kind, true, |_, _| {}, move |_, cancel| job(cancel))
+    }
+
+    fn push(&self, kind: &'static str, background: bool, notice: impl FnOnce(u64, usize), run: impl FnOnce(u64, CancelFlag) + Send + 'static) -> Result<u64, QueueFull> {
+        let mut state = self.lock_state();
+        let (waiting, capacity) = if background { (state.background.len(), BACKGROUND_CAPACITY) } else { (state.jobs.len(), self.capacity) };
+        if waiting >= capacity {
+            return Err(QueueFull);
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+        notice(id, state.jobs.len() + 1);
+        let cancel: CancelFlag = Arc::new(AtomicBool::new(false));
+        let job_cancel = cancel.clone();
+        let jobs = if background { &mut state.background } else { &mut state.jobs };
+        jobs.push_back(Job {
+            id,
+            kind,
+            queued_at: Instant::now(),
+            cancel,
+            run: Box::new(move || run(id, job_cancel)),
+        });
+        drop(state);
+        self.available.notify_one();
+        Ok(id)
+    }
+
+    // a queued generation still runs, so its user message is saved, but stops before generating anything.
+    // false if there is no queued or running generation with this id
+    pub fn cancel(&self, id: u64) -> bool {
+        let state = self.lock_state();
+        let running = state.running.iter().map(|r| (r.id, &r.cancel));
+        let queued = state.queued().map(|j| (j.id, &j.cancel));
+        let found = running.chain(queued).find(|(job_id, _)| *job_id == id);
+        match found {
+            Some((_, cancel)) => {
+                cancel.store(true, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // outcome is saved before the worker takes the next job, so a job is never missing between running and finished
+    fn finish(&self, id: u64, kind: &'static str, outcome: Result<serde_json::Value, String>) {
+        let mut finished = self.finished.lock().unwrap_or_else(|e| e.into_inner());
+        if finished.len() >= FINISHED_CAPACITY {
+            finished.pop_front();
+        }
+        let (status, result, error) = match outcome {
+            Ok(v) => ("done", Some(v), None),
+            Err(e) => ("failed", None, Some(e)),
+        };
+        finished.push_back(JobStatusView { id, kind, status, position: None, result, error });
+    }
+
+    // None if the job is not queued or running, and its outcome is not kept
+    pub fn job_status(&self, id: u64) -> Option<JobStatusView> {
+        {
+            let state = self.lock_state();
+            if let Some(r) = state.running.as_ref().filter(|r| r.id == id) {
+                return Some(JobStatusView { id, kind: r.kind, status: "running", position: None, result: None, error: None });
+            }
+            let queued = state.queued().enumerate().find(|(_, j)| j.id == id);
+            if let Some((i, j)) = queued {
+                return Some(JobStatusView { id, kind: j.kind, status: "queued", position: Some(i + 1), result: None, error: None });
+            }
+        }
+        let finished = self.finished.lock().unwrap_or_else(|e| e.into_inner());
+        finished.iter().find(|j| j.id == id).cloned()
+    }
+
+    pub fn status(&self) -> QueueStatusView {
+        let state = self.lock_state();
+        QueueStatusView {
+            worker: if state.running.is_some() { "Running" } else { "Idle" },
+            capacity: self.capacity,
+            running: state.running.as_ref().map(|r| RunningJobView {
+                id: r.id,
+                kind: r.kind,
+                running_ms: r.started_at.elapsed().as_millis(),
+            }),
+            queued: state.queued().enumerate().map(|(i, j)| QueuedJobView {
+                id: j.id,
+                kind: j.kind,
+                position: i + 1,
+                waiting_ms: j.queued_at.elapsed().as_millis(),
+            }).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn wait_for_outcome(queue: &InferenceQueue, id: u64) -> JobStatusView {
+        for _ in 0..200 {
+            match queue.job_status(id) {
+                Some(status) if status.status == "done" || status.status == "failed" => return status,
+                _ => std::thread::sleep(Duration::from_millis(10)),
+            }
+        }
+        panic!("job {} didn't finish", id);
+    }
+
+    // keeps the worker busy until the returned sender is dropped
+    fn block_worker(queue: &InferenceQueue) -> std::sync::mpsc::Sender<()> {
+        let (tx, rx) = std::sync::mpsc::channel::<()>();
+        let _ = queue.submit("block", move |_| { let _ = rx.recv(); });
+        while queue.status().running.is_none() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        tx
+    }
+
+    #[test]
+    fn background_jobs_run_after_waiting_generations() {
+        let queue = InferenceQueue::start(QUEUE_CAPACITY);
+        let blocker = block_worker(&queue);
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let background_order = order.clone();
+        queue.submit_background("facts", move |_| background_order.lock().unwrap().push("facts")).ok().unwrap();
+        let prompt_order = order.clone();
+        let prompt = queue.submit_detached("prompt", move |_| { prompt_order.lock().unwrap().push("prompt"); Ok(()) }).ok().unwrap();
+        let kinds: Vec<&str> = queue.status().queued.iter().map(|j| j.kind).collect();
+        assert_eq!(kinds, vec!["prompt", "facts"]);
+        drop(blocker);
+        wait_for_outcome(&queue, prompt);
+        while order.lock().unwrap().len() < 2 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(*order.lock().unwrap(), vec!["prompt", "facts"]);
+    }
+
+    #[test]
+    fn background_jobs_dont_take_places_of_generations() {
+        let queue = InferenceQueue::start(1);
+        let _blocker = block_worker(&queue);
+        assert!(queue.submit_background("facts", |_| {}).is_ok());
+        assert!(queue.submit("prompt", |_| ()).is_ok());
+        assert!(queue.submit("prompt", |_| ()).is_err());
+        for _ in 1..BACKGROUND_CAPACITY {
+            assert!(queue.submit_background("facts", |_| {}).is_ok());
+        }
+        assert!(queue.submit_background("facts", |_| {}).is_err());
+    }
+
+    #[test]
+    fn detached_job_outcome_is_kept() {
+        let queue = InferenceQueue::start(QUEUE_CAPACITY);
+        let id = queue.submit_detached("test", |_| Ok(42)).ok().unwrap();
+        let status = wait_for_outcome(&queue, id);
+        assert_eq!(status.status, "done");
+        assert_eq!(status.result, Some(serde_json::json!(42)));
+    }
+
+    #[test]
+    fn panicking_detached_job_is_failed() {
+        let queue = InferenceQueue::start(QUEUE_CAPACITY);
+        let id = queue.submit_detached::<()>("test", |_| panic!("broken model")).ok().unwrap();
+        let status = wait_for_outcome(&queue, id);
+        assert_eq!(status.status, "failed");
+        assert_eq!(status.error.as_deref(), Some("generation panicked"));
+        // the worker keeps running
+        let next = queue.submit_detached("test", |_| Ok("next")).ok().unwrap();
+        assert_eq!(wait_for_outcome(&queue, next).status, "done");
+    }
+}
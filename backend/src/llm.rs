@@ -1,181 +1,548 @@
 
+use std::collections::HashSet;
 use std::io::Write;
-use chrono::{DateTime, Local};
+use std::sync::atomic::{AtomicBool, Ordering};
+use serde::Serialize;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use chrono::Local;
 
-use crate::database::{Database, NewMessage, Message, ConfigView, UserView, CompanionView, PromptTemplate, Device, get_current_date, contains_time_question};
+use crate::database::{Database, NewMessage, Message, ConfigView, PromptTemplate, UserView, Companion, Conversation, get_current_date, parse_date, contains_time_question, io_error};
 use crate::dialogue_tuning::DialogueTuning;
-use crate::long_term_mem::LongTermMem;
+use crate::facts::Facts;
+use crate::embeddings::{EmbeddingStore, normalize};
+use crate::long_term_mem::{LongTermMem, MemoryEntry, MemorySource, RetrievalSettings, SemanticQuery};
+use crate::model_manager::ModelManager;
+use crate::gguf::cached_gguf_info;
+use crate::prompt_template::TemplateFormat;
+use crate::context::{assemble, AssembledPrompt, ContextReport, PromptParts, PromptSection};
 
-pub fn prompt(prompt: &str) -> Result<String, std::io::Error> {
+#[derive(Serialize)]
+pub struct InferenceStatsView {
+    pub prompt_tokens: usize,
+    pub predict_tokens: usize,
+    pub feed_prompt_duration_ms: u128,
+    pub predict_duration_ms: u128,
+}
+
+impl From<llm::InferenceStats> for InferenceStatsView {
+    fn from(stats: llm::InferenceStats) -> Self {
+        InferenceStatsView {
+            prompt_tokens: stats.prompt_tokens,
+            predict_tokens: stats.predict_tokens,
+            feed_prompt_duration_ms: stats.feed_prompt_duration.as_millis(),
+            predict_duration_ms: stats.predict_duration.as_millis(),
+        }
+    }
+}
+
+// where the generated response is saved
+pub enum ReplyTarget {
+    NewMessage,
+    // new alternative of the latest message, which is left out of the prompt
+    Alternative(i32),
+    // latest message is extended, its text is left out of the prompt and put at the start of the generated turn
+    Continue(i32),
+    // suggested message of the user, not saved
+    Impersonate,
+}
+
+#[derive(Serialize)]
+pub struct PromptResult {
+    pub message_id: Option<i32>,
+    // index of the alternative of the message that was generated
+    pub alternative: usize,
+    pub content: String,
+    pub stats: Option<InferenceStatsView>,
+    pub context: Option<ContextReport>,
+    pub cancelled: bool,
+}
+
+pub fn prompt(prompt: &str, conversation_id: i32, target: ReplyTarget, model_manager: &ModelManager, cancel: &AtomicBool, keep_partial: bool) -> Result<PromptResult, std::io::Error> {
+    prompt_with_callback(prompt, conversation_id, target, model_manager, cancel, keep_partial, |_| {})
+}
+
+fn exchange_entry(date: &str, prompt: &str, response: &str) -> String {
+    format!("* at {} *\n{}: {}\n{}: {}\n", date, "{{user}}", prompt, "{{char}}", response)
+}
+
+// long-term memory entry of an ai message is made from its selected alternative and the message before it
+pub fn remember_selected_alternative(message_id: i32) -> Result<(), std::io::Error> {
+    let message = Database::get_message(message_id).map_err(io_error)?;
+    let alternatives = Database::get_message_alternatives(message_id).map_err(io_error)?;
+    let date = alternatives.get(message.selected_alternative).map(|a| a.created_at.clone()).unwrap_or(message.created_at);
+    let timestamp = parse_date(&date).unwrap_or_else(Local::now).timestamp();
+    let companion_id = Database::get_message_companion_id(message_id).map_err(io_error)?;
+    let prompt = match Database::get_parent_message(message_id) {
+        Ok(previous) => previous.content,
+        Err(_) => String::new(),
+    };
+    let long_term_memory = LongTermMem::connect().map_err(io_error)?;
+    long_term_memory.set_message_entry(message_id, companion_id, timestamp, &exchange_entry(&date, &prompt, &message.content))
+        .map(|_| ())
+        .map_err(io_error)
+}
+
+// hidden state of the model after reading the text, normalized to length 1.
+// text longer than context of the model is cut
+pub fn embed(llama: &dyn llm::Model, text: &str) -> Result<Vec<f32>, std::io::Error> {
+    let mut tokens: Vec<llm::TokenId> = match llama.tokenizer().tokenize(text, true) {
+        Ok(tokens) => tokens.into_iter().map(|(_, id)| id).collect(),
+        Err(e) => return Err(std::io::Error::other(format!("Error while tokenizing text for embedding: {}", e))),
+    };
+    tokens.truncate(llama.context_size());
+    let mut session = llama.start_session(Default::default());
+    let mut output_request = llm::OutputRequest {
+        all_logits: None,
+        embeddings: Some(Vec::new()),
+    };
+    llama.evaluate(&mut session, &tokens, &mut output_request);
+    match output_request.embeddings {
+        Some(mut embedding) if !embedding.is_empty() => {
+            normalize(&mut embedding);
+            Ok(embedding)
+        }
+        _ => Err(std::io::Error::other("Model didn't return an embedding")),
+    }
+}
+
+// embeddings are skipped when semantic_weight is 0, entries without one can get it from backfill_embeddings later
+pub fn remember_embedding(llama: &dyn llm::Model, config: &ConfigView, entry_id: &str, companion_id: i32, text: &str) {
+    if config.semantic_weight <= 0.0 {
+        return;
+    }
+    let saved = embed(llama, text).and_then(|embedding| {
+        EmbeddingStore::set(entry_id, &config.llm_model_path, companion_id, &embedding)
+            .map_err(io_error)
+    });
+    if let Err(e) = saved {
+        eprintln!("Error while saving embedding of long-term memory entry: {}", e);
+    }
+}
+
+// None when semantic_weight is 0 or embedding fails, then long-term memory falls back to keyword search
+pub fn semantic_query(llama: &dyn llm::Model, config: &ConfigView, text: &str) -> Option<SemanticQuery> {
+    if config.semantic_weight <= 0.0 {
+        return None;
+    }
+    match embed(llama, text) {
+        Ok(embedding) => Some(SemanticQuery {
+            embedding,
+            model: config.llm_model_path.clone(),
+            keyword_weight: config.keyword_weight,
+            semantic_weight: config.semantic_weight,
+        }),
+        Err(e) => {
+            eprintln!("Error while making embedding of query: {}", e);
+            None
+        }
+    }
+}
+
+// template selected in config, Auto uses the preset suggested by metadata of the model,
+// or Default if the model file can't be read
+pub fn template_format(config: &ConfigView) -> Result<TemplateFormat, rusqlite::Error> {
+    if config.prompt_template != PromptTemplate::Auto {
+        return Database::get_template_format(&config.prompt_template, config.custom_template_id);
+    }
+    let info = cached_gguf_info(&config.llm_model_path).ok();
+    let template = info.as_ref().and_then(|i| i.suggested_template.clone()).unwrap_or(PromptTemplate::Default);
+    let mut format = Database::get_template_format(&template, None)?;
+    // eos token of the model also ends the response, in case it's written out as text
+    if let Some(eos_token) = info.and_then(|i| i.eos_token) {
+        if !eos_token.is_empty() && !format.stop_sequences.contains(&eos_token) {
+            format.stop_sequences.push(eos_token);
+        }
+    }
+    Ok(format)
+}
+
+fn retrieval_settings(companion: &Companion) -> RetrievalSettings {
+    RetrievalSettings {
+        min_score: companion.memory_min_score,
+        half_life_days: companion.memory_half_life_days,
+    }
+}
+
+// same ranking of long-term memory as in prompts, embeddings are used only if the model is already loaded
+pub fn search_memory(query: &str, companion_id: i32, limit: usize, model_manager: &ModelManager) -> Result<Vec<MemoryEntry>, std::io::Error> {
+    let config = Database::get_config().map_err(io_error)?;
+    let companion = Database::get_companion(companion_id).map_err(io_error)?;
+    let long_term_memory = LongTermMem::connect().map_err(io_error)?;
+    let semantic = model_manager.get_loaded().and_then(|(llama, _)| semantic_query(llama.as_ref(), &config, query));
+    long_term_memory.search(query, companion_id, limit, semantic.as_ref(), &retrieval_settings(&companion)).map_err(io_error)
+}
+
+#[derive(Serialize)]
+pub struct BackfillResult {
+    // entries that got an embedding
+    pub embedded: usize,
+    // embeddings of entries that don't exist anymore
+    pub removed: usize,
+    // entries still without an embedding, when cancelled
+    pub remaining: usize,
+}
+
+// makes embeddings with the model from config for entries of the companion that don't have one yet,
+// and removes embeddings of the companion's entries that were deleted
+pub fn backfill_embeddings(companion_id: i32, model_manager: &ModelManager, cancel: &AtomicBool) -> Result<BackfillResult, std::io::Error> {
+    let config = Database::get_config().map_err(io_error)?;
+    let llama = model_manager.get_for_config(&config)?;
+    let long_term_memory = LongTermMem::connect().map_err(io_error)?;
+    let entries = long_term_memory.export_entries(companion_id).map_err(io_error)?;
+    let embedded_ids: Vec<(String, i32)> = EmbeddingStore::get_ids(&config.llm_model_path).map_err(io_error)?;
+    let entry_ids: HashSet<&str> = entries.iter().map(|e| e.id.as_str()).collect();
+    let mut removed: usize = 0;
+    for (id, _) in embedded_ids.iter().filter(|(id, companion)| *companion == companion_id && !entry_ids.contains(id.as_str())) {
+        removed += EmbeddingStore::delete(id).map_err(io_error)?;
+    }
+    let embedded_ids: HashSet<&str> = embedded_ids.iter().map(|(id, _)| id.as_str()).collect();
+    let missing: Vec<_> = entries.iter().filter(|e| !embedded_ids.contains(e.id.as_str())).collect();
+    println!("Making embeddings of {} long-term memory entries", missing.len());
+    let mut embedded: usize = 0;
+    for entry in &missing {
+        if cancel.load(Ordering::Relaxed) {
+            break;
+        }
+        let embedding = embed(llama.as_ref(), &entry.text)?;
+        EmbeddingStore::set(&entry.id, &config.llm_model_path, companion_id, &embedding).map_err(io_error)?;
+        embedded += 1;
+    }
+    Ok(BackfillResult { embedded, removed, remaining: missing.len() - embedded })
+}
+
+// everything that is read from database before building the prompt of a conversation
+struct PromptContext {
+    config: ConfigView,
+    user: UserView,
+    companion: Companion,
+    template: TemplateFormat,
+    long_term_memory: LongTermMem,
+}
+
+fn load_prompt_context(conversation_id: i32) -> Result<PromptContext, std::io::Error> {
     let long_term_memory = match LongTermMem::connect() {
         Ok(ltm) => ltm,
         Err(e) => {
             eprintln!("Error while connecting to tantivy: {}", e);
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, "Error while connecting to tantivy"));
+            return Err(std::io::Error::other("Error while connecting to tantivy"));
         }
     };
-    let local: DateTime<Local> = Local::now();
-    let formatted_date = local.format("* at %A %d.%m.%Y %H:%M *\n").to_string();
     let config: ConfigView = match Database::get_config() {
         Ok(config) => config,
         Err(e) => {
             eprintln!("Error while getting config: {}", e);
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, "Error while getting config"));
+            return Err(std::io::Error::other("Error while getting config"));
         }
     };
     let user: UserView = match Database::get_user_data() {
         Ok(user) => user,
         Err(e) => {
             eprintln!("Error while getting user data: {}", e);
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, "Error while getting user data"));
+            return Err(std::io::Error::other("Error while getting user data"));
         }
     };
-    let companion: CompanionView = match Database::get_companion_data() {
+    let conversation: Conversation = match Database::get_conversation(conversation_id) {
+        Ok(conversation) => conversation,
+        Err(e) => {
+            eprintln!("Error while getting conversation: {}", e);
+            return Err(std::io::Error::other("Error while getting conversation"));
+        }
+    };
+    let companion: Companion = match Database::get_companion(conversation.companion_id) {
         Ok(companion) => companion,
         Err(e) => {
             eprintln!("Error while getting companion data: {}", e);
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, "Error while getting companion data"));
+            return Err(std::io::Error::other("Error while getting companion data"));
         }
     };
-
-    let llama_model_params = {
-        let mut params = llm::ModelParameters::default();
-        if config.device == Device::GPU || config.device == Device::Metal {
-            params.use_gpu = true;
-            params.gpu_layers = Some(config.gpu_layers);
-        } else {
-            params.use_gpu = false;
-            params.gpu_layers = None;
+    let template: TemplateFormat = match template_format(&config) {
+        Ok(template) => template,
+        Err(e) => {
+            eprintln!("Error while getting prompt template: {}", e);
+            return Err(std::io::Error::other("Error while getting prompt template"));
         }
-        params
-    };
-    
-    let llama = llm::load(
-        std::path::Path::new(&config.llm_model_path),
-        llm::TokenizerSource::Embedded,
-        llama_model_params,
-        llm::load_progress_callback_stdout,
-    );
-   
-    let llama = match llama {
-        Ok(llama) => llama,
-        Err(e) => return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("Failed to load llm model: {}", e.to_string()))),
     };
+    Ok(PromptContext { config, user, companion, template, long_term_memory })
+}
 
-    let mut session = llama.start_session(Default::default());
-    println!("Generating ai response...");
-    let mut base_prompt: String;
-    let mut rp: &str = "";
-    let mut tuned_dialogue: String = String::from("");
-    if companion.roleplay {
-        rp = "gestures and other non-verbal actions are written between asterisks (for example, *waves hello* or *moves closer*)";
+// number of tokens in text, estimated from its length if tokenizer fails
+pub fn count_tokens(llama: &dyn llm::Model, text: &str) -> usize {
+    match llama.tokenizer().tokenize(text, false) {
+        Ok(tokens) => tokens.len(),
+        Err(_) => text.len() / 4 + 1,
     }
+}
+
+// builds the prompt from persona, dialogue tuning, long-term memory and short-term memory,
+// trimmed to fit in context size from config. pending_message is a user message that is not saved in database yet,
+// target decides how the generated turn starts and if the latest saved message is left out.
+// long-term memory entries picked for the prompt are returned with their scores
+fn build_prompt(prompt: &str, conversation_id: i32, pending_message: Option<&str>, target: &ReplyTarget, ctx: &PromptContext, llama: &dyn llm::Model) -> Result<(AssembledPrompt, Vec<MemoryEntry>), std::io::Error> {
+    let user = &ctx.user;
+    let companion = &ctx.companion;
+    let mut tuned_dialogue: String = String::from("");
     if companion.dialogue_tuning {
-        match DialogueTuning::get_random_dialogue() {
-            Ok(dialogue) => {
-                tuned_dialogue = format!("{}: {}\n{}: {}", &user.name, &dialogue.user_msg, &companion.name, &dialogue.ai_msg);
-            }
-            Err(_) => {}
-        };
-    }
-    if config.prompt_template == PromptTemplate::Default {
-        base_prompt = 
-        format!("Text transcript of a conversation between {} and {}. {}\n{}'s Persona: {}\n{}'s Persona: {}\n<START>\n{}\n<START>\n{}\n<START>\n", 
-                                            user.name, companion.name, rp, user.name, user.persona.replace("{{char}}", &companion.name).replace("{{user}}", &user.name), companion.name, companion.persona.replace("{{char}}", &companion.name).replace("{{user}}", &user.name), companion.example_dialogue.replace("{{char}}", &companion.name).replace("{{user}}", &user.name), &tuned_dialogue);
-    }
-    else if config.prompt_template == PromptTemplate::Llama2 {
-        base_prompt = 
-        format!("<<SYS>>\nYou are {}, {}\nyou are talking with {}, {} is {}\n{}\n[INST]\n{}\n{}\n[/INST]",
-                companion.name, companion.persona.replace("{{char}}", &companion.name).replace("{{user}}", &user.name), user.name, user.name, user.persona.replace("{{char}}", &companion.name).replace("{{user}}", &user.name), rp, companion.example_dialogue.replace("{{char}}", &companion.name).replace("{{user}}", &user.name), &tuned_dialogue);
-    }
-    else {
-        base_prompt = 
-        format!("<s>[INST]Text transcript of a conversation between {} and {}. {}\n{}'s Persona: {}\n{}'s Persona: {}[/INST]\n<s>[INST]\n{}[/INST]\n<s>[INST]\n{}\n[/INST]\n",
-        user.name, companion.name, rp, user.name, user.persona.replace("{{char}}", &companion.name).replace("{{user}}", &user.name), companion.name, companion.persona.replace("{{char}}", &companion.name).replace("{{user}}", &user.name), companion.example_dialogue.replace("{{char}}", &companion.name).replace("{{user}}", &user.name), &tuned_dialogue);
+        if let Ok(dialogue) = DialogueTuning::get_random_dialogue() {
+            tuned_dialogue = format!("{}: {}\n{}: {}", &user.name, &dialogue.user_msg, &companion.name, &dialogue.ai_msg);
+        }
     }
+    let facts = match Facts::get_facts(companion.id, ctx.config.fact_min_confidence) {
+        Ok(facts) => facts,
+        Err(e) => {
+            eprintln!("Error while getting facts about user: {}", e);
+            return Err(std::io::Error::other("Error while getting facts about user"));
+        }
+    };
+    let known_facts = if facts.is_empty() {
+        String::new()
+    } else {
+        let list: Vec<String> = facts.iter()
+            .map(|fact| format!("- {}", fact.content.replace("{{char}}", &companion.name).replace("{{user}}", &user.name)))
+            .collect();
+        format!("What {} knows about {}:\n{}", companion.name, user.name, list.join("\n"))
+    };
+    let mut memory_entries: Vec<MemoryEntry> = Vec::new();
     if companion.long_term_mem > 0 {
-        let long_term_memory_entries: Vec<String> = match long_term_memory.get_matches(prompt, companion.long_term_mem) {
+        let semantic = semantic_query(llama, &ctx.config, prompt);
+        memory_entries = match ctx.long_term_memory.search(prompt, companion.id, companion.long_term_mem, semantic.as_ref(), &retrieval_settings(companion)) {
             Ok(entries) => entries,
             Err(e) => {
                 eprintln!("Error while getting long term memory entries: {}", e);
-                return Err(std::io::Error::new(std::io::ErrorKind::Other, "Error while getting long term memory entries"));
+                return Err(std::io::Error::other("Error while getting long term memory entries"));
             }
         };
-        for entry in long_term_memory_entries {
-            if config.prompt_template == PromptTemplate::Llama2 {
-                base_prompt += &format!("[INST]{}[/INST]\n", entry).replace("{{char}}", &companion.name).replace("{{user}}", &user.name);
-            }
-            else if config.prompt_template == PromptTemplate::Mistral {
-                base_prompt += &format!("<s>[INST]{}[/INST]\n", entry).replace("{{char}}", &companion.name).replace("{{user}}", &user.name);
-            }
-            else {
-                base_prompt += &entry.replace("{{char}}", &companion.name).replace("{{user}}", &user.name);
-            }
-        }
     }
-    let short_term_memory_entries: Vec<Message> = match Database::get_x_messages(
-        if companion.short_term_mem > 0 { companion.short_term_mem } else { 1 }, 0) {
+    let memories: Vec<String> = memory_entries.iter()
+        .map(|entry| entry.text.trim_end().replace("{{char}}", &companion.name).replace("{{user}}", &user.name))
+        .collect();
+    let prefill = match target {
+        ReplyTarget::Continue(id) => match Database::get_message(*id) {
+            Ok(message) => message.content,
+            Err(e) => {
+                eprintln!("Error while getting message to continue: {}", e);
+                return Err(std::io::Error::other("Error while getting message to continue"));
+            }
+        },
+        _ => String::new(),
+    };
+    let skip_latest = matches!(target, ReplyTarget::Alternative(_) | ReplyTarget::Continue(_));
+    let short_term_mem = if companion.short_term_mem > 0 { companion.short_term_mem } else { 1 };
+    let saved_messages = if pending_message.is_some() { short_term_mem - 1 } else { short_term_mem };
+    let short_term_memory_entries: Vec<Message> = match Database::get_x_messages(conversation_id, saved_messages, skip_latest as usize) {
         Ok(entries) => entries,
         Err(e) => {
             eprintln!("Error while getting short term memory entries: {}", e);
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, "Error while getting short term memory entries"));
+            return Err(std::io::Error::other("Error while getting short term memory entries"));
         }
     };
-    let mut message_counter = 1;
-    let short_term_mem_len = short_term_memory_entries.len();
-    for message in short_term_memory_entries {
-        let prefix = if message.ai { &companion.name } else { &user.name };
-        let text = message.content;
-        let mut formatted_message = format!("{}: {}\n", prefix, text);
-        if message_counter == short_term_mem_len && contains_time_question(&formatted_message) {
-            formatted_message = format!("\n* it's currently {} *\n{}", get_current_date(), formatted_message);
-        }
-        if config.prompt_template == PromptTemplate::Llama2 {
-            if !message.ai {
-                base_prompt += &format!("[INST]{}", formatted_message);
-            }
-            else {
-                base_prompt += &format!("{}[/INST]\n", formatted_message);
-            }
-        }
-        else if config.prompt_template == PromptTemplate::Mistral {
-            if !message.ai {
-                base_prompt += &format!("<s>[INST]{}", formatted_message);
-            }
-            else {
-                base_prompt += &format!("{}[/INST]\n", formatted_message);
-            }
-        }
-        else {
-            base_prompt += &formatted_message;
-        }
-        message_counter += 1;
+    let mut messages: Vec<(bool, String)> = short_term_memory_entries.into_iter()
+        .map(|message| (message.ai, message.content))
+        .collect();
+    if let Some(pending) = pending_message {
+        messages.push((false, pending.to_string()));
+    }
+    let current_date = messages.last()
+        .filter(|(_, text)| contains_time_question(text))
+        .map(|_| get_current_date());
+    let parts = PromptParts {
+        user_name: user.name.clone(),
+        companion_name: companion.name.clone(),
+        user_persona: user.persona.replace("{{char}}", &companion.name).replace("{{user}}", &user.name),
+        companion_persona: companion.persona.replace("{{char}}", &companion.name).replace("{{user}}", &user.name),
+        roleplay: companion.roleplay,
+        known_facts,
+        example_dialogue: companion.example_dialogue.replace("{{char}}", &companion.name).replace("{{user}}", &user.name),
+        tuned_dialogue,
+        memories,
+        messages,
+        current_date,
+        reply_as_user: matches!(target, ReplyTarget::Impersonate),
+        prefill,
+    };
+    Ok((assemble(&ctx.template, parts, ctx.config.context_size, ctx.config.response_budget, |text| count_tokens(llama, text)), memory_entries))
+}
+
+#[derive(Serialize)]
+pub struct PromptPreview {
+    pub prompt: String,
+    pub sections: Vec<PromptSection>,
+    pub stop_sequences: Vec<String>,
+    pub context: ContextReport,
+    // long-term memory entries picked for the prompt, most relevant first, with their scores.
+    // the last ones can be dropped from the prompt when it doesn't fit in context
+    pub memories: Vec<MemoryEntry>,
+}
+
+// builds the same prompt as prompt_with_callback, without generating a response or saving anything,
+// prompt is treated as a new user message, without it the prompt is built from messages in database
+pub fn preview_prompt(prompt: Option<&str>, conversation_id: i32, model_manager: &ModelManager) -> Result<PromptPreview, std::io::Error> {
+    let ctx = load_prompt_context(conversation_id)?;
+    let llama = model_manager.get_for_config(&ctx.config)?;
+    let query = match prompt {
+        Some(p) => p.to_string(),
+        None => Database::get_latest_message(conversation_id).map(|m| m.content).unwrap_or_default(),
+    };
+    let (assembled, memories) = build_prompt(&query, conversation_id, prompt, &ReplyTarget::NewMessage, &ctx, llama.as_ref())?;
+    Ok(PromptPreview {
+        prompt: assembled.text,
+        sections: assembled.sections,
+        stop_sequences: assembled.stop_sequences,
+        context: assembled.report,
+        memories,
+    })
+}
+
+// same as prompt, but calls on_token with every piece of text that is ready to be shown to the user.
+// when cancelled, partial response is saved only with keep_partial.
+// content of the result is the whole message, for Continue it includes the text it already had
+pub fn prompt_with_callback(prompt: &str, conversation_id: i32, target: ReplyTarget, model_manager: &ModelManager, cancel: &AtomicBool, keep_partial: bool, on_token: impl FnMut(&str)) -> Result<PromptResult, std::io::Error> {
+    let ctx = load_prompt_context(conversation_id)?;
+    let llama = model_manager.get_for_config(&ctx.config)?;
+    let (assembled, memories) = build_prompt(prompt, conversation_id, None, &target, &ctx, llama.as_ref())?;
+    for entry in &memories {
+        println!("Long-term memory entry {} (score {:.3}): {}", entry.id, entry.score.unwrap_or(0.0), entry.text.replace('\n', " ").chars().take(80).collect::<String>());
     }
+    let report = &assembled.report;
+    if report.trimmed() {
+        println!("Prompt trimmed to fit in context: dropped {} messages, {} memory entries{}{}",
+            report.dropped_messages, report.dropped_memories,
+            if report.dropped_tuning { ", tuning dialogue" } else { "" },
+            if report.truncated_example_dialogue { ", part of example dialogue" } else { "" });
+    }
+    if !report.fits {
+        eprintln!("Prompt ({} tokens) doesn't fit in context size {} with response budget {}", report.prompt_tokens, report.context_size, report.response_budget);
+    }
+    let companion = &ctx.companion;
+    let continues = matches!(target, ReplyTarget::Continue(_));
+    let generation = generate_text(llama.as_ref(), &ctx.config, &assembled.text, &assembled.stop_sequences, !continues, cancel, on_token)?;
+    let stats = generation.stats.map(InferenceStatsView::from);
+    let generated_text = generation.text
+    .split(&format!("\n{}: ", &companion.name))
+    .next()
+    .unwrap_or("");
+    if generation.cancelled && (!keep_partial || generated_text.trim().is_empty()) {
+        println!("Generation cancelled, partial response discarded");
+        return Ok(PromptResult {
+            message_id: None,
+            alternative: 0,
+            content: String::new(),
+            stats,
+            context: Some(assembled.report),
+            cancelled: true,
+        });
+    }
+    let companion_text = match &target {
+        ReplyTarget::Continue(id) => match Database::get_message(*id) {
+            Ok(message) => format!("{}{}", message.content, generated_text),
+            Err(_) => generated_text.to_string(),
+        },
+        _ => generated_text.to_string(),
+    };
+    let companion_text = companion_text.as_str();
+    let saved = match target {
+        ReplyTarget::NewMessage => Database::insert_message(conversation_id, NewMessage { ai: true, content: companion_text.to_string() }).map(|id| (id, 0)),
+        ReplyTarget::Alternative(id) => Database::add_message_alternative(id, companion_text).map(|index| (id, index)),
+        ReplyTarget::Continue(id) => Database::edit_message(id, NewMessage { ai: true, content: companion_text.to_string() })
+            .and_then(|id| Database::get_message(id).map(|message| (id, message.selected_alternative))),
+        ReplyTarget::Impersonate => {
+            return Ok(PromptResult {
+                message_id: None,
+                alternative: 0,
+                content: generated_text.trim().to_string(),
+                stats,
+                context: Some(assembled.report),
+                cancelled: generation.cancelled,
+            });
+        }
+    };
+    let (message_id, alternative) = match saved {
+        Ok((id, index)) => (Some(id), index),
+        Err(e) => {
+            eprintln!("Error while adding message to database/short-term memory: {}", e);
+            (None, 0)
+        },
+    };
+    let entry = exchange_entry(&get_current_date(), prompt, companion_text);
+    let remembered = match message_id {
+        Some(id) => ctx.long_term_memory.set_message_entry(id, companion.id, Local::now().timestamp(), &entry),
+        None => ctx.long_term_memory.add_entry(&entry, MemorySource::Chat, companion.id),
+    };
+    match remembered {
+        Ok(entry_id) => remember_embedding(llama.as_ref(), &ctx.config, &entry_id, companion.id, &entry),
+        Err(e) => eprintln!("Error while adding message to long-term memory: {}", e),
+    };
+    Ok(PromptResult {
+        message_id,
+        alternative,
+        content: companion_text.trim_start().to_string(),
+        stats,
+        context: Some(assembled.report),
+        cancelled: generation.cancelled,
+    })
+}
+
+pub struct Generation {
+    // generated text cut at the first stop sequence, without special tokens
+    pub text: String,
+    pub stats: Option<llm::InferenceStats>,
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    // generation ended because max_new_tokens was reached
+    pub reached_token_limit: bool,
+    // generation was halted by cancel flag
+    pub cancelled: bool,
+}
+
+// runs inference with sampler settings from config until a stop sequence, max_new_tokens, end of context
+// or until cancel is set
+pub fn generate(llama: &dyn llm::Model, config: &ConfigView, prompt_text: &str, stop_sequences: &[String], cancel: &AtomicBool, on_token: impl FnMut(&str)) -> Result<Generation, std::io::Error> {
+    generate_text(llama, config, prompt_text, stop_sequences, true, cancel, on_token)
+}
+
+// leading whitespace is kept when the text continues an existing message, "the" + " store" would be glued together otherwise
+fn generate_text(llama: &dyn llm::Model, config: &ConfigView, prompt_text: &str, stop_sequences: &[String], trim_start: bool, cancel: &AtomicBool, mut on_token: impl FnMut(&str)) -> Result<Generation, std::io::Error> {
+    let mut session = llama.start_session(Default::default());
+    println!("Generating ai response...");
     let mut end_of_generation = String::new();
-    let eog = format!("\n{}:", user.name);
+    let mut streamed = String::new();
+    let mut completion_tokens: usize = 0;
+    let mut stopped = false;
+    let mut cancelled = false;
+    let inference_parameters = inference_parameters(config, llama)?;
+    let mut rng = rng_from_seed(config.seed);
     let res = session.infer::<std::convert::Infallible>(
-        llama.as_ref(),
-        &mut rand::thread_rng(),
+        llama,
+        &mut rng,
         &llm::InferenceRequest {
-            prompt: llm::Prompt::Text(&format!("{}{}: ", &base_prompt, companion.name)),
-            parameters: &llm::InferenceParameters::default(),
+            prompt: llm::Prompt::Text(prompt_text),
+            parameters: &inference_parameters,
             play_back_previous_tokens: false,
-            maximum_token_count: None,
+            maximum_token_count: if config.max_new_tokens > 0 { Some(config.max_new_tokens) } else { None },
         },
         &mut Default::default(),
         |t| {
+            if cancel.load(Ordering::Relaxed) {
+                cancelled = true;
+                return Ok(llm::InferenceFeedback::Halt);
+            }
             match t {
                 llm::InferenceResponse::SnapshotToken(_) => {/*print!("{token}");*/}
                 llm::InferenceResponse::PromptToken(_) => {/*print!("{token}");*/}
                 llm::InferenceResponse::InferredToken(token) => {
                     //  x = x.clone()+&token;
                     end_of_generation.push_str(&token);
+                    completion_tokens += 1;
                     print!("{token}");
-                    if end_of_generation.contains(&eog) || end_of_generation.contains("[/INST]") || end_of_generation.contains("<</SYS>>") ||
-                       end_of_generation.contains("[s]") ||
-                       end_of_generation.contains(&format!("{}:", &companion.name)) || end_of_generation.contains(&format!("{}:", &user.name)) ||
-                       end_of_generation.contains("<|user|>") {
-                        return Ok(llm::InferenceFeedback::Halt);          
+                    let stop = stop_sequences.iter().any(|s| end_of_generation.contains(s.as_str()));
+                    let visible = streamable_text(&end_of_generation, stop_sequences, trim_start);
+                    if visible.len() > streamed.len() && visible.starts_with(streamed.as_str()) {
+                        on_token(&visible[streamed.len()..]);
+                        streamed = visible;
+                    }
+                    if stop {
+                        stopped = true;
+                        return Ok(llm::InferenceFeedback::Halt);
                     }
                 }
                 llm::InferenceResponse::EotToken => {}
@@ -184,22 +551,151 @@ pub fn prompt(prompt: &str) -> Result<String, std::io::Error> {
             Ok(llm::InferenceFeedback::Continue)
         }
     );
-    let x: String = end_of_generation.replace(&eog, "").replace("[INST]", "").replace("[/INST]", "").replace("<</SYS>>", "").replace("<s>", "").replace("</s>", "").replace("<|user|>", "");
-    match res {
-        Ok(result) => println!("\n\nInference stats:\n{result}"),
-        Err(err) => println!("\n{err}"),
-    }
-    let companion_text = x
-    .split(&format!("\n{}: ", &companion.name))
-    .next()
-    .unwrap_or("");
-    match Database::insert_message(NewMessage { ai: true, content: companion_text.to_string() }) {
-        Ok(_) => {},
-        Err(e) => eprintln!("Error while adding message to database/short-term memory: {}", e),
+    let stats = match res {
+        Ok(result) => {
+            println!("\n\nInference stats:\n{result}");
+            Some(result)
+        },
+        Err(err) => {
+            println!("\n{err}");
+            None
+        },
     };
-    match long_term_memory.add_entry(&format!("{}{}: {}\n{}: {}\n", formatted_date, "{{user}}", &prompt, "{{char}}", &companion_text)) {
-        Ok(_) => {},
-        Err(e) => eprintln!("Error while adding message to long-term memory: {}", e),
+    let prompt_tokens = match &stats {
+        Some(s) => s.prompt_tokens,
+        None => llama.tokenizer().tokenize(prompt_text, true).map(|t| t.len()).unwrap_or(0),
     };
-    Ok(companion_text.trim_start().to_string())
-}
\ No newline at end of file
+    Ok(Generation {
+        text: final_text(&end_of_generation, stop_sequences, trim_start),
+        stats,
+        prompt_tokens,
+        completion_tokens,
+        reached_token_limit: !stopped && !cancelled && config.max_new_tokens > 0 && completion_tokens >= config.max_new_tokens,
+        cancelled,
+    })
+}
+
+fn inference_parameters(config: &ConfigView, model: &dyn llm::Model) -> Result<llm::InferenceParameters, std::io::Error> {
+    let sampler_options = [
+        format!("repetition:penalty={}:last_n={}", config.repeat_penalty, config.repeat_last_n),
+        format!("topk:k={}", config.top_k),
+        format!("topp:p={}", config.top_p),
+        format!("temperature:temperature={}", config.temperature),
+    ];
+    match llm::samplers::build_sampler(model.tokenizer().len(), &[], &sampler_options) {
+        Ok(sampler) => Ok(llm::InferenceParameters { sampler }),
+        Err(e) => Err(std::io::Error::other(format!("Invalid sampler settings: {}", e))),
+    }
+}
+
+// fixed seed makes generations reproducible, -1 uses a random one
+fn rng_from_seed(seed: i64) -> StdRng {
+    if seed >= 0 {
+        StdRng::seed_from_u64(seed as u64)
+    } else {
+        StdRng::from_entropy()
+    }
+}
+
+const CLEANUP_TOKENS: [&str; 6] = ["[INST]", "[/INST]", "<</SYS>>", "<s>", "</s>", "<|user|>"];
+
+fn clean_generated_text(text: &str) -> String {
+    let mut cleaned = text.to_string();
+    for token in CLEANUP_TOKENS {
+        cleaned = cleaned.replace(token, "");
+    }
+    cleaned
+}
+
+fn cut_at_stop_sequence<'a>(generated: &'a str, stop_sequences: &[String]) -> &'a str {
+    let mut end = generated.len();
+    for stop in stop_sequences {
+        if let Some(pos) = generated.find(stop.as_str()) {
+            end = end.min(pos);
+        }
+    }
+    &generated[..end]
+}
+
+fn final_text(generated: &str, stop_sequences: &[String], trim_start: bool) -> String {
+    let text = clean_generated_text(cut_at_stop_sequence(generated, stop_sequences));
+    if trim_start { text.trim_start().to_string() } else { text }
+}
+
+// part of generated text that can already be sent to the client,
+// text is cut at the first stop sequence, and held back while its end could still become a stop sequence or a special token
+fn streamable_text(generated: &str, stop_sequences: &[String], trim_start: bool) -> String {
+    let text = cut_at_stop_sequence(generated, stop_sequences);
+    let mut safe_end = text.len();
+    for (i, _) in text.char_indices() {
+        let tail = &text[i..];
+        if stop_sequences.iter().any(|s| s.starts_with(tail)) || CLEANUP_TOKENS.iter().any(|s| s.starts_with(tail)) {
+            safe_end = i;
+            break;
+        }
+    }
+    let text = clean_generated_text(&text[..safe_end]);
+    if trim_start { text.trim_start().to_string() } else { text }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stops(stops: &[&str]) -> Vec<String> {
+        stops.iter().map(|s| s.to_string()).collect()
+    }
+
+    // feeds chunks like generated tokens, returns text sent to the client after each chunk
+    fn stream(chunks: &[&str], stop_sequences: &[String]) -> Vec<String> {
+        let mut generated = String::new();
+        let mut streamed = String::new();
+        let mut sent = Vec::new();
+        for chunk in chunks {
+            generated.push_str(chunk);
+            let visible = streamable_text(&generated, stop_sequences, true);
+            if visible.len() > streamed.len() && visible.starts_with(streamed.as_str()) {
+                streamed = visible;
+            }
+            sent.push(streamed.clone());
+        }
+        sent
+    }
+
+    #[test]
+    fn cuts_at_earliest_stop_sequence() {
+        let stop_sequences = stops(&["\nAnn:", "[/INST]"]);
+        assert_eq!(cut_at_stop_sequence("hi[/INST] there\nAnn: x", &stop_sequences), "hi");
+        assert_eq!(cut_at_stop_sequence("no stop here", &stop_sequences), "no stop here");
+    }
+
+    #[test]
+    fn stop_sequence_split_across_chunks_is_never_streamed() {
+        let sent = stream(&["Hello", " there\nA", "n", "n: how", " are you?"], &stops(&["\nAnn:"]));
+        assert_eq!(sent, vec!["Hello", "Hello there", "Hello there", "Hello there", "Hello there"]);
+    }
+
+    #[test]
+    fn held_back_text_is_sent_when_it_is_not_a_stop_sequence() {
+        let sent = stream(&["Hello", " there\nA", "lright"], &stops(&["\nAnn:"]));
+        assert_eq!(sent.last().unwrap(), "Hello there\nAlright");
+    }
+
+    #[test]
+    fn special_token_split_across_chunks_is_removed() {
+        let sent = stream(&["Bye", "</", "s>"], &Vec::new());
+        assert_eq!(sent, vec!["Bye", "Bye", "Bye"]);
+    }
+
+    #[test]
+    fn multibyte_text_before_partial_stop_sequence() {
+        let sent = stream(&["Zażółć ", "gęślą\n", "Ann", ": jaźń"], &stops(&["\nAnn:"]));
+        assert_eq!(sent.last().unwrap(), "Zażółć gęślą");
+    }
+
+    #[test]
+    fn leading_whitespace_is_kept_when_continuing() {
+        assert_eq!(streamable_text(" and then", &Vec::new(), false), " and then");
+        assert_eq!(final_text(" and then\nAnn: x", &stops(&["\nAnn:"]), false), " and then");
+        assert_eq!(final_text(" and then", &Vec::new(), true), "and then");
+    }
+}
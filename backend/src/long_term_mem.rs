@@ -1,45 +1,434 @@
 
-use tantivy::collector::TopDocs;
-use tantivy::query::QueryParser;
+use tantivy::collector::{DocSetCollector, TopDocs};
+use tantivy::query::{AllQuery, BooleanQuery, Occur, Query, QueryParser, TermQuery};
 use tantivy::schema::*;
-use tantivy::Index;
+use tantivy::{DocAddress, Index, IndexWriter, Searcher, Term};
 use tantivy::error::TantivyError;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use chrono::Local;
+use rand::Rng;
+use rand::distributions::Alphanumeric;
+use serde::{Serialize, Deserialize};
+
+use crate::consolidation::ConsolidatedMessages;
+use crate::database::{Database, parse_date};
+use crate::embeddings::{EmbeddingStore, cosine_similarity};
+use crate::time_expression::date_range;
 
 pub struct LongTermMem {
     index: Index,
+    // stable id of the entry, kept when the entry is updated
+    id_field: Field,
     chat_field: Field,
+    // unix timestamp in seconds of when the remembered thing happened
+    date_field: Field,
+    // "chat", "manual", "document" or "summary"
+    source_field: Field,
+    companion_id_field: Field,
+    // name of the document the entry is a chunk of, and number of the chunk in it
+    document_field: Field,
+    position_field: Field,
+    // id of the ai message the entry was made from, missing in entries added manually
+    message_id_field: Field,
+    // ids of all ai messages a summary was made from
+    source_message_id_field: Field,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum MemorySource {
+    Chat,
+    Manual,
+    Document,
+    // facts summarized from older chat entries by consolidation
+    Summary,
+}
+
+impl MemorySource {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MemorySource::Chat => "chat",
+            MemorySource::Manual => "manual",
+            MemorySource::Document => "document",
+            MemorySource::Summary => "summary",
+        }
+    }
+}
+
+// entry from an export, added with a new id
+#[derive(Deserialize)]
+pub struct ImportedEntry {
+    pub text: String,
+    pub date: Option<i64>,
+    pub source: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct MemoryEntry {
+    pub id: String,
+    pub text: String,
+    pub date: Option<i64>,
+    pub source: String,
+    pub companion_id: Option<u64>,
+    pub message_id: Option<u64>,
+    pub document: Option<String>,
+    pub position: Option<u64>,
+    // ai messages the summary was made from, empty in other entries
+    pub source_message_ids: Vec<u64>,
+    // relevance to the query, only in search results
+    pub score: Option<f32>,
+}
+
+#[derive(Serialize)]
+pub struct DocumentView {
+    pub name: String,
+    pub chunks: usize,
+    pub date: Option<i64>,
+}
+
+// embedding of the query made by the model, with weights of keyword and semantic scores from config
+pub struct SemanticQuery {
+    pub embedding: Vec<f32>,
+    pub model: String,
+    pub keyword_weight: f32,
+    pub semantic_weight: f32,
+}
+
+// settings of the companion deciding which matches are used
+#[derive(Default)]
+pub struct RetrievalSettings {
+    // matches less relevant than this are left out. relevance is between 0 and 1 and doesn't depend
+    // on other matches, so a query without a real match gets nothing
+    pub min_score: f32,
+    // score is halved every this many days since the date of the entry, 0 turns decay off
+    pub half_life_days: f32,
+}
+
+impl RetrievalSettings {
+    fn keep_relevant(&self, entries: Vec<MemoryEntry>, relevance: &HashMap<String, f32>) -> Vec<MemoryEntry> {
+        entries.into_iter().filter(|e| relevance.get(&e.id).copied().unwrap_or(0.0) >= self.min_score).collect()
+    }
+
+    // scores are decayed by age of the entry and entries sorted by them
+    fn decay(&self, mut kept: Vec<MemoryEntry>, now: i64) -> Vec<MemoryEntry> {
+        if self.half_life_days > 0.0 {
+            for entry in kept.iter_mut() {
+                if let (Some(score), Some(date)) = (entry.score, entry.date) {
+                    let age_days = (now - date).max(0) as f32 / 86400.0;
+                    entry.score = Some(score * 0.5f32.powf(age_days / self.half_life_days));
+                }
+            }
+            kept.sort_by(|a, b| b.score.unwrap_or(0.0).total_cmp(&a.score.unwrap_or(0.0)));
+        }
+        kept
+    }
+}
+
+// entries sharing at least this part of their words are near duplicates, only the better one is used
+const DUPLICATE_OVERLAP: f32 = 0.8;
+
+// words of the entry without its "* at ... *" or "* from ... *" line, so the same thing remembered twice is a duplicate
+fn content_words(text: &str) -> HashSet<String> {
+    let body = match text.split_once('\n') {
+        Some((first, rest)) if first.starts_with("* ") && first.ends_with(" *") => rest,
+        _ => text,
+    };
+    body.split(|c: char| !c.is_alphanumeric()).filter(|w| !w.is_empty()).map(|w| w.to_lowercase()).collect()
+}
+
+fn is_near_duplicate(a: &HashSet<String>, b: &HashSet<String>) -> bool {
+    if a.is_empty() || b.is_empty() {
+        return false;
+    }
+    let shared = a.intersection(b).count();
+    shared as f32 / (a.len() + b.len() - shared) as f32 >= DUPLICATE_OVERLAP
+}
+
+// ids start with time of creation in hex, so sorting by id sorts entries from oldest to newest
+fn new_entry_id() -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0);
+    let suffix: String = rand::thread_rng().sample_iter(&Alphanumeric).take(6).map(char::from).collect();
+    format!("{:016x}-{}", nanos, suffix)
 }
 
 impl LongTermMem {
-    pub fn connect() -> tantivy::Result<Self> {
+    fn schema() -> Schema {
         let mut schema_builder = SchemaBuilder::default();
-        let chat_field = schema_builder.add_text_field("chat", TEXT | STORED);
-        let schema = schema_builder.build();
+        schema_builder.add_text_field("id", STRING | STORED);
+        schema_builder.add_text_field("chat", TEXT | STORED);
+        schema_builder.add_i64_field("date", INDEXED | STORED | FAST);
+        schema_builder.add_text_field("source", STRING | STORED);
+        schema_builder.add_u64_field("companion_id", INDEXED | STORED);
+        schema_builder.add_u64_field("message_id", INDEXED | STORED);
+        schema_builder.add_text_field("document", STRING | STORED);
+        schema_builder.add_u64_field("position", STORED);
+        schema_builder.add_u64_field("source_message_id", INDEXED | STORED);
+        schema_builder.build()
+    }
+
+    pub fn connect() -> tantivy::Result<Self> {
+        let schema = LongTermMem::schema();
         if !Path::new("longterm_memory").exists() {
             fs::create_dir("longterm_memory")?;
         }
-        let companion_vector = match Index::open_in_dir("longterm_memory") {
+        let mut companion_vector = match Index::open_in_dir("longterm_memory") {
             Ok(index) => index,
-            Err(_) => Index::create_in_dir("longterm_memory", schema)?,
+            Err(_) => Index::create_in_dir("longterm_memory", schema.clone())?,
         };
+        let missing_field = schema.fields().any(|(_, entry)| companion_vector.schema().get_field(entry.name()).is_err());
+        if missing_field {
+            companion_vector = LongTermMem::migrate(companion_vector, schema.clone())?;
+        }
         Ok(LongTermMem {
             index: companion_vector,
-            chat_field,
+            id_field: schema.get_field("id")?,
+            chat_field: schema.get_field("chat")?,
+            date_field: schema.get_field("date")?,
+            source_field: schema.get_field("source")?,
+            companion_id_field: schema.get_field("companion_id")?,
+            message_id_field: schema.get_field("message_id")?,
+            document_field: schema.get_field("document")?,
+            position_field: schema.get_field("position")?,
+            source_message_id_field: schema.get_field("source_message_id")?,
         })
     }
 
-    pub fn add_entry(&self, text: &str) -> Result<(), TantivyError> {
-        let mut writer = self.index.writer(50_000_000)?;
-        writer.add_document(tantivy::doc!(
-            self.chat_field => text
-        ))?;
+    // index from an older version is copied to a new index with current schema, fields are matched by name.
+    // entries from before ids were added get a new id, entries from before metadata was added get the date
+    // from "* at ... *" at the start of their text, and are from chat if they start with it.
+    // entries without a companion belong to the companion of their message, or to the active companion
+    fn migrate(old_index: Index, schema: Schema) -> tantivy::Result<Index> {
+        println!("Migrating long-term memory to a new schema");
+        let migration_dir = "longterm_memory_migration";
+        if Path::new(migration_dir).exists() {
+            fs::remove_dir_all(migration_dir)?;
+        }
+        fs::create_dir(migration_dir)?;
+        let new_index = Index::create_in_dir(migration_dir, schema.clone())?;
+        let old_schema = old_index.schema();
+        let searcher = old_index.reader()?.searcher();
+        let mut writer = new_index.writer(50_000_000)?;
+        for address in searcher.search(&AllQuery, &DocSetCollector)? {
+            let old_doc = searcher.doc(address)?;
+            let mut doc = tantivy::doc!();
+            for (field, entry) in schema.fields() {
+                if let Ok(old_field) = old_schema.get_field(entry.name()) {
+                    for value in old_doc.get_all(old_field) {
+                        doc.add_field_value(field, value.clone());
+                    }
+                }
+            }
+            let id_field = schema.get_field("id")?;
+            if doc.get_first(id_field).is_none() {
+                doc.add_text(id_field, new_entry_id());
+            }
+            let text = doc.get_first(schema.get_field("chat")?).and_then(|val| val.as_text()).unwrap_or("").to_string();
+            let written_date = text.strip_prefix("* at ").and_then(|t| t.split(" *").next()).and_then(parse_date);
+            let date_field = schema.get_field("date")?;
+            if doc.get_first(date_field).is_none() {
+                if let Some(date) = written_date {
+                    doc.add_i64(date_field, date.timestamp());
+                }
+            }
+            let source_field = schema.get_field("source")?;
+            if doc.get_first(source_field).is_none() {
+                let from_chat = written_date.is_some() || doc.get_first(schema.get_field("message_id")?).is_some();
+                doc.add_text(source_field, if from_chat { MemorySource::Chat.name() } else { MemorySource::Manual.name() });
+            }
+            let companion_id_field = schema.get_field("companion_id")?;
+            if doc.get_first(companion_id_field).is_none() {
+                let message_id = doc.get_first(schema.get_field("message_id")?).and_then(|val| val.as_u64());
+                let companion_id = match message_id {
+                    Some(id) => Database::get_message_companion_id(id as i32),
+                    None => Database::get_active_companion_id(),
+                };
+                match companion_id {
+                    Ok(id) => doc.add_u64(companion_id_field, id as u64),
+                    Err(e) => eprintln!("Error while getting companion of long-term memory entry: {}", e),
+                }
+            }
+            writer.add_document(doc)?;
+        }
         writer.commit()?;
+        drop(writer);
+        drop(searcher);
+        drop(old_index);
+        drop(new_index);
+        fs::remove_dir_all("longterm_memory")?;
+        fs::rename(migration_dir, "longterm_memory")?;
+        Index::open_in_dir("longterm_memory")
+    }
+
+    fn id_term(&self, id: &str) -> Term {
+        Term::from_field_text(self.id_field, id)
+    }
+
+    fn companion_term(&self, companion_id: i32) -> Term {
+        Term::from_field_u64(self.companion_id_field, companion_id as u64)
+    }
+
+    fn companion_query(&self, companion_id: i32) -> Box<dyn Query> {
+        Box::new(TermQuery::new(self.companion_term(companion_id), IndexRecordOption::Basic))
+    }
+
+    fn entry_at(&self, searcher: &Searcher, address: DocAddress, score: Option<f32>) -> Result<MemoryEntry, TantivyError> {
+        let retrieved = searcher.doc(address)?;
+        Ok(MemoryEntry {
+            id: retrieved.get_first(self.id_field).and_then(|val| val.as_text()).unwrap_or("").to_string(),
+            text: retrieved.get_first(self.chat_field).and_then(|val| val.as_text()).unwrap_or("").to_string(),
+            date: retrieved.get_first(self.date_field).and_then(|val| val.as_i64()),
+            source: retrieved.get_first(self.source_field).and_then(|val| val.as_text()).unwrap_or("").to_string(),
+            companion_id: retrieved.get_first(self.companion_id_field).and_then(|val| val.as_u64()),
+            message_id: retrieved.get_first(self.message_id_field).and_then(|val| val.as_u64()),
+            document: retrieved.get_first(self.document_field).and_then(|val| val.as_text()).map(|name| name.to_string()),
+            position: retrieved.get_first(self.position_field).and_then(|val| val.as_u64()),
+            source_message_ids: retrieved.get_all(self.source_message_id_field).filter_map(|val| val.as_u64()).collect(),
+            score,
+        })
+    }
+
+    fn write_entry(&self, writer: &mut IndexWriter, entry: &MemoryEntry) -> Result<(), TantivyError> {
+        let mut doc = tantivy::doc!(
+            self.id_field => entry.id.as_str(),
+            self.chat_field => entry.text.as_str(),
+            self.source_field => entry.source.as_str()
+        );
+        if let Some(date) = entry.date {
+            doc.add_i64(self.date_field, date);
+        }
+        if let Some(companion_id) = entry.companion_id {
+            doc.add_u64(self.companion_id_field, companion_id);
+        }
+        if let Some(message_id) = entry.message_id {
+            doc.add_u64(self.message_id_field, message_id);
+        }
+        if let Some(document) = &entry.document {
+            doc.add_text(self.document_field, document);
+        }
+        if let Some(position) = entry.position {
+            doc.add_u64(self.position_field, position);
+        }
+        for source_message_id in &entry.source_message_ids {
+            doc.add_u64(self.source_message_id_field, *source_message_id);
+        }
+        writer.add_document(doc)?;
         Ok(())
     }
 
-    pub fn get_matches(&self, query_string: &str, limit: usize) -> Result<Vec<String>, TantivyError> {
+    // dated now, returns id of the new entry
+    pub fn add_entry(&self, text: &str, source: MemorySource, companion_id: i32) -> Result<String, TantivyError> {
+        let entry = MemoryEntry {
+            id: new_entry_id(),
+            text: text.to_string(),
+            date: Some(Local::now().timestamp()),
+            source: source.name().to_string(),
+            companion_id: Some(companion_id as u64),
+            message_id: None,
+            document: None,
+            position: None,
+            source_message_ids: Vec::new(),
+            score: None,
+        };
+        let mut writer = self.index.writer(50_000_000)?;
+        self.write_entry(&mut writer, &entry)?;
+        writer.commit()?;
+        Ok(entry.id)
+    }
+
+    // stored embeddings of changed or removed entries don't match their text anymore
+    fn forget_embeddings<'a>(ids: impl IntoIterator<Item = &'a str>) {
+        for id in ids {
+            if let Err(e) = EmbeddingStore::delete(id) {
+                eprintln!("Error while removing embedding of long-term memory entry: {}", e);
+            }
+        }
+    }
+
+    // replaces the entry made from an ai message, so only its selected alternative is remembered.
+    // date is a unix timestamp of the message, returns id of the entry
+    pub fn set_message_entry(&self, message_id: i32, companion_id: i32, date: i64, text: &str) -> Result<String, TantivyError> {
+        let message_term = Term::from_field_u64(self.message_id_field, message_id as u64);
+        let searcher = self.index.reader()?.searcher();
+        let existing = searcher.search(&TermQuery::new(message_term.clone(), IndexRecordOption::Basic), &TopDocs::with_limit(1))?;
+        let id = match existing.first() {
+            Some((_, address)) => self.entry_at(&searcher, *address, None)?.id,
+            None => new_entry_id(),
+        };
+        let entry = MemoryEntry {
+            id,
+            text: text.to_string(),
+            date: Some(date),
+            source: MemorySource::Chat.name().to_string(),
+            companion_id: Some(companion_id as u64),
+            message_id: Some(message_id as u64),
+            document: None,
+            position: None,
+            source_message_ids: Vec::new(),
+            score: None,
+        };
+        let mut writer = self.index.writer(50_000_000)?;
+        writer.delete_term(message_term);
+        self.write_entry(&mut writer, &entry)?;
+        writer.commit()?;
+        LongTermMem::forget_embeddings([entry.id.as_str()]);
+        Ok(entry.id)
+    }
+
+    pub fn get_entry(&self, id: &str) -> Result<Option<MemoryEntry>, TantivyError> {
+        let searcher = self.index.reader()?.searcher();
+        let found = searcher.search(&TermQuery::new(self.id_term(id), IndexRecordOption::Basic), &TopDocs::with_limit(1))?;
+        match found.first() {
+            Some((_, address)) => Ok(Some(self.entry_at(&searcher, *address, None)?)),
+            None => Ok(None),
+        }
+    }
+
+    // entries of the companion from oldest to newest, with number of all its entries
+    pub fn list_entries(&self, companion_id: i32, start_index: usize, limit: usize) -> Result<(usize, Vec<MemoryEntry>), TantivyError> {
+        let searcher = self.index.reader()?.searcher();
+        let mut entries: Vec<MemoryEntry> = Vec::new();
+        for address in searcher.search(&self.companion_query(companion_id), &DocSetCollector)? {
+            entries.push(self.entry_at(&searcher, address, None)?);
+        }
+        entries.sort_by(|a, b| a.id.cmp(&b.id));
+        let total = entries.len();
+        Ok((total, entries.into_iter().skip(start_index).take(limit).collect()))
+    }
+
+    // text is changed in place, id and metadata of the entry stay the same. false if there is no entry with this id
+    pub fn update_entry(&self, id: &str, text: &str) -> Result<bool, TantivyError> {
+        let mut entry = match self.get_entry(id)? {
+            Some(entry) => entry,
+            None => return Ok(false),
+        };
+        entry.text = text.to_string();
+        let mut writer = self.index.writer(50_000_000)?;
+        writer.delete_term(self.id_term(id));
+        self.write_entry(&mut writer, &entry)?;
+        writer.commit()?;
+        LongTermMem::forget_embeddings([id]);
+        Ok(true)
+    }
+
+    // false if there is no entry with this id
+    pub fn delete_entry(&self, id: &str) -> Result<bool, TantivyError> {
+        if self.get_entry(id)?.is_none() {
+            return Ok(false);
+        }
+        let mut writer = self.index.writer(50_000_000)?;
+        writer.delete_term(self.id_term(id));
+        writer.commit()?;
+        LongTermMem::forget_embeddings([id]);
+        Ok(true)
+    }
+
+    // entries of the companion, most relevant first, with their scores. if the query asks about a time, like "last week",
+    // entries from that time come first, and best matches from other times fill the rest.
+    // with a semantic query, entries close in meaning are found even without matching words.
+    // near duplicates of a better entry are skipped
+    pub fn search(&self, query_string: &str, companion_id: i32, limit: usize, semantic: Option<&SemanticQuery>, settings: &RetrievalSettings) -> Result<Vec<MemoryEntry>, TantivyError> {
         let mut sanitized_query = query_string.replace("\n", " ");
         sanitized_query = sanitized_query
             .chars()
@@ -50,31 +439,389 @@ impl LongTermMem {
         let reader = self.index.reader()?;
         let searcher = reader.searcher();
         let qp = QueryParser::for_index(&self.index, vec![self.chat_field]);
-        let query = match qp.parse_query(&sanitized_query) {
+        let text_query = match qp.parse_query(&sanitized_query) {
             Ok(q) => q,
             Err(e) => return Err(TantivyError::from(e)),
         };
+        let query = BooleanQuery::new(vec![
+            (Occur::Must, self.companion_query(companion_id)),
+            (Occur::Must, text_query),
+        ]);
     
         if limit == 0 {
             return Ok(Vec::new());
         }
+        // more candidates than needed are ranked, so good matches in meaning, recent entries
+        // and entries left after removing duplicates aren't cut off
+        let candidates = limit * 4;
     
-        let matches: Vec<(f32, tantivy::DocAddress)> = searcher.search(&query, &TopDocs::with_limit(limit))?;
-        let mut result: Vec<String> = Vec::new();
+        let mut dated: Vec<MemoryEntry> = Vec::new();
+        if let Some((start, end)) = date_range(query_string, Local::now()) {
+            // entries from the range match even without matching words, words only rank them
+            let dated_query = BooleanQuery::new(vec![
+                (Occur::Must, self.companion_query(companion_id)),
+                (Occur::Must, qp.parse_query(&format!("+date:[{} TO {}}} {}", start.timestamp(), end.timestamp(), sanitized_query))?),
+            ]);
+            for (score, text_addr) in searcher.search(&dated_query, &TopDocs::with_limit(candidates))? {
+                dated.push(self.entry_at(&searcher, text_addr, Some(score))?);
+            }
+        }
     
-        for (_, text_addr) in matches {
-            let retrieved = searcher.doc(text_addr)?;
-            let r = retrieved.get_first(self.chat_field).and_then(|val| val.as_text()).unwrap_or("");
-            result.push(r.to_string());
+        let mut matches: Vec<MemoryEntry> = Vec::new();
+        for (score, text_addr) in searcher.search(&query, &TopDocs::with_limit(candidates))? {
+            matches.push(self.entry_at(&searcher, text_addr, Some(score))?);
+        }
+
+        let mut similarities: HashMap<String, f32> = HashMap::new();
+        if let Some(semantic) = semantic {
+            similarities = EmbeddingStore::get_for_companion(companion_id, &semantic.model)
+                .map_err(|e| TantivyError::InternalError(format!("Error while reading embeddings: {}", e)))?
+                .into_iter()
+                .map(|(id, vector)| (id, cosine_similarity(&semantic.embedding, &vector)))
+                .collect();
+            let mut closest: Vec<(&String, f32)> = similarities.iter().map(|(id, similarity)| (id, *similarity)).filter(|(_, similarity)| *similarity > 0.0).collect();
+            closest.sort_by(|a, b| b.1.total_cmp(&a.1));
+            for (id, _) in closest.into_iter().take(candidates) {
+                if matches.iter().any(|e| &e.id == id) {
+                    continue;
+                }
+                // embeddings of removed entries are left until the next backfill
+                if let Some(mut entry) = self.get_entry(id)? {
+                    entry.score = Some(0.0);
+                    matches.push(entry);
+                }
+            }
+        }
+        // taken from raw scores, before they are normalized for ranking.
+        // entries from the asked time are used whatever their relevance
+        let relevance = relevance(&matches, &similarities, semantic);
+        normalize_scores(&mut dated);
+        normalize_scores(&mut matches);
+        if let Some(semantic) = semantic {
+            blend_scores(&mut dated, &similarities, semantic);
+            blend_scores(&mut matches, &similarities, semantic);
+        }
+
+        let now = Local::now().timestamp();
+        let dated = settings.decay(dated, now);
+        let matches = settings.decay(settings.keep_relevant(matches, &relevance), now);
+        let mut result: Vec<MemoryEntry> = Vec::new();
+        let mut result_words: Vec<HashSet<String>> = Vec::new();
+        for entry in dated.into_iter().chain(matches) {
+            if result.len() >= limit {
+                break;
+            }
+            let words = content_words(&entry.text);
+            if result.iter().any(|e| e.id == entry.id) || result_words.iter().any(|w| is_near_duplicate(w, &words)) {
+                continue;
+            }
+            result.push(entry);
+            result_words.push(words);
         }
     
         Ok(result)
     }
 
-    pub fn erase_memory(&self) -> Result<(), TantivyError> {
+    fn document_query(&self, companion_id: i32, name: &str) -> BooleanQuery {
+        BooleanQuery::new(vec![
+            (Occur::Must, self.companion_query(companion_id)),
+            (Occur::Must, Box::new(TermQuery::new(Term::from_field_text(self.document_field, name), IndexRecordOption::Basic))),
+        ])
+    }
+
+    // chunks are saved as entries of the companion, a document with the same name is replaced.
+    // all documents are written in one commit, so nothing is saved if one of them fails
+    pub fn add_documents(&self, companion_id: i32, documents: Vec<(String, Vec<String>)>) -> Result<(), TantivyError> {
+        let date = Local::now().timestamp();
+        let mut replaced: Vec<String> = Vec::new();
+        for (name, _) in &documents {
+            replaced.extend(self.document_entry_ids(companion_id, name)?);
+        }
+        let mut writer = self.index.writer(50_000_000)?;
+        for (name, chunks) in documents {
+            writer.delete_query(Box::new(self.document_query(companion_id, &name)))?;
+            for (position, chunk) in chunks.into_iter().enumerate() {
+                let entry = MemoryEntry {
+                    id: new_entry_id(),
+                    text: format!("* from {} *\n{}", name, chunk),
+                    date: Some(date),
+                    source: MemorySource::Document.name().to_string(),
+                    companion_id: Some(companion_id as u64),
+                    message_id: None,
+                    document: Some(name.clone()),
+                    position: Some(position as u64),
+                    source_message_ids: Vec::new(),
+                    score: None,
+                };
+                self.write_entry(&mut writer, &entry)?;
+            }
+        }
+        writer.commit()?;
+        LongTermMem::forget_embeddings(replaced.iter().map(|id| id.as_str()));
+        Ok(())
+    }
+
+    fn document_entry_ids(&self, companion_id: i32, name: &str) -> Result<Vec<String>, TantivyError> {
+        let searcher = self.index.reader()?.searcher();
+        let mut ids: Vec<String> = Vec::new();
+        for address in searcher.search(&self.document_query(companion_id, name), &DocSetCollector)? {
+            ids.push(self.entry_at(&searcher, address, None)?.id);
+        }
+        Ok(ids)
+    }
+
+    // documents of the companion, sorted by name
+    pub fn list_documents(&self, companion_id: i32) -> Result<Vec<DocumentView>, TantivyError> {
+        let mut documents: Vec<DocumentView> = Vec::new();
+        for entry in self.export_entries(companion_id)? {
+            let name = match entry.document {
+                Some(name) => name,
+                None => continue,
+            };
+            match documents.iter_mut().find(|d| d.name == name) {
+                Some(document) => document.chunks += 1,
+                None => documents.push(DocumentView { name, chunks: 1, date: entry.date }),
+            }
+        }
+        documents.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(documents)
+    }
+
+    // removes all chunks of the document, false if the companion has no document with this name
+    pub fn delete_document(&self, companion_id: i32, name: &str) -> Result<bool, TantivyError> {
+        let removed = self.document_entry_ids(companion_id, name)?;
+        if removed.is_empty() {
+            return Ok(false);
+        }
+        let mut writer = self.index.writer(50_000_000)?;
+        writer.delete_query(Box::new(self.document_query(companion_id, name)))?;
+        writer.commit()?;
+        LongTermMem::forget_embeddings(removed.iter().map(|id| id.as_str()));
+        Ok(true)
+    }
+
+    // every fact is saved as a separate entry linked to all source messages. returns ids of the new entries
+    pub fn add_summary(&self, companion_id: i32, date: i64, facts: &[String], source_message_ids: &[u64]) -> Result<Vec<String>, TantivyError> {
+        let mut writer = self.index.writer(50_000_000)?;
+        let mut ids: Vec<String> = Vec::new();
+        for fact in facts {
+            let entry = MemoryEntry {
+                id: new_entry_id(),
+                text: fact.clone(),
+                date: Some(date),
+                source: MemorySource::Summary.name().to_string(),
+                companion_id: Some(companion_id as u64),
+                message_id: None,
+                document: None,
+                position: None,
+                source_message_ids: source_message_ids.to_vec(),
+                score: None,
+            };
+            self.write_entry(&mut writer, &entry)?;
+            ids.push(entry.id);
+        }
+        writer.commit()?;
+        Ok(ids)
+    }
+
+    // ids of messages that are already summarized in entries of the companion
+    pub fn summarized_message_ids(&self, companion_id: i32) -> Result<HashSet<u64>, TantivyError> {
+        let query = BooleanQuery::new(vec![
+            (Occur::Must, self.companion_query(companion_id)),
+            (Occur::Must, Box::new(TermQuery::new(Term::from_field_text(self.source_field, MemorySource::Summary.name()), IndexRecordOption::Basic))),
+        ]);
+        let searcher = self.index.reader()?.searcher();
+        let mut ids: HashSet<u64> = HashSet::new();
+        for address in searcher.search(&query, &DocSetCollector)? {
+            ids.extend(self.entry_at(&searcher, address, None)?.source_message_ids);
+        }
+        Ok(ids)
+    }
+
+    pub fn delete_entries(&self, ids: &[String]) -> Result<(), TantivyError> {
+        let mut writer = self.index.writer(50_000_000)?;
+        for id in ids {
+            writer.delete_term(self.id_term(id));
+        }
+        writer.commit()?;
+        LongTermMem::forget_embeddings(ids.iter().map(|id| id.as_str()));
+        Ok(())
+    }
+
+    // removes all entries of the companion, entries of other companions are kept
+    pub fn erase_memory(&self, companion_id: i32) -> Result<(), TantivyError> {
         let mut writer = self.index.writer(50_000_000)?;
-        writer.delete_all_documents()?;
+        writer.delete_term(self.companion_term(companion_id));
         writer.commit()?;
+        if let Err(e) = EmbeddingStore::delete_companion(companion_id) {
+            eprintln!("Error while removing embeddings of long-term memory: {}", e);
+        }
+        if let Err(e) = ConsolidatedMessages::delete_companion(companion_id) {
+            eprintln!("Error while removing consolidated messages of long-term memory: {}", e);
+        }
         Ok(())
     }
-}
\ No newline at end of file
+
+    // all entries of the companion from oldest to newest
+    pub fn export_entries(&self, companion_id: i32) -> Result<Vec<MemoryEntry>, TantivyError> {
+        self.list_entries(companion_id, 0, usize::MAX).map(|(_, entries)| entries)
+    }
+
+    // entries are added to the companion with new ids, without links to messages. entries without date are dated now,
+    // unknown sources are imported as manual. returns number of imported entries
+    pub fn import_entries(&self, companion_id: i32, imported: Vec<ImportedEntry>) -> Result<usize, TantivyError> {
+        let mut writer = self.index.writer(50_000_000)?;
+        let count = imported.len();
+        for imported_entry in imported {
+            let source = match imported_entry.source.as_deref() {
+                Some("chat") => MemorySource::Chat,
+                Some("summary") => MemorySource::Summary,
+                _ => MemorySource::Manual,
+            };
+            let entry = MemoryEntry {
+                id: new_entry_id(),
+                text: imported_entry.text,
+                date: Some(imported_entry.date.unwrap_or_else(|| Local::now().timestamp())),
+                source: source.name().to_string(),
+                companion_id: Some(companion_id as u64),
+                message_id: None,
+                document: None,
+                position: None,
+                source_message_ids: Vec::new(),
+                score: None,
+            };
+            self.write_entry(&mut writer, &entry)?;
+        }
+        writer.commit()?;
+        Ok(count)
+    }
+}
+
+// keyword scores are divided by the best one, so they are between 0 and 1 like similarities
+// and the weights mean the same for every query when ranking
+fn normalize_scores(entries: &mut [MemoryEntry]) {
+    let best = entries.iter().filter_map(|e| e.score).fold(0.0, f32::max);
+    for entry in entries.iter_mut() {
+        entry.score = Some(if best > 0.0 { entry.score.unwrap_or(0.0) / best } else { 0.0 });
+    }
+}
+
+// weighted average of keyword score and similarity, both between 0 and 1
+fn blend(keyword: f32, similarity: f32, semantic: &SemanticQuery) -> f32 {
+    let total_weight = semantic.keyword_weight + semantic.semantic_weight;
+    if total_weight > 0.0 { (semantic.keyword_weight * keyword + semantic.semantic_weight * similarity.max(0.0)) / total_weight } else { 0.0 }
+}
+
+// normalized keyword scores are blended with similarities and entries sorted by the blended score
+fn blend_scores(entries: &mut [MemoryEntry], similarities: &HashMap<String, f32>, semantic: &SemanticQuery) {
+    for entry in entries.iter_mut() {
+        let similarity = similarities.get(&entry.id).copied().unwrap_or(0.0);
+        entry.score = Some(blend(entry.score.unwrap_or(0.0), similarity, semantic));
+    }
+    entries.sort_by(|a, b| b.score.unwrap_or(0.0).total_cmp(&a.score.unwrap_or(0.0)));
+}
+
+// raw keyword score that counts as relevance 0.5
+const KEYWORD_SCORE_MIDPOINT: f32 = 3.0;
+
+// raw keyword scores have no upper bound, this maps them between 0 and 1 without looking at other matches
+fn keyword_relevance(score: f32) -> f32 {
+    let score = score.max(0.0);
+    score / (score + KEYWORD_SCORE_MIDPOINT)
+}
+
+// relevance of entries compared with the minimum score, blended with similarity like the ranking score
+fn relevance(entries: &[MemoryEntry], similarities: &HashMap<String, f32>, semantic: Option<&SemanticQuery>) -> HashMap<String, f32> {
+    entries.iter()
+        .map(|entry| {
+            let keyword = keyword_relevance(entry.score.unwrap_or(0.0));
+            let value = match semantic {
+                Some(semantic) => blend(keyword, similarities.get(&entry.id).copied().unwrap_or(0.0), semantic),
+                None => keyword,
+            };
+            (entry.id.clone(), value)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn duplicates(a: &str, b: &str) -> bool {
+        is_near_duplicate(&content_words(a), &content_words(b))
+    }
+
+    #[test]
+    fn same_memory_with_other_date_is_a_duplicate() {
+        assert!(duplicates("* at Monday 01.01.2024 10:00 *\nAnn: I love tea\nBot: me too", "* at Friday 05.01.2024 18:00 *\nann: I LOVE tea!\nBot: Me too."));
+    }
+
+    #[test]
+    fn small_difference_in_long_entry_is_a_duplicate() {
+        let a = "Ann: we went hiking in the mountains with my brother and his dog last summer";
+        let b = "Ann: we went hiking in the mountains with my sister and his dog last summer";
+        assert!(duplicates(a, b));
+    }
+
+    #[test]
+    fn different_memories_are_not_duplicates() {
+        assert!(!duplicates("Ann: I love tea", "Ann: I hate coffee"));
+        assert!(!duplicates("* from notes.md *\nAnn likes tea", "* from notes.md *\nAnn has a cat"));
+    }
+
+    #[test]
+    fn empty_entries_are_never_duplicates() {
+        assert!(!duplicates("", ""));
+        assert!(!duplicates("* at Monday 01.01.2024 10:00 *\n...", "* at Monday 01.01.2024 10:00 *\n!!!"));
+    }
+
+    fn entry(id: &str, score: f32) -> MemoryEntry {
+        MemoryEntry {
+            id: id.to_string(),
+            text: String::new(),
+            date: None,
+            source: "chat".to_string(),
+            companion_id: Some(1),
+            message_id: None,
+            document: None,
+            position: None,
+            source_message_ids: Vec::new(),
+            score: Some(score),
+        }
+    }
+
+    fn kept_ids(raw_scores: &[(&str, f32)], similarities: &HashMap<String, f32>, semantic: Option<&SemanticQuery>, min_score: f32) -> Vec<String> {
+        let mut entries: Vec<MemoryEntry> = raw_scores.iter().map(|(id, score)| entry(id, *score)).collect();
+        let relevance = relevance(&entries, similarities, semantic);
+        normalize_scores(&mut entries);
+        let settings = RetrievalSettings { min_score, half_life_days: 0.0 };
+        settings.keep_relevant(entries, &relevance).into_iter().map(|e| e.id).collect()
+    }
+
+    #[test]
+    fn query_without_real_match_returns_nothing() {
+        // the best of the noise hits gets 1.0 for ranking, but it is still not relevant
+        assert!(kept_ids(&[("a", 0.4), ("b", 0.2), ("c", 0.1)], &HashMap::new(), None, 0.3).is_empty());
+    }
+
+    #[test]
+    fn minimum_score_does_not_depend_on_other_matches() {
+        assert_eq!(kept_ids(&[("strong", 12.0), ("weak", 0.5)], &HashMap::new(), None, 0.3), vec!["strong"]);
+        assert_eq!(kept_ids(&[("weak", 0.5)], &HashMap::new(), None, 0.3), Vec::<String>::new());
+        assert_eq!(kept_ids(&[("a", 0.4), ("b", 0.2)], &HashMap::new(), None, 0.0), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn similarity_counts_towards_relevance() {
+        let semantic = SemanticQuery { embedding: Vec::new(), model: String::new(), keyword_weight: 1.0, semantic_weight: 1.0 };
+        let similarities: HashMap<String, f32> = [("close", 0.9), ("far", 0.1)].iter().map(|(id, s)| (id.to_string(), *s)).collect();
+        assert_eq!(kept_ids(&[("close", 0.0), ("far", 0.4)], &similarities, Some(&semantic), 0.3), vec!["close"]);
+    }
+
+    #[test]
+    fn only_a_date_line_is_skipped() {
+        assert_eq!(content_words("* at Monday *\nHello there"), ["hello", "there"].iter().map(|w| w.to_string()).collect());
+        assert_eq!(content_words("*waves*\nHello"), ["waves", "hello"].iter().map(|w| w.to_string()).collect());
+    }
+}
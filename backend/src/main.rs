@@ -1,20 +1,45 @@
-use actix_web::{get, post, delete, put, App, web, HttpResponse, HttpServer};
+use actix_web::{get, post, delete, put, App, web, HttpRequest, HttpResponse, HttpServer};
+use actix_web::http::header;
+use actix_multipart::Multipart;
 use futures_util::StreamExt as _;
 mod database;
-use database::{Database, Message, NewMessage, CompanionView, UserView, ConfigModify};
+use database::{Database, Message, NewMessage, Companion, CompanionView, UserView, ConfigModify, Conversation, NewConversation, ConversationModify, PromptTemplateData, PromptTemplateView, io_error};
 mod long_term_mem;
-use long_term_mem::LongTermMem;
+use long_term_mem::{LongTermMem, MemoryEntry, MemorySource, ImportedEntry};
 mod dialogue_tuning;
 use dialogue_tuning::DialogueTuning;
 mod character_card;
 use character_card::CharacterCard;
-use serde::Deserialize;
+use serde::{Serialize, Deserialize};
 mod llm;
-use crate::llm::prompt;
+use crate::llm::{prompt, prompt_with_callback, preview_prompt, remember_selected_alternative, search_memory, backfill_embeddings, ReplyTarget};
+mod model_manager;
+use model_manager::ModelManager;
+mod openai;
+mod prompt_template;
+mod gguf;
+mod context;
+mod inference_queue;
+mod stateless;
+mod time_expression;
+mod chunking;
+use chunking::split_document;
+mod embeddings;
+use embeddings::EmbeddingStore;
+mod consolidation;
+use consolidation::{consolidate_memory, ConsolidatedMessages, ConsolidationOptions};
+mod facts;
+use facts::{Facts, NewFact, FactModify};
+mod fact_extraction;
+use fact_extraction::extract_facts;
+use inference_queue::{InferenceQueue, CancelFlag, QUEUE_CAPACITY};
+use openai::{ChatCompletionRequest, CompletionRequest, ErrorResponse};
 
 use std::fs;
 use std::fs::File;
 use std::io::{Write, Read};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
 
 #[get("/")]
 async fn index() -> HttpResponse {
@@ -71,6 +96,35 @@ async fn companion_avatar_custom() -> actix_web::Result<actix_web::HttpResponse>
 struct MessageQuery {
     start_index: Option<usize>,
     limit: Option<usize>,
+    conversation_id: Option<i32>,
+}
+
+#[derive(serde::Deserialize)]
+struct ConversationQuery {
+    conversation_id: Option<i32>,
+}
+
+#[derive(Deserialize)]
+struct RegenerateQuery {
+    conversation_id: Option<i32>,
+    keep_partial: Option<bool>,
+}
+
+// conversation selected with "conversation_id" query parameter,
+// without it the latest conversation of the active companion is used
+fn resolve_conversation(conversation_id: Option<i32>) -> Result<Conversation, HttpResponse> {
+    let conversation = match conversation_id {
+        Some(id) => Database::get_conversation(id),
+        None => Database::get_default_conversation(),
+    };
+    match conversation {
+        Ok(c) => Ok(c),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Err(HttpResponse::NotFound().body("Conversation not found")),
+        Err(e) => {
+            println!("Failed to get conversation: {}", e);
+            Err(HttpResponse::InternalServerError().body("Error while getting conversation, check logs for more information"))
+        }
+    }
 }
 
 #[get("/api/message")]
@@ -80,8 +134,13 @@ async fn message(query_params: web::Query<MessageQuery>) -> HttpResponse {
     // 50 Messages is the max
     let limit: usize = query_params.limit.unwrap_or(15).min(50);
 
+    let conversation = match resolve_conversation(query_params.conversation_id) {
+        Ok(c) => c,
+        Err(response) => return response,
+    };
+
     // query to database, and return messages
-    let messages: Vec<Message> = match Database::get_x_messages(limit, start_index) {
+    let messages: Vec<Message> = match Database::get_x_messages(conversation.id, limit, start_index) {
         Ok(v) => v,
         Err(e) => {
             println!("Failed to get messages from database: {}", e);
@@ -93,8 +152,12 @@ async fn message(query_params: web::Query<MessageQuery>) -> HttpResponse {
 }
 
 #[post("/api/message")]
-async fn message_post(received: web::Json<NewMessage>) -> HttpResponse {
-    match Database::insert_message(received.into_inner()) {
+async fn message_post(received: web::Json<NewMessage>, query_params: web::Query<ConversationQuery>) -> HttpResponse {
+    let conversation = match resolve_conversation(query_params.conversation_id) {
+        Ok(c) => c,
+        Err(response) => return response,
+    };
+    match Database::insert_message(conversation.id, received.into_inner()) {
         Ok(_) => HttpResponse::Ok().body("Message added!"),
         Err(e) => {
             println!("Failed to add message: {}", e);
@@ -104,8 +167,12 @@ async fn message_post(received: web::Json<NewMessage>) -> HttpResponse {
 }
 
 #[delete("/api/message")]
-async fn clear_messages() -> HttpResponse {
-    match Database::erase_messages() {
+async fn clear_messages(query_params: web::Query<ConversationQuery>) -> HttpResponse {
+    let conversation = match resolve_conversation(query_params.conversation_id) {
+        Ok(c) => c,
+        Err(response) => return response,
+    };
+    match Database::erase_messages(conversation.id) {
         Ok(_) => HttpResponse::Ok().body("Chat log cleared!"),
         Err(e) => {
             println!("Failed to clear chat log: {}", e);
@@ -130,7 +197,9 @@ async fn message_id(id: web::Path<i32>) -> HttpResponse {
 #[put("/api/message/{id}")]
 async fn message_put(id: web::Path<i32>, received: web::Json<NewMessage>) -> HttpResponse {
     match Database::edit_message(*id, received.into_inner()) {
+        Ok(edited_id) if edited_id != *id => HttpResponse::Ok().body(format!("Message at id {} already has replies, edited message was saved in a new branch at id {}!", id, edited_id)),
         Ok(_) => HttpResponse::Ok().body(format!("Message edited at id {}!", id)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => HttpResponse::NotFound().body(format!("Message with id {} not found", id)),
         Err(e) => {
             println!("Failed to edit message at id {}: {}", id, e);
             HttpResponse::InternalServerError().body(format!("Error while editing message at id {}, check logs for more information", id))
@@ -149,8 +218,93 @@ async fn message_delete(id: web::Path<i32>) -> HttpResponse {
     }
 }
 
+#[get("/api/message/{id}/alternatives")]
+async fn message_alternatives(id: web::Path<i32>) -> HttpResponse {
+    match Database::get_message_alternatives(*id) {
+        Ok(v) => {
+            let alternatives_json = serde_json::to_string(&v).unwrap_or(String::from("Error serializing message alternatives as JSON"));
+            HttpResponse::Ok().body(alternatives_json)
+        },
+        Err(rusqlite::Error::QueryReturnedNoRows) => HttpResponse::NotFound().body(format!("Message with id {} not found", id)),
+        Err(e) => {
+            println!("Failed to get alternatives of message at id {}: {}", id, e);
+            HttpResponse::InternalServerError().body(format!("Error while getting alternatives of message at id {}, check logs for more information", id))
+        }
+    }
+}
+
+// long-term memory is updated too, so it remembers only the selected alternative
+fn refresh_alternative_memory(id: i32) {
+    if let Err(e) = remember_selected_alternative(id) {
+        eprintln!("Failed to update long-term memory of message at id {}: {}", id, e);
+    }
+}
+
+#[put("/api/message/{id}/alternatives/{index}/active")]
+async fn message_alternative_select(path: web::Path<(i32, usize)>) -> HttpResponse {
+    let (id, alternative) = path.into_inner();
+    match Database::select_message_alternative(id, alternative) {
+        Ok(_) => {
+            refresh_alternative_memory(id);
+            HttpResponse::Ok().body(format!("Alternative {} of message at id {} is now selected!", alternative, id))
+        },
+        Err(rusqlite::Error::QueryReturnedNoRows) => HttpResponse::NotFound().body(format!("Message with id {} not found", id)),
+        Err(rusqlite::Error::InvalidParameterName(e)) => HttpResponse::BadRequest().body(e),
+        Err(e) => {
+            println!("Failed to select alternative {} of message at id {}: {}", alternative, id, e);
+            HttpResponse::InternalServerError().body(format!("Error while selecting alternative of message at id {}, check logs for more information", id))
+        }
+    }
+}
+
+#[delete("/api/message/{id}/alternatives/{index}")]
+async fn message_alternative_delete(path: web::Path<(i32, usize)>) -> HttpResponse {
+    let (id, alternative) = path.into_inner();
+    match Database::delete_message_alternative(id, alternative) {
+        Ok(_) => {
+            refresh_alternative_memory(id);
+            HttpResponse::Ok().body(format!("Alternative {} of message at id {} deleted!", alternative, id))
+        },
+        Err(rusqlite::Error::QueryReturnedNoRows) => HttpResponse::NotFound().body(format!("Message with id {} not found", id)),
+        Err(rusqlite::Error::InvalidParameterName(e)) => HttpResponse::BadRequest().body(e),
+        Err(e) => {
+            println!("Failed to delete alternative {} of message at id {}: {}", alternative, id, e);
+            HttpResponse::InternalServerError().body(format!("Error while deleting alternative of message at id {}, check logs for more information", id))
+        }
+    }
+}
+
+// new conversation with messages of the branch from the first message to this one
+#[post("/api/message/{id}/fork")]
+async fn message_fork(id: web::Path<i32>) -> HttpResponse {
+    match Database::fork_conversation(*id) {
+        Ok(conversation_id) => HttpResponse::Ok().json(CreatedId { id: conversation_id }),
+        Err(rusqlite::Error::QueryReturnedNoRows) => HttpResponse::NotFound().body(format!("Message with id {} not found", id)),
+        Err(e) => {
+            println!("Failed to fork conversation at message id {}: {}", id, e);
+            HttpResponse::InternalServerError().body(format!("Error while forking conversation at message id {}, check logs for more information", id))
+        }
+    }
+}
+
 //              Companion
 
+// every companion has its own avatar file, served under /api/companions/{id}/avatar
+fn save_companion_avatar(companion_id: i32, data: &[u8]) -> Result<String, std::io::Error> {
+    fs::create_dir_all("assets/avatars")?;
+    let mut avatar_file = File::create(format!("assets/avatars/companion_{}.png", companion_id))?;
+    avatar_file.write_all(data)?;
+    Ok(format!("/api/companions/{}/avatar", companion_id))
+}
+
+async fn read_payload(mut received: web::Payload) -> Result<web::BytesMut, actix_web::Error> {
+    let mut data = web::BytesMut::new();
+    while let Some(chunk) = received.next().await {
+        data.extend_from_slice(&chunk?);
+    }
+    Ok(data)
+}
+
 #[get("/api/companion")]
 async fn companion() -> HttpResponse {
     let companion_data: CompanionView = match Database::get_companion_data() {
@@ -168,6 +322,7 @@ async fn companion() -> HttpResponse {
 async fn companion_edit_data(received: web::Json<CompanionView>) -> HttpResponse {
     match Database::edit_companion(received.into_inner()) {
         Ok(_) => HttpResponse::Ok().body("Companion data edited!"),
+        Err(rusqlite::Error::InvalidParameterName(e)) => HttpResponse::BadRequest().body(e),
         Err(e) => {
             println!("Failed to edit companion data: {}", e);
             HttpResponse::InternalServerError().body("Error while editing companion data, check logs for more information")
@@ -176,13 +331,15 @@ async fn companion_edit_data(received: web::Json<CompanionView>) -> HttpResponse
 }
 
 #[post("/api/companion/card")]
-async fn companion_card(mut received: actix_web::web::Payload) -> HttpResponse {
+async fn companion_card(received: actix_web::web::Payload) -> HttpResponse {
     // curl -X POST -H "Content-Type: image/png" -T card.png http://localhost:3000/api/companion/card
-    let mut data = web::BytesMut::new();
-    while let Some(chunk) = received.next().await {
-        let d = chunk.unwrap();
-        data.extend_from_slice(&d);
-    }
+    let data = match read_payload(received).await {
+        Ok(d) => d,
+        Err(e) => {
+            eprintln!("Error while receiving character card: {}", e);
+            return HttpResponse::BadRequest().body("Error while receiving character card");
+        }
+    };
     let character_card: CharacterCard = match CharacterCard::load_character_card(&data) {
         Ok(c) => c,
         Err(e) => {
@@ -191,27 +348,34 @@ async fn companion_card(mut received: actix_web::web::Payload) -> HttpResponse {
         }
     };
     let character_name = character_card.name.to_string();
-    let mut avatar_file = match File::create("assets/avatar.png") {
-        Ok(f) => f,
+    let companion_id = match Database::import_character_json(character_card) {
+        Ok(id) => id,
         Err(e) => {
-            eprintln!("Error while creating 'avatar.png' file in a 'assets' folder: {}", e);
+            eprintln!("Error while importing character card: {}", e);
             return HttpResponse::InternalServerError().body("Error while importing character card, check logs for more information");
         }
     };
-    match avatar_file.write_all(&data) {
-        Ok(_) => {},
+    let avatar_path = match save_companion_avatar(companion_id, &data) {
+        Ok(p) => p,
         Err(e) => {
-            eprintln!("Error while writing bytes to 'avatar.png' file in a 'assets' folder: {}", e);
+            eprintln!("Error while saving avatar of companion {}: {}", companion_id, e);
             return HttpResponse::InternalServerError().body("Error while importing character card, check logs for more information");
         }
     };
-    match Database::import_character_card(character_card, "assets/avatar.png") {
+    match Database::change_companion_avatar(companion_id, &avatar_path) {
         Ok(_) => {},
         Err(e) => {
             eprintln!("Error while changing companion avatar using character card: {}", e);
             return HttpResponse::InternalServerError().body("Error while importing character card, check logs for more information");
         }
     };
+    match Database::set_active_companion(companion_id) {
+        Ok(_) => {},
+        Err(e) => {
+            eprintln!("Error while selecting imported companion: {}", e);
+            return HttpResponse::InternalServerError().body("Error while importing character card, check logs for more information");
+        }
+    };
     println!("Character \"{}\" imported successfully! (from character card)", character_name);
     HttpResponse::Ok().body("Updated companion data via character card!")
 
@@ -220,7 +384,7 @@ async fn companion_card(mut received: actix_web::web::Payload) -> HttpResponse {
 #[post("/api/companion/characterJson")]
 async fn companion_character_json(received: web::Json<CharacterCard>) -> HttpResponse {
     let character_name = received.name.to_string();
-    match Database::import_character_json(received.into_inner()) {
+    match Database::import_character_json(received.into_inner()).and_then(Database::set_active_companion) {
         Ok(_) => {
             println!("Character \"{}\" imported successfully! (from character JSON)", character_name);
             HttpResponse::Ok().body("Character json imported successfully!") 
@@ -237,47 +401,43 @@ async fn get_companion_character_json() -> HttpResponse {
     match Database::get_companion_card_data() {
         Ok(v) => { 
             let character_json: String = serde_json::to_string_pretty(&v as &CharacterCard).unwrap_or(String::from("Error serializing companion data as JSON"));
-            return HttpResponse::Ok().body(character_json);
+            HttpResponse::Ok().body(character_json)
         },
         Err(e) => {
             println!("Failed to get companion card data: {}", e);
-            return HttpResponse::InternalServerError().body("Error while getting companion card data, check logs for more information");
+            HttpResponse::InternalServerError().body("Error while getting companion card data, check logs for more information")
         },
-    };
+    }
 }
 
 #[post("/api/companion/avatar")]
-async fn companion_avatar(mut received: actix_web::web::Payload) -> HttpResponse {
+async fn companion_avatar(received: actix_web::web::Payload) -> HttpResponse {
     // curl -X POST -H "Content-Type: image/png" -T avatar.png http://localhost:3000/api/companion/avatar
-    let mut data = web::BytesMut::new();
-    while let Some(chunk) = received.next().await {
-        let d = chunk.unwrap();
-        data.extend_from_slice(&d);
-    }
-    if fs::metadata("assets").is_err() {
-        match fs::create_dir("assets") {
-            Ok(_) => {},
-            Err(e) => {
-                eprintln!("Error while creating 'assets' directory: {}", e);
-                return HttpResponse::InternalServerError().body("Error while importing character card, check logs for more information");
-            }
-        };
+    match Database::get_active_companion_id() {
+        Ok(id) => change_avatar(id, received).await,
+        Err(e) => {
+            eprintln!("Error while getting active companion: {}", e);
+            HttpResponse::InternalServerError().body("Error while changing companion avatar, check logs for more information")
+        }
     }
-    let mut avatar_file = match File::create("assets/avatar.png") {
-        Ok(f) => f,
+}
+
+async fn change_avatar(companion_id: i32, received: web::Payload) -> HttpResponse {
+    let data = match read_payload(received).await {
+        Ok(d) => d,
         Err(e) => {
-            eprintln!("Error while creating 'avatar.png' file in a 'assets' folder: {}", e);
-            return HttpResponse::InternalServerError().body("Error while importing character card, check logs for more information");
+            eprintln!("Error while receiving avatar: {}", e);
+            return HttpResponse::BadRequest().body("Error while receiving avatar");
         }
     };
-    match avatar_file.write_all(&data) {
-        Ok(_) => {},
+    let avatar_path = match save_companion_avatar(companion_id, &data) {
+        Ok(p) => p,
         Err(e) => {
-            eprintln!("Error while writing bytes to 'avatar.png' file in a 'assets' folder: {}", e);
-            return HttpResponse::InternalServerError().body("Error while importing character card, check logs for more information");
+            eprintln!("Error while saving avatar of companion {}: {}", companion_id, e);
+            return HttpResponse::InternalServerError().body("Error while changing companion avatar, check logs for more information");
         }
     };
-    match Database::change_companion_avatar("assets/avatar.png") {
+    match Database::change_companion_avatar(companion_id, &avatar_path) {
         Ok(_) => {},
         Err(e) => {
             eprintln!("Error while changing companion avatar: {}", e);
@@ -287,214 +447,1578 @@ async fn companion_avatar(mut received: actix_web::web::Payload) -> HttpResponse
     HttpResponse::Ok().body("Companion avatar changed!")
 }
 
-//              User
+//              Companions
 
-#[get("/api/user")]
-async fn user() -> HttpResponse {
-    let user_data: UserView = match Database::get_user_data() {
+#[get("/api/companions")]
+async fn companions() -> HttpResponse {
+    let companions: Vec<Companion> = match Database::get_companions() {
         Ok(v) => v,
         Err(e) => {
-            println!("Failed to get user data: {}", e);
-            return HttpResponse::InternalServerError().finish();
+            println!("Failed to get companions: {}", e);
+            return HttpResponse::InternalServerError().body("Error while getting companions, check logs for more information");
         }
     };
-    let user_json: String = serde_json::to_string(&user_data).unwrap_or(String::from("Error serializing user data as JSON"));
-    HttpResponse::Ok().body(user_json)
+    let companions_json = serde_json::to_string(&companions).unwrap_or(String::from("Error serializing companions as JSON"));
+    HttpResponse::Ok().body(companions_json)
 }
 
-#[put("/api/user")]
-async fn user_put(received: web::Json<UserView>) -> HttpResponse {
-    match Database::edit_user(received.into_inner()) {
-        Ok(_) => HttpResponse::Ok().body("User data edited!"),
+#[derive(Serialize)]
+struct CreatedId {
+    id: i32
+}
+
+#[post("/api/companions")]
+async fn companions_post(received: web::Json<CompanionView>) -> HttpResponse {
+    match Database::insert_companion(received.into_inner()) {
+        Ok(id) => HttpResponse::Ok().json(CreatedId { id }),
+        Err(rusqlite::Error::InvalidParameterName(e)) => HttpResponse::BadRequest().body(e),
         Err(e) => {
-            println!("Failed to edit user data: {}", e);
-            HttpResponse::InternalServerError().body("Error while editing user data, check logs for more information")
+            println!("Failed to add companion: {}", e);
+            HttpResponse::InternalServerError().body("Error while adding companion, check logs for more information")
         }
     }
 }
 
-
-
-//              Memory
-
-#[derive(Deserialize)]
-struct LongTermMemMessage {
-    entry: String
-}
-
-#[post("/api/memory/longTerm")]
-async fn add_memory_long_term_message(received: web::Json<LongTermMemMessage>) -> HttpResponse {
-    let ltm = match LongTermMem::connect() {
+#[get("/api/companions/active")]
+async fn companions_active() -> HttpResponse {
+    let companion_data = match Database::get_active_companion_id().and_then(Database::get_companion) {
         Ok(v) => v,
         Err(e) => {
-            println!("Failed to connect to long term memory: {}", e);
-            return HttpResponse::InternalServerError().body("Error while connecting to long term memory, check logs for more information");
+            println!("Failed to get active companion: {}", e);
+            return HttpResponse::InternalServerError().body("Error while getting active companion, check logs for more information");
         }
     };
-    match ltm.add_entry(&received.into_inner().entry) {
-        Ok(_) => HttpResponse::Ok().body("Long term memory entry added!"),
-        Err(e) => {
-            println!("Failed to add long term memory entry: {}", e);
-            HttpResponse::InternalServerError().body("Error while adding long term memory entry, check logs for more information")
-        }
-    }
+    let companion_json = serde_json::to_string(&companion_data).unwrap_or(String::from("Error serializing companion as JSON"));
+    HttpResponse::Ok().body(companion_json)
 }
 
-#[delete("/api/memory/longTerm")]
-async fn erase_long_term() -> HttpResponse {
-    let ltm = match LongTermMem::connect() {
+#[get("/api/companions/{id}")]
+async fn companions_id(id: web::Path<i32>) -> HttpResponse {
+    let companion_data: Companion = match Database::get_companion(*id) {
         Ok(v) => v,
+        Err(rusqlite::Error::QueryReturnedNoRows) => return HttpResponse::NotFound().body(format!("Companion with id {} not found", id)),
         Err(e) => {
-            println!("Failed to connect to long term memory: {}", e);
-            return HttpResponse::InternalServerError().body("Error while connecting to long term memory, check logs for more information");
+            println!("Failed to get companion at id {}: {}", id, e);
+            return HttpResponse::InternalServerError().body(format!("Error while getting companion at id {}, check logs for more information", id));
         }
     };
-    match ltm.erase_memory() {
-        Ok(_) => HttpResponse::Ok().body("Long term memory cleared!"),
+    let companion_json = serde_json::to_string(&companion_data).unwrap_or(String::from("Error serializing companion as JSON"));
+    HttpResponse::Ok().body(companion_json)
+}
+
+#[put("/api/companions/{id}")]
+async fn companions_put(id: web::Path<i32>, received: web::Json<CompanionView>) -> HttpResponse {
+    match Database::edit_companion_by_id(*id, received.into_inner()) {
+        Ok(_) => HttpResponse::Ok().body(format!("Companion edited at id {}!", id)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => HttpResponse::NotFound().body(format!("Companion with id {} not found", id)),
+        Err(rusqlite::Error::InvalidParameterName(e)) => HttpResponse::BadRequest().body(e),
         Err(e) => {
-            println!("Failed to clear long term memory: {}", e);
-            HttpResponse::InternalServerError().body("Error while clearing long term memory, check logs for more information")
+            println!("Failed to edit companion at id {}: {}", id, e);
+            HttpResponse::InternalServerError().body(format!("Error while editing companion at id {}, check logs for more information", id))
         }
     }
 }
 
-#[post("/api/memory/dialogueTuning")]
-async fn add_tuning_message() -> HttpResponse {
-    let messages = match Database::get_x_messages(2, 0) {
-        Ok(v) => v,
+#[delete("/api/companions/{id}")]
+async fn companions_delete(id: web::Path<i32>) -> HttpResponse {
+    match Database::delete_companion(*id) {
+        Ok(_) => {
+            let _ = fs::remove_file(format!("assets/avatars/companion_{}.png", id));
+            if let Err(e) = LongTermMem::connect().and_then(|ltm| ltm.erase_memory(*id)) {
+                println!("Failed to clear long term memory of deleted companion {}: {}", id, e);
+            }
+            if let Err(e) = Facts::delete_companion(*id) {
+                println!("Failed to clear facts of deleted companion {}: {}", id, e);
+            }
+            HttpResponse::Ok().body(format!("Companion deleted at id {}!", id))
+        },
+        Err(rusqlite::Error::QueryReturnedNoRows) => HttpResponse::NotFound().body(format!("Companion with id {} not found", id)),
+        Err(rusqlite::Error::InvalidParameterName(e)) => HttpResponse::BadRequest().body(e),
         Err(e) => {
-            println!("Failed to get last 2 messages from database: {}", e);
-            return HttpResponse::InternalServerError().body("Error while getting last 2 messages from database, check logs for more information");
+            println!("Failed to delete companion at id {}: {}", id, e);
+            HttpResponse::InternalServerError().body(format!("Error while deleting companion at id {}, check logs for more information", id))
         }
-    };
-    match DialogueTuning::insert(&messages[0].content, &messages[1].content) {
-        Ok(_) => HttpResponse::Ok().body("Saved previous dialogue as template dialogue"),
+    }
+}
+
+#[put("/api/companions/{id}/active")]
+async fn companions_set_active(id: web::Path<i32>) -> HttpResponse {
+    match Database::set_active_companion(*id) {
+        Ok(_) => HttpResponse::Ok().body(format!("Companion with id {} is now active!", id)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => HttpResponse::NotFound().body(format!("Companion with id {} not found", id)),
         Err(e) => {
-            println!("Failed to save previous dialogue as template dialogue: {}", e);
-            HttpResponse::InternalServerError().body("Error while saving previous dialogue as template dialogue, check logs for more information")
+            println!("Failed to set active companion to id {}: {}", id, e);
+            HttpResponse::InternalServerError().body("Error while changing active companion, check logs for more information")
         }
     }
 }
 
-#[delete("/api/memory/dialogueTuning")]
-async fn erase_tuning_message() -> HttpResponse {
-    match DialogueTuning::clear_dialogues() {
-        Ok(_) => HttpResponse::Ok().body("Dialogue tuning memory cleared!"),
+#[get("/api/companions/{id}/characterJson")]
+async fn companions_character_json(id: web::Path<i32>) -> HttpResponse {
+    match Database::get_companion_card_data_by_id(*id) {
+        Ok(v) => {
+            let character_json: String = serde_json::to_string_pretty(&v).unwrap_or(String::from("Error serializing companion data as JSON"));
+            HttpResponse::Ok().body(character_json)
+        },
+        Err(rusqlite::Error::QueryReturnedNoRows) => HttpResponse::NotFound().body(format!("Companion with id {} not found", id)),
         Err(e) => {
-            println!("Failed to clear dialogue tuning: {}", e);
-            HttpResponse::InternalServerError().body("Error while clearing dialogue tuning, check logs for more information")
+            println!("Failed to get companion card data at id {}: {}", id, e);
+            HttpResponse::InternalServerError().body("Error while getting companion card data, check logs for more information")
+        },
+    }
+}
+
+#[get("/api/companions/{id}/avatar")]
+async fn companions_avatar(id: web::Path<i32>) -> actix_web::Result<actix_web::HttpResponse> {
+    match File::open(format!("assets/avatars/companion_{}.png", id)) {
+        Ok(mut file) => {
+            let mut buffer = Vec::new();
+            file.read_to_end(&mut buffer)?;
+
+            Ok(actix_web::HttpResponse::Ok()
+                .content_type("image/png")
+                .body(buffer))
         }
+        Err(_) => Err(actix_web::error::ErrorNotFound("File not found")),
     }
 }
 
+#[post("/api/companions/{id}/avatar")]
+async fn companions_avatar_post(id: web::Path<i32>, received: actix_web::web::Payload) -> HttpResponse {
+    // curl -X POST -H "Content-Type: image/png" -T avatar.png http://localhost:3000/api/companions/1/avatar
+    match Database::get_companion(*id) {
+        Ok(_) => change_avatar(*id, received).await,
+        Err(rusqlite::Error::QueryReturnedNoRows) => HttpResponse::NotFound().body(format!("Companion with id {} not found", id)),
+        Err(e) => {
+            eprintln!("Error while getting companion at id {}: {}", id, e);
+            HttpResponse::InternalServerError().body("Error while changing companion avatar, check logs for more information")
+        }
+    }
+}
 
-//              Prompting
+//              Conversations
 
 #[derive(Deserialize)]
-struct Prompt {
-    prompt: String
+struct ConversationsQuery {
+    companion_id: Option<i32>,
+    archived: Option<bool>,
 }
 
-#[post("/api/prompt")]
-async fn prompt_message(received: web::Json<Prompt>) -> HttpResponse {
-    let prompt_message = received.into_inner().prompt.clone();
-    match Database::insert_message(NewMessage { ai: false, content: prompt_message.to_string() }) {
-        Ok(_) => {}
+#[get("/api/conversations")]
+async fn conversations(query_params: web::Query<ConversationsQuery>) -> HttpResponse {
+    let conversations: Vec<Conversation> = match Database::get_conversations(query_params.companion_id, query_params.archived) {
+        Ok(v) => v,
         Err(e) => {
-            eprintln!("Failed to add message to database: {}", e);
-            return HttpResponse::InternalServerError().body("Error while adding message to database, check logs for more information");
+            println!("Failed to get conversations: {}", e);
+            return HttpResponse::InternalServerError().body("Error while getting conversations, check logs for more information");
         }
     };
-    match prompt(&prompt_message) {
-        Ok(v) => HttpResponse::Ok().body(v),
+    let conversations_json = serde_json::to_string(&conversations).unwrap_or(String::from("Error serializing conversations as JSON"));
+    HttpResponse::Ok().body(conversations_json)
+}
+
+#[post("/api/conversations")]
+async fn conversations_post(received: web::Json<NewConversation>) -> HttpResponse {
+    match Database::create_conversation(received.into_inner()) {
+        Ok(id) => HttpResponse::Ok().json(CreatedId { id }),
+        Err(rusqlite::Error::InvalidParameterName(e)) => HttpResponse::BadRequest().body(e),
         Err(e) => {
-            println!("Failed to generate prompt: {}", e);
-            HttpResponse::InternalServerError().body("Error while generating prompt, check logs for more information")
+            println!("Failed to create conversation: {}", e);
+            HttpResponse::InternalServerError().body("Error while creating conversation, check logs for more information")
         }
     }
 }
 
-#[get("/api/prompt/regenerate")]
-async fn regenerate_prompt() -> HttpResponse {
-    match Database::delete_latest_message() {
-        Ok(_) => {},
+#[get("/api/conversations/{id}")]
+async fn conversations_id(id: web::Path<i32>) -> HttpResponse {
+    let conversation: Conversation = match Database::get_conversation(*id) {
+        Ok(v) => v,
+        Err(rusqlite::Error::QueryReturnedNoRows) => return HttpResponse::NotFound().body(format!("Conversation with id {} not found", id)),
+        Err(e) => {
+            println!("Failed to get conversation at id {}: {}", id, e);
+            return HttpResponse::InternalServerError().body(format!("Error while getting conversation at id {}, check logs for more information", id));
+        }
+    };
+    let conversation_json = serde_json::to_string(&conversation).unwrap_or(String::from("Error serializing conversation as JSON"));
+    HttpResponse::Ok().body(conversation_json)
+}
+
+#[put("/api/conversations/{id}")]
+async fn conversations_put(id: web::Path<i32>, received: web::Json<ConversationModify>) -> HttpResponse {
+    match Database::edit_conversation(*id, received.into_inner()) {
+        Ok(_) => HttpResponse::Ok().body(format!("Conversation edited at id {}!", id)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => HttpResponse::NotFound().body(format!("Conversation with id {} not found", id)),
         Err(e) => {
-            println!("Failed to delete latest message: {}", e);
-            return HttpResponse::InternalServerError().body("Error while deleting latest message, check logs for more information");
+            println!("Failed to edit conversation at id {}: {}", id, e);
+            HttpResponse::InternalServerError().body(format!("Error while editing conversation at id {}, check logs for more information", id))
         }
     }
-    let prompt_msg: String = match Database::get_latest_message() {
-        Ok(v) => v.content,
+}
+
+#[delete("/api/conversations/{id}")]
+async fn conversations_delete(id: web::Path<i32>) -> HttpResponse {
+    match Database::delete_conversation(*id) {
+        Ok(_) => HttpResponse::Ok().body(format!("Conversation deleted at id {}!", id)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => HttpResponse::NotFound().body(format!("Conversation with id {} not found", id)),
         Err(e) => {
-            println!("Failed to get latest message: {}", e);
-            return HttpResponse::InternalServerError().body("Error while getting latest message, check logs for more information");
+            println!("Failed to delete conversation at id {}: {}", id, e);
+            HttpResponse::InternalServerError().body(format!("Error while deleting conversation at id {}, check logs for more information", id))
         }
-    };
-    match prompt(&prompt_msg) {
-        Ok(v) => HttpResponse::Ok().body(v),
+    }
+}
+
+#[get("/api/conversations/{id}/branches")]
+async fn conversations_branches(id: web::Path<i32>) -> HttpResponse {
+    match Database::get_branches(*id) {
+        Ok(v) => {
+            let branches_json = serde_json::to_string(&v).unwrap_or(String::from("Error serializing branches as JSON"));
+            HttpResponse::Ok().body(branches_json)
+        },
+        Err(rusqlite::Error::QueryReturnedNoRows) => HttpResponse::NotFound().body(format!("Conversation with id {} not found", id)),
         Err(e) => {
-            println!("Failed to re-generate prompt: {}", e);
-            HttpResponse::InternalServerError().body("Error while generating prompt, check logs for more information")
+            println!("Failed to get branches of conversation at id {}: {}", id, e);
+            HttpResponse::InternalServerError().body(format!("Error while getting branches of conversation at id {}, check logs for more information", id))
         }
     }
 }
 
-//              Config
+#[put("/api/conversations/{id}/branches/{message_id}/active")]
+async fn conversations_branch_select(path: web::Path<(i32, i32)>) -> HttpResponse {
+    let (id, branch_message_id) = path.into_inner();
+    match Database::set_active_branch(id, branch_message_id) {
+        Ok(head) => HttpResponse::Ok().body(format!("Branch ending at message id {} is now active!", head)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => HttpResponse::NotFound().body(format!("Message with id {} not found in conversation {}", branch_message_id, id)),
+        Err(e) => {
+            println!("Failed to change active branch of conversation at id {}: {}", id, e);
+            HttpResponse::InternalServerError().body(format!("Error while changing active branch of conversation at id {}, check logs for more information", id))
+        }
+    }
+}
 
-#[get("/api/config")]
-async fn config() -> HttpResponse {
-    let config = match Database::get_config() {
+//              User
+
+#[get("/api/user")]
+async fn user() -> HttpResponse {
+    let user_data: UserView = match Database::get_user_data() {
         Ok(v) => v,
         Err(e) => {
-            println!("Failed to get config: {}", e);
-            return HttpResponse::InternalServerError().body("Error while getting config, check logs for more information");
+            println!("Failed to get user data: {}", e);
+            return HttpResponse::InternalServerError().finish();
         }
     };
-    let config_json = serde_json::to_string(&config).unwrap_or(String::from("Error serializing config as JSON"));
-    HttpResponse::Ok().body(config_json)
+    let user_json: String = serde_json::to_string(&user_data).unwrap_or(String::from("Error serializing user data as JSON"));
+    HttpResponse::Ok().body(user_json)
 }
 
-#[put("/api/config")]
-async fn config_post(received: web::Json<ConfigModify>) -> HttpResponse {
-    match Database::change_config(received.into_inner()) {
-        Ok(_) => HttpResponse::Ok().body("Config updated!"),
+#[put("/api/user")]
+async fn user_put(received: web::Json<UserView>) -> HttpResponse {
+    match Database::edit_user(received.into_inner()) {
+        Ok(_) => HttpResponse::Ok().body("User data edited!"),
         Err(e) => {
-            println!("Failed to update config: {}", e);
-            HttpResponse::InternalServerError().body("Error while updating config, check logs for more information")
+            println!("Failed to edit user data: {}", e);
+            HttpResponse::InternalServerError().body("Error while editing user data, check logs for more information")
         }
     }
 }
 
-//
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
 
-    let port: u16 = 3000;
-    let hostname: &str = "0.0.0.0";
+//              Memory
 
-    match Database::new() {
-        Ok(_) => { }
-        Err(e) => eprintln!("⚠️ Failed to connect to sqlite database: {}\n", e),
+#[derive(Deserialize)]
+struct LongTermMemMessage {
+    entry: String
+}
+
+#[derive(Deserialize)]
+struct CompanionQuery {
+    companion_id: Option<i32>,
+}
+
+// companion selected with "companion_id" query parameter, without it the active companion is used
+fn resolve_companion_id(companion_id: Option<i32>) -> Result<i32, HttpResponse> {
+    let companion_id = match companion_id {
+        Some(id) => Database::get_companion(id).map(|c| c.id),
+        None => Database::get_active_companion_id(),
+    };
+    match companion_id {
+        Ok(id) => Ok(id),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Err(HttpResponse::NotFound().body("Companion not found")),
+        Err(e) => {
+            println!("Failed to get companion: {}", e);
+            Err(HttpResponse::InternalServerError().body("Error while getting companion, check logs for more information"))
+        }
     }
+}
 
+fn connect_long_term() -> Result<LongTermMem, HttpResponse> {
     match LongTermMem::connect() {
-        Ok(_) => { }
-        Err(e) => eprintln!("⚠️ Failed to connect to tantivy: {}\n", e),
+        Ok(v) => Ok(v),
+        Err(e) => {
+            println!("Failed to connect to long term memory: {}", e);
+            Err(HttpResponse::InternalServerError().body("Error while connecting to long term memory, check logs for more information"))
+        }
     }
+}
 
-    match DialogueTuning::create() {
-        Ok(_) => { }
-        Err(e) => eprintln!("⚠️ Failed to create dialogue tuning table in sqlite database: {}\n", e),
-    }
+#[post("/api/memory/longTerm")]
+async fn add_memory_long_term_message(received: web::Json<LongTermMemMessage>, query_params: web::Query<CompanionQuery>) -> HttpResponse {
+    let companion_id = match resolve_companion_id(query_params.companion_id) {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    let ltm = match connect_long_term() {
+        Ok(v) => v,
+        Err(response) => return response,
+    };
+    match ltm.add_entry(&received.into_inner().entry, MemorySource::Manual, companion_id) {
+        Ok(_) => HttpResponse::Ok().body("Long term memory entry added!"),
+        Err(e) => {
+            println!("Failed to add long term memory entry: {}", e);
+            HttpResponse::InternalServerError().body("Error while adding long term memory entry, check logs for more information")
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct MemoryEntryList {
+    total: usize,
+    entries: Vec<MemoryEntry>,
+}
+
+#[derive(Deserialize)]
+struct MemoryListQuery {
+    start_index: Option<usize>,
+    limit: Option<usize>,
+    companion_id: Option<i32>,
+}
+
+#[derive(Deserialize)]
+struct MemorySearchQuery {
+    query: String,
+    limit: Option<usize>,
+    companion_id: Option<i32>,
+}
+
+#[get("/api/memory/longTerm")]
+async fn long_term_entries(query_params: web::Query<MemoryListQuery>) -> HttpResponse {
+    let start_index = query_params.start_index.unwrap_or(0);
+    let limit = query_params.limit.unwrap_or(15).min(50);
+    let companion_id = match resolve_companion_id(query_params.companion_id) {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    let ltm = match connect_long_term() {
+        Ok(v) => v,
+        Err(response) => return response,
+    };
+    match ltm.list_entries(companion_id, start_index, limit) {
+        Ok((total, entries)) => HttpResponse::Ok().json(MemoryEntryList { total, entries }),
+        Err(e) => {
+            println!("Failed to get long term memory entries: {}", e);
+            HttpResponse::InternalServerError().body("Error while getting long term memory entries, check logs for more information")
+        }
+    }
+}
+
+// same matching as used for prompts, most relevant entries first
+// runs in inference queue, the query is embedded by the same model that generates
+#[get("/api/memory/longTerm/search")]
+async fn search_long_term(query_params: web::Query<MemorySearchQuery>, model_manager: web::Data<ModelManager>, queue: web::Data<InferenceQueue>) -> HttpResponse {
+    if query_params.query.trim().is_empty() {
+        return HttpResponse::BadRequest().body("Search query can't be empty");
+    }
+    let limit = query_params.limit.unwrap_or(5).min(50);
+    let companion_id = match resolve_companion_id(query_params.companion_id) {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    let query = query_params.into_inner().query;
+    let model_manager = model_manager.into_inner();
+    let ticket = match queue.submit("search", move |_: CancelFlag| search_memory(&query, companion_id, limit, &model_manager)) {
+        Ok(t) => t,
+        Err(_) => return queue_full(),
+    };
+    match ticket.result.await {
+        Ok(Ok(v)) => HttpResponse::Ok().json(v),
+        Ok(Err(e)) => {
+            println!("Failed to search long term memory: {}", e);
+            HttpResponse::InternalServerError().body("Error while searching long term memory, check logs for more information")
+        }
+        Err(e) => {
+            println!("Failed to search long term memory: {}", e);
+            HttpResponse::InternalServerError().body("Error while searching long term memory, check logs for more information")
+        }
+    }
+}
+
+#[get("/api/memory/longTerm/export")]
+async fn export_long_term(query_params: web::Query<CompanionQuery>) -> HttpResponse {
+    let companion_id = match resolve_companion_id(query_params.companion_id) {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    let ltm = match connect_long_term() {
+        Ok(v) => v,
+        Err(response) => return response,
+    };
+    match ltm.export_entries(companion_id) {
+        Ok(v) => HttpResponse::Ok()
+            .insert_header(("Content-Disposition", format!("attachment; filename=\"longterm_memory_{}.json\"", companion_id)))
+            .json(v),
+        Err(e) => {
+            println!("Failed to export long term memory: {}", e);
+            HttpResponse::InternalServerError().body("Error while exporting long term memory, check logs for more information")
+        }
+    }
+}
+
+#[post("/api/memory/longTerm/import")]
+async fn import_long_term(received: web::Json<Vec<ImportedEntry>>, query_params: web::Query<CompanionQuery>) -> HttpResponse {
+    let companion_id = match resolve_companion_id(query_params.companion_id) {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    let ltm = match connect_long_term() {
+        Ok(v) => v,
+        Err(response) => return response,
+    };
+    match ltm.import_entries(companion_id, received.into_inner()) {
+        Ok(count) => HttpResponse::Ok().body(format!("Imported {} long term memory entries!", count)),
+        Err(e) => {
+            println!("Failed to import long term memory: {}", e);
+            HttpResponse::InternalServerError().body("Error while importing long term memory, check logs for more information")
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct DocumentQuery {
+    companion_id: Option<i32>,
+    name: Option<String>,
+}
+
+#[derive(Serialize)]
+struct IngestedDocument {
+    name: String,
+    chunks: usize,
+}
+
+fn is_markdown(name: &str, content_type: &str) -> bool {
+    let name = name.to_lowercase();
+    content_type.starts_with("text/markdown") || name.ends_with(".md") || name.ends_with(".markdown")
+}
+
+// plain text or markdown body named with "name" query parameter, or multipart/form-data
+// where every file is a separate document named after the file
+#[post("/api/memory/longTerm/documents")]
+async fn add_long_term_documents(request: HttpRequest, received: web::Payload, query_params: web::Query<DocumentQuery>) -> HttpResponse {
+    let companion_id = match resolve_companion_id(query_params.companion_id) {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    let content_type = request.headers().get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).unwrap_or("").to_lowercase();
+    // (name, text, markdown)
+    let mut documents: Vec<(String, String, bool)> = Vec::new();
+    if content_type.starts_with("multipart/form-data") {
+        let mut multipart = Multipart::new(request.headers(), received);
+        while let Some(field) = multipart.next().await {
+            let mut field = match field {
+                Ok(f) => f,
+                Err(e) => return HttpResponse::BadRequest().body(format!("Error while receiving documents: {}", e)),
+            };
+            // form fields that are not files are ignored
+            let name = match field.content_disposition().and_then(|cd| cd.get_filename()) {
+                Some(n) => n.to_string(),
+                None => continue,
+            };
+            let field_type = field.content_type().map(|m| m.essence_str().to_string()).unwrap_or_default();
+            let mut data = web::BytesMut::new();
+            while let Some(chunk) = field.next().await {
+                match chunk {
+                    Ok(c) => data.extend_from_slice(&c),
+                    Err(e) => return HttpResponse::BadRequest().body(format!("Error while receiving documents: {}", e)),
+                }
+            }
+            let text = match String::from_utf8(data.to_vec()) {
+                Ok(t) => t,
+                Err(_) => return HttpResponse::BadRequest().body(format!("{} is not a UTF-8 text file", name)),
+            };
+            let markdown = is_markdown(&name, &field_type);
+            documents.push((name, text, markdown));
+        }
+    } else {
+        let name = match &query_params.name {
+            Some(n) if !n.trim().is_empty() => n.trim().to_string(),
+            _ => return HttpResponse::BadRequest().body("Document name is required in \"name\" query parameter"),
+        };
+        let data = match read_payload(received).await {
+            Ok(d) => d,
+            Err(e) => {
+                eprintln!("Error while receiving document: {}", e);
+                return HttpResponse::BadRequest().body("Error while receiving document");
+            }
+        };
+        let text = match String::from_utf8(data.to_vec()) {
+            Ok(t) => t,
+            Err(_) => return HttpResponse::BadRequest().body("Document is not UTF-8 text"),
+        };
+        let markdown = is_markdown(&name, &content_type);
+        documents.push((name, text, markdown));
+    }
+    if documents.is_empty() {
+        return HttpResponse::BadRequest().body("No documents were sent");
+    }
+    // every document is split and checked before anything is saved
+    let mut split: Vec<(String, Vec<String>)> = Vec::new();
+    for (name, text, markdown) in documents {
+        let chunks = split_document(&text, markdown);
+        if chunks.is_empty() {
+            return HttpResponse::BadRequest().body(format!("{} is empty", name));
+        }
+        split.push((name, chunks));
+    }
+    let ingested: Vec<IngestedDocument> = split.iter().map(|(name, chunks)| IngestedDocument { name: name.clone(), chunks: chunks.len() }).collect();
+    let ltm = match connect_long_term() {
+        Ok(v) => v,
+        Err(response) => return response,
+    };
+    match ltm.add_documents(companion_id, split) {
+        Ok(_) => HttpResponse::Ok().json(ingested),
+        Err(e) => {
+            println!("Failed to add documents to long term memory: {}", e);
+            HttpResponse::InternalServerError().body("Error while adding documents to long term memory, check logs for more information")
+        }
+    }
+}
+
+#[get("/api/memory/longTerm/documents")]
+async fn long_term_documents(query_params: web::Query<CompanionQuery>) -> HttpResponse {
+    let companion_id = match resolve_companion_id(query_params.companion_id) {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    let ltm = match connect_long_term() {
+        Ok(v) => v,
+        Err(response) => return response,
+    };
+    match ltm.list_documents(companion_id) {
+        Ok(v) => HttpResponse::Ok().json(v),
+        Err(e) => {
+            println!("Failed to get documents in long term memory: {}", e);
+            HttpResponse::InternalServerError().body("Error while getting documents in long term memory, check logs for more information")
+        }
+    }
+}
+
+#[delete("/api/memory/longTerm/documents/{name}")]
+async fn delete_long_term_document(name: web::Path<String>, query_params: web::Query<CompanionQuery>) -> HttpResponse {
+    let companion_id = match resolve_companion_id(query_params.companion_id) {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    let ltm = match connect_long_term() {
+        Ok(v) => v,
+        Err(response) => return response,
+    };
+    match ltm.delete_document(companion_id, &name) {
+        Ok(true) => HttpResponse::Ok().body("Document removed from long term memory!"),
+        Ok(false) => HttpResponse::NotFound().body("Document not found"),
+        Err(e) => {
+            println!("Failed to remove document from long term memory: {}", e);
+            HttpResponse::InternalServerError().body("Error while removing document from long term memory, check logs for more information")
+        }
+    }
+}
+
+// embeddings of entries that don't have one from the current model are made in inference queue,
+// the job can be cancelled like a generation, entries done until then keep their embeddings
+#[post("/api/memory/longTerm/embeddings/backfill")]
+async fn backfill_long_term_embeddings(query_params: web::Query<CompanionQuery>, wait: web::Query<WaitQuery>, model_manager: web::Data<ModelManager>, queue: web::Data<InferenceQueue>) -> HttpResponse {
+    let companion_id = match resolve_companion_id(query_params.companion_id) {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    let model_manager = model_manager.into_inner();
+    if !wait.wait.unwrap_or(true) {
+        return match queue.into_inner().submit_detached("embeddings", move |cancel: CancelFlag| {
+            backfill_embeddings(companion_id, &model_manager, &cancel).map_err(|e| job_error("embeddings", e))
+        }) {
+            Ok(id) => submitted(id),
+            Err(_) => queue_full(),
+        };
+    }
+    let ticket = match queue.submit("embeddings", move |cancel: CancelFlag| backfill_embeddings(companion_id, &model_manager, &cancel)) {
+        Ok(t) => t,
+        Err(_) => return queue_full(),
+    };
+    match ticket.result.await {
+        Ok(Ok(v)) => HttpResponse::Ok().insert_header(("X-Generation-Id", ticket.id.to_string())).json(v),
+        Ok(Err(e)) => {
+            println!("Failed to make embeddings of long term memory: {}", e);
+            HttpResponse::InternalServerError().body("Error while making embeddings of long term memory, check logs for more information")
+        }
+        Err(e) => {
+            println!("Failed to make embeddings of long term memory: {}", e);
+            HttpResponse::InternalServerError().body("Error while making embeddings of long term memory, check logs for more information")
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ConsolidateQuery {
+    companion_id: Option<i32>,
+    older_than_days: Option<u32>,
+    batch_size: Option<usize>,
+    prune: Option<bool>,
+}
+
+// older chat entries are summarized into facts in inference queue, cancelling keeps batches that are done
+#[post("/api/memory/longTerm/consolidate")]
+async fn consolidate_long_term(query_params: web::Query<ConsolidateQuery>, wait: web::Query<WaitQuery>, model_manager: web::Data<ModelManager>, queue: web::Data<InferenceQueue>) -> HttpResponse {
+    let query_params = query_params.into_inner();
+    let companion_id = match resolve_companion_id(query_params.companion_id) {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    let options = ConsolidationOptions {
+        older_than_days: query_params.older_than_days,
+        batch_size: query_params.batch_size,
+        prune: query_params.prune,
+    };
+    let model_manager = model_manager.into_inner();
+    if !wait.wait.unwrap_or(true) {
+        return match queue.into_inner().submit_detached("consolidate", move |cancel: CancelFlag| {
+            consolidate_memory(companion_id, options, &model_manager, &cancel).map_err(|e| job_error("consolidate", e))
+        }) {
+            Ok(id) => submitted(id),
+            Err(_) => queue_full(),
+        };
+    }
+    let ticket = match queue.submit("consolidate", move |cancel: CancelFlag| consolidate_memory(companion_id, options, &model_manager, &cancel)) {
+        Ok(t) => t,
+        Err(_) => return queue_full(),
+    };
+    match ticket.result.await {
+        Ok(Ok(v)) => HttpResponse::Ok().insert_header(("X-Generation-Id", ticket.id.to_string())).json(v),
+        Ok(Err(e)) => {
+            println!("Failed to consolidate long term memory: {}", e);
+            HttpResponse::InternalServerError().body("Error while consolidating long term memory, check logs for more information")
+        }
+        Err(e) => {
+            println!("Failed to consolidate long term memory: {}", e);
+            HttpResponse::InternalServerError().body("Error while consolidating long term memory, check logs for more information")
+        }
+    }
+}
+
+#[put("/api/memory/longTerm/{id}")]
+async fn update_long_term_entry(id: web::Path<String>, received: web::Json<LongTermMemMessage>) -> HttpResponse {
+    let ltm = match connect_long_term() {
+        Ok(v) => v,
+        Err(response) => return response,
+    };
+    match ltm.update_entry(&id.into_inner(), &received.into_inner().entry) {
+        Ok(true) => HttpResponse::Ok().body("Long term memory entry updated!"),
+        Ok(false) => HttpResponse::NotFound().body("Long term memory entry not found"),
+        Err(e) => {
+            println!("Failed to update long term memory entry: {}", e);
+            HttpResponse::InternalServerError().body("Error while updating long term memory entry, check logs for more information")
+        }
+    }
+}
+
+#[delete("/api/memory/longTerm/{id}")]
+async fn delete_long_term_entry(id: web::Path<String>) -> HttpResponse {
+    let ltm = match connect_long_term() {
+        Ok(v) => v,
+        Err(response) => return response,
+    };
+    match ltm.delete_entry(&id.into_inner()) {
+        Ok(true) => HttpResponse::Ok().body("Long term memory entry deleted!"),
+        Ok(false) => HttpResponse::NotFound().body("Long term memory entry not found"),
+        Err(e) => {
+            println!("Failed to delete long term memory entry: {}", e);
+            HttpResponse::InternalServerError().body("Error while deleting long term memory entry, check logs for more information")
+        }
+    }
+}
+
+#[delete("/api/memory/longTerm")]
+async fn erase_long_term(query_params: web::Query<CompanionQuery>) -> HttpResponse {
+    let companion_id = match resolve_companion_id(query_params.companion_id) {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    let ltm = match connect_long_term() {
+        Ok(v) => v,
+        Err(response) => return response,
+    };
+    match ltm.erase_memory(companion_id) {
+        Ok(_) => HttpResponse::Ok().body("Long term memory cleared!"),
+        Err(e) => {
+            println!("Failed to clear long term memory: {}", e);
+            HttpResponse::InternalServerError().body("Error while clearing long term memory, check logs for more information")
+        }
+    }
+}
+
+#[post("/api/memory/dialogueTuning")]
+async fn add_tuning_message(query_params: web::Query<ConversationQuery>) -> HttpResponse {
+    let conversation = match resolve_conversation(query_params.conversation_id) {
+        Ok(c) => c,
+        Err(response) => return response,
+    };
+    let messages = match Database::get_x_messages(conversation.id, 2, 0) {
+        Ok(v) => v,
+        Err(e) => {
+            println!("Failed to get last 2 messages from database: {}", e);
+            return HttpResponse::InternalServerError().body("Error while getting last 2 messages from database, check logs for more information");
+        }
+    };
+    let (user_message, ai_message) = match messages.as_slice() {
+        [user_message, ai_message] if !user_message.ai && ai_message.ai => (user_message, ai_message),
+        _ => return HttpResponse::BadRequest().body("Conversation has to end with a user message and a response of the companion"),
+    };
+    match DialogueTuning::insert(&user_message.content, &ai_message.content) {
+        Ok(_) => HttpResponse::Ok().body("Saved previous dialogue as template dialogue"),
+        Err(e) => {
+            println!("Failed to save previous dialogue as template dialogue: {}", e);
+            HttpResponse::InternalServerError().body("Error while saving previous dialogue as template dialogue, check logs for more information")
+        }
+    }
+}
+
+#[delete("/api/memory/dialogueTuning")]
+async fn erase_tuning_message() -> HttpResponse {
+    match DialogueTuning::clear_dialogues() {
+        Ok(_) => HttpResponse::Ok().body("Dialogue tuning memory cleared!"),
+        Err(e) => {
+            println!("Failed to clear dialogue tuning: {}", e);
+            HttpResponse::InternalServerError().body("Error while clearing dialogue tuning, check logs for more information")
+        }
+    }
+}
+
+#[get("/api/facts")]
+async fn facts_get(query_params: web::Query<CompanionQuery>) -> HttpResponse {
+    let companion_id = match resolve_companion_id(query_params.companion_id) {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    match Facts::get_facts(companion_id, 0.0) {
+        Ok(v) => HttpResponse::Ok().json(v),
+        Err(e) => {
+            println!("Failed to get facts: {}", e);
+            HttpResponse::InternalServerError().body("Error while getting facts, check logs for more information")
+        }
+    }
+}
+
+#[post("/api/facts")]
+async fn facts_post(received: web::Json<NewFact>, query_params: web::Query<CompanionQuery>) -> HttpResponse {
+    let companion_id = match resolve_companion_id(query_params.companion_id) {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    let fact = received.into_inner();
+    match Facts::insert(companion_id, &fact.content, fact.confidence.unwrap_or(1.0), None) {
+        Ok(id) => HttpResponse::Ok().json(CreatedId { id }),
+        Err(rusqlite::Error::InvalidParameterName(e)) => HttpResponse::BadRequest().body(e),
+        Err(e) => {
+            println!("Failed to add fact: {}", e);
+            HttpResponse::InternalServerError().body("Error while adding fact, check logs for more information")
+        }
+    }
+}
+
+#[put("/api/facts/{id}")]
+async fn facts_put(id: web::Path<i32>, received: web::Json<FactModify>) -> HttpResponse {
+    match Facts::edit(*id, received.into_inner()) {
+        Ok(_) => HttpResponse::Ok().body(format!("Fact edited at id {}!", id)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => HttpResponse::NotFound().body(format!("Fact with id {} not found", id)),
+        Err(rusqlite::Error::InvalidParameterName(e)) => HttpResponse::BadRequest().body(e),
+        Err(e) => {
+            println!("Failed to edit fact at id {}: {}", id, e);
+            HttpResponse::InternalServerError().body(format!("Error while editing fact at id {}, check logs for more information", id))
+        }
+    }
+}
+
+#[delete("/api/facts/{id}")]
+async fn facts_delete(id: web::Path<i32>) -> HttpResponse {
+    match Facts::delete(*id) {
+        Ok(_) => HttpResponse::Ok().body(format!("Fact deleted at id {}!", id)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => HttpResponse::NotFound().body(format!("Fact with id {} not found", id)),
+        Err(e) => {
+            println!("Failed to delete fact at id {}: {}", id, e);
+            HttpResponse::InternalServerError().body(format!("Error while deleting fact at id {}, check logs for more information", id))
+        }
+    }
+}
+
+#[delete("/api/facts")]
+async fn erase_facts(query_params: web::Query<CompanionQuery>) -> HttpResponse {
+    let companion_id = match resolve_companion_id(query_params.companion_id) {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    match Facts::delete_companion(companion_id) {
+        Ok(_) => HttpResponse::Ok().body("Facts cleared!"),
+        Err(e) => {
+            println!("Failed to clear facts: {}", e);
+            HttpResponse::InternalServerError().body("Error while clearing facts, check logs for more information")
+        }
+    }
+}
+
+
+//              Prompting
+
+#[derive(Deserialize)]
+struct Prompt {
+    prompt: String,
+    // save text generated before the generation was cancelled, true by default
+    keep_partial: Option<bool>,
+    // message to reply to, starts a new branch if it already has replies. end of the active branch by default
+    parent_id: Option<i32>
+}
+
+// saves user message, called from the inference queue so the message is saved only if generation was queued
+fn add_user_message(conversation_id: i32, parent_id: Option<i32>, text: &str) -> Result<(), std::io::Error> {
+    let user_message = NewMessage { ai: false, content: text.to_string() };
+    let inserted = match parent_id {
+        Some(_) => Database::insert_message_after(conversation_id, parent_id, user_message),
+        None => Database::insert_message(conversation_id, user_message),
+    };
+    match inserted {
+        Ok(_) => Ok(()),
+        Err(rusqlite::Error::InvalidParameterName(e)) => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, e)),
+        Err(e) => {
+            eprintln!("Failed to add message to database: {}", e);
+            Err(std::io::Error::other("Error while adding message to database"))
+        }
+    }
+}
+
+// latest ai message gets a new alternative, its earlier responses are kept.
+// if the latest message is from the user, it gets a new response. returns the user message to respond to
+fn prepare_regenerate(conversation_id: i32) -> Result<(String, ReplyTarget), std::io::Error> {
+    let latest = match Database::get_latest_message(conversation_id) {
+        Ok(v) => v,
+        Err(e) => {
+            println!("Failed to get latest message: {}", e);
+            return Err(std::io::Error::other("Error while getting latest message"));
+        }
+    };
+    if !latest.ai {
+        return Ok((latest.content, ReplyTarget::NewMessage));
+    }
+    match Database::get_parent_message(latest.id) {
+        Ok(v) => Ok((v.content, ReplyTarget::Alternative(latest.id))),
+        Err(e) => {
+            println!("Failed to get message before latest message: {}", e);
+            Err(std::io::Error::other("Error while getting message before latest message"))
+        }
+    }
+}
+
+// latest ai message is extended with new text. returns the user message it responds to
+fn prepare_continue(conversation_id: i32) -> Result<(String, ReplyTarget), std::io::Error> {
+    let latest = match Database::get_latest_message(conversation_id) {
+        Ok(v) => v,
+        Err(e) => {
+            println!("Failed to get latest message: {}", e);
+            return Err(std::io::Error::other("Error while getting latest message"));
+        }
+    };
+    if !latest.ai {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Latest message is not from the companion, there is nothing to continue"));
+    }
+    let prompt_msg = Database::get_parent_message(latest.id).map(|m| m.content).unwrap_or_default();
+    Ok((prompt_msg, ReplyTarget::Continue(latest.id)))
+}
+
+// message suggested for the user is a response to the latest message, it's not saved
+fn prepare_impersonate(conversation_id: i32) -> Result<(String, ReplyTarget), std::io::Error> {
+    let latest = Database::get_latest_message(conversation_id).map(|m| m.content).unwrap_or_default();
+    Ok((latest, ReplyTarget::Impersonate))
+}
+
+// facts are extracted in a background job after the reply is sent, so the reply doesn't wait for it
+// and it runs after generations users wait for. when too many are waiting the reply is only not learned from
+fn queue_fact_extraction(reply_id: i32, model_manager: Arc<ModelManager>, queue: &InferenceQueue) {
+    let submitted = queue.submit_background("facts", move |cancel: CancelFlag| {
+        if let Err(e) = extract_facts(reply_id, &model_manager, &cancel) {
+            println!("Failed to extract facts from message {}: {}", reply_id, e);
+        }
+    });
+    if submitted.is_err() {
+        println!("Too many background jobs are waiting, facts are not extracted from message {}", reply_id);
+    }
+}
+
+fn queue_full() -> HttpResponse {
+    HttpResponse::ServiceUnavailable()
+        .insert_header(("Retry-After", "10"))
+        .body("Too many generations are waiting in queue, try again later")
+}
+
+#[derive(Deserialize)]
+struct WaitQuery {
+    // false responds right away with id of the generation, its result is read from /api/queue/{id}
+    wait: Option<bool>,
+}
+
+#[derive(Serialize)]
+struct QueuedId {
+    id: u64
+}
+
+fn submitted(id: u64) -> HttpResponse {
+    HttpResponse::Accepted().insert_header(("X-Generation-Id", id.to_string())).json(QueuedId { id })
+}
+
+// error kept for a job nobody waits for, like the body of a failed request
+fn job_error(kind: &str, e: std::io::Error) -> String {
+    if e.kind() == std::io::ErrorKind::InvalidInput {
+        return e.to_string();
+    }
+    println!("Failed to run job ({}): {}", kind, e);
+    String::from("Error while running job, check logs for more information")
+}
+
+// queues generation and waits for it, prepare runs in queue before generation and returns the prompt
+// and where the response is saved. id of the generation is sent in X-Generation-Id header,
+// without waiting only the id is sent, so the generation can be cancelled while it's queued or running
+async fn queued_prompt(kind: &'static str, prepare: impl FnOnce() -> Result<(String, ReplyTarget), std::io::Error> + Send + 'static, conversation_id: i32, keep_partial: bool, wait: bool, model_manager: Arc<ModelManager>, queue: Arc<InferenceQueue>) -> HttpResponse {
+    let job_model_manager = model_manager.clone();
+    let job = move |cancel: CancelFlag| {
+        let (prompt_text, target) = prepare()?;
+        prompt(&prompt_text, conversation_id, target, &job_model_manager, &cancel, keep_partial)
+    };
+    if !wait {
+        let job_queue = queue.clone();
+        return match queue.submit_detached(kind, move |cancel: CancelFlag| {
+            let v = job(cancel).map_err(|e| job_error(kind, e))?;
+            if let Some(reply_id) = v.message_id {
+                queue_fact_extraction(reply_id, model_manager, &job_queue);
+            }
+            Ok(v)
+        }) {
+            Ok(id) => submitted(id),
+            Err(_) => queue_full(),
+        };
+    }
+    let ticket = match queue.submit(kind, job) {
+        Ok(t) => t,
+        Err(_) => return queue_full(),
+    };
+    match ticket.result.await {
+        Ok(Ok(v)) => {
+            if let Some(reply_id) = v.message_id {
+                queue_fact_extraction(reply_id, model_manager, &queue);
+            }
+            HttpResponse::Ok().insert_header(("X-Generation-Id", ticket.id.to_string())).body(v.content)
+        }
+        Ok(Err(e)) if e.kind() == std::io::ErrorKind::InvalidInput => HttpResponse::BadRequest().body(e.to_string()),
+        Ok(Err(e)) => {
+            println!("Failed to generate prompt ({}): {}", kind, e);
+            HttpResponse::InternalServerError().body("Error while generating prompt, check logs for more information")
+        }
+        Err(e) => {
+            println!("Failed to generate prompt ({}): {}", kind, e);
+            HttpResponse::InternalServerError().body("Error while generating prompt, check logs for more information")
+        }
+    }
+}
+
+#[post("/api/prompt")]
+async fn prompt_message(received: web::Json<Prompt>, query_params: web::Query<ConversationQuery>, wait: web::Query<WaitQuery>, model_manager: web::Data<ModelManager>, queue: web::Data<InferenceQueue>) -> HttpResponse {
+    let received = received.into_inner();
+    let prompt_text = received.prompt;
+    let keep_partial = received.keep_partial.unwrap_or(true);
+    let parent_id = received.parent_id;
+    let conversation = match resolve_conversation(query_params.conversation_id) {
+        Ok(c) => c,
+        Err(response) => return response,
+    };
+    let prepare = move || add_user_message(conversation.id, parent_id, &prompt_text).map(|_| (prompt_text, ReplyTarget::NewMessage));
+    queued_prompt("prompt", prepare, conversation.id, keep_partial, wait.wait.unwrap_or(true), model_manager.into_inner(), queue.into_inner()).await
+}
+
+#[derive(Deserialize)]
+struct PromptPreviewRequest {
+    prompt: Option<String>
+}
+
+// runs in inference queue, because the model may be loaded for it and the prompt is embedded for memory search
+#[post("/api/prompt/preview")]
+async fn prompt_preview(received: web::Json<PromptPreviewRequest>, query_params: web::Query<ConversationQuery>, model_manager: web::Data<ModelManager>, queue: web::Data<InferenceQueue>) -> HttpResponse {
+    let prompt_text = received.into_inner().prompt;
+    let conversation = match resolve_conversation(query_params.conversation_id) {
+        Ok(c) => c,
+        Err(response) => return response,
+    };
+    let model_manager = model_manager.into_inner();
+    let ticket = match queue.submit("preview", move |_: CancelFlag| preview_prompt(prompt_text.as_deref(), conversation.id, &model_manager)) {
+        Ok(t) => t,
+        Err(_) => return queue_full(),
+    };
+    match ticket.result.await {
+        Ok(Ok(preview)) => HttpResponse::Ok().json(preview),
+        Ok(Err(e)) => {
+            println!("Failed to build prompt preview: {}", e);
+            HttpResponse::InternalServerError().body("Error while building prompt preview, check logs for more information")
+        }
+        Err(e) => {
+            println!("Failed to build prompt preview: {}", e);
+            HttpResponse::InternalServerError().body("Error while building prompt preview, check logs for more information")
+        }
+    }
+}
+
+#[get("/api/prompt/regenerate")]
+async fn regenerate_prompt(query_params: web::Query<RegenerateQuery>, wait: web::Query<WaitQuery>, model_manager: web::Data<ModelManager>, queue: web::Data<InferenceQueue>) -> HttpResponse {
+    let keep_partial = query_params.keep_partial.unwrap_or(true);
+    let conversation = match resolve_conversation(query_params.conversation_id) {
+        Ok(c) => c,
+        Err(response) => return response,
+    };
+    queued_prompt("regenerate", move || prepare_regenerate(conversation.id), conversation.id, keep_partial, wait.wait.unwrap_or(true), model_manager.into_inner(), queue.into_inner()).await
+}
+
+#[get("/api/prompt/continue")]
+async fn continue_prompt(query_params: web::Query<RegenerateQuery>, wait: web::Query<WaitQuery>, model_manager: web::Data<ModelManager>, queue: web::Data<InferenceQueue>) -> HttpResponse {
+    let keep_partial = query_params.keep_partial.unwrap_or(true);
+    let conversation = match resolve_conversation(query_params.conversation_id) {
+        Ok(c) => c,
+        Err(response) => return response,
+    };
+    queued_prompt("continue", move || prepare_continue(conversation.id), conversation.id, keep_partial, wait.wait.unwrap_or(true), model_manager.into_inner(), queue.into_inner()).await
+}
+
+#[get("/api/prompt/impersonate")]
+async fn impersonate_prompt(query_params: web::Query<ConversationQuery>, wait: web::Query<WaitQuery>, model_manager: web::Data<ModelManager>, queue: web::Data<InferenceQueue>) -> HttpResponse {
+    let conversation = match resolve_conversation(query_params.conversation_id) {
+        Ok(c) => c,
+        Err(response) => return response,
+    };
+    queued_prompt("impersonate", move || prepare_impersonate(conversation.id), conversation.id, true, wait.wait.unwrap_or(true), model_manager.into_inner(), queue.into_inner()).await
+}
+
+// stops a queued or running generation, what was generated before it stopped is saved if keep_partial was set
+#[post("/api/prompt/{id}/cancel")]
+async fn cancel_prompt(id: web::Path<u64>, queue: web::Data<InferenceQueue>) -> HttpResponse {
+    let id = id.into_inner();
+    if queue.cancel(id) {
+        HttpResponse::Ok().body("Generation cancelled!")
+    } else {
+        HttpResponse::NotFound().body(format!("Generation {} is not queued or running", id))
+    }
+}
+
+// generation from companion, user and messages sent in the request, nothing is saved
+#[post("/api/generate")]
+async fn stateless_generate(received: web::Json<stateless::GenerateRequest>, wait: web::Query<WaitQuery>, model_manager: web::Data<ModelManager>, queue: web::Data<InferenceQueue>) -> HttpResponse {
+    let request = received.into_inner();
+    let model_manager = model_manager.into_inner();
+    if !wait.wait.unwrap_or(true) {
+        return match queue.into_inner().submit_detached("generate", move |cancel: CancelFlag| {
+            stateless::generate_stateless(request, &model_manager, &cancel, |_| {}).map_err(|e| job_error("generate", e))
+        }) {
+            Ok(id) => submitted(id),
+            Err(_) => queue_full(),
+        };
+    }
+    let ticket = match queue.submit("generate", move |cancel: CancelFlag| stateless::generate_stateless(request, &model_manager, &cancel, |_| {})) {
+        Ok(t) => t,
+        Err(_) => return queue_full(),
+    };
+    match ticket.result.await {
+        Ok(Ok(v)) => HttpResponse::Ok().insert_header(("X-Generation-Id", ticket.id.to_string())).json(v),
+        Ok(Err(e)) if e.kind() == std::io::ErrorKind::InvalidInput => HttpResponse::BadRequest().body(e.to_string()),
+        Ok(Err(e)) if e.kind() == std::io::ErrorKind::NotFound => HttpResponse::ServiceUnavailable().body("Model is not loaded yet, check model status"),
+        Ok(Err(e)) => {
+            println!("Failed to generate response: {}", e);
+            HttpResponse::InternalServerError().body("Error while generating response, check logs for more information")
+        }
+        Err(e) => {
+            println!("Failed to generate response: {}", e);
+            HttpResponse::InternalServerError().body("Error while generating response, check logs for more information")
+        }
+    }
+}
+
+//              Streaming (Server-Sent Events)
+
+#[derive(Serialize)]
+struct StreamToken<'a> {
+    text: &'a str
+}
+
+#[derive(Serialize)]
+struct StreamError<'a> {
+    error: &'a str
+}
+
+#[derive(Serialize)]
+struct StreamQueued {
+    id: u64,
+    position: usize
+}
+
+fn sse_event(event: &str, data: &str) -> web::Bytes {
+    web::Bytes::from(format!("event: {}\ndata: {}\n\n", event, data))
+}
+
+// queues generation and sends a "queued" event with its id and position in queue, then "token" events as text
+// is generated, followed by a "done" event with id of the saved message and inference stats, or an "error" event.
+// prepare runs in queue before generation and returns the prompt and where the response is saved.
+// generation is cancelled when the client disconnects
+fn stream_prompt(kind: &'static str, prepare: impl FnOnce() -> Result<(String, ReplyTarget), std::io::Error> + Send + 'static, conversation_id: i32, keep_partial: bool, model_manager: Arc<ModelManager>, queue: Arc<InferenceQueue>) -> HttpResponse {
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<web::Bytes>();
+    let queued_tx = tx.clone();
+    let notice = move |id: u64, position: usize| {
+        let queued_json = serde_json::to_string(&StreamQueued { id, position }).unwrap_or_default();
+        let _ = queued_tx.send(sse_event("queued", &queued_json));
+    };
+    let job_queue = queue.clone();
+    let submitted = queue.submit_with_notice(kind, notice, move |cancel: CancelFlag| {
+        if tx.is_closed() {
+            cancel.store(true, Ordering::Relaxed);
+        }
+        let result = prepare().and_then(|(prompt_text, target)| prompt_with_callback(&prompt_text, conversation_id, target, &model_manager, &cancel, keep_partial, |token| {
+            let token_json = serde_json::to_string(&StreamToken { text: token }).unwrap_or_default();
+            if tx.send(sse_event("token", &token_json)).is_err() {
+                cancel.store(true, Ordering::Relaxed);
+            }
+        }));
+        match result {
+            Ok(v) => {
+                let result_json = serde_json::to_string(&v).unwrap_or_default();
+                let _ = tx.send(sse_event("done", &result_json));
+                if let Some(reply_id) = v.message_id {
+                    queue_fact_extraction(reply_id, model_manager, &job_queue);
+                }
+            },
+            Err(e) => {
+                println!("Failed to generate prompt: {}", e);
+                let error_json = serde_json::to_string(&StreamError { error: "Error while generating prompt, check logs for more information" }).unwrap_or_default();
+                let _ = tx.send(sse_event("error", &error_json));
+            }
+        }
+    });
+    match submitted {
+        Ok(_) => sse_response(rx),
+        Err(_) => queue_full(),
+    }
+}
+
+#[post("/api/prompt/stream")]
+async fn prompt_message_stream(received: web::Json<Prompt>, query_params: web::Query<ConversationQuery>, model_manager: web::Data<ModelManager>, queue: web::Data<InferenceQueue>) -> HttpResponse {
+    let received = received.into_inner();
+    let prompt_text = received.prompt;
+    let keep_partial = received.keep_partial.unwrap_or(true);
+    let parent_id = received.parent_id;
+    let conversation = match resolve_conversation(query_params.conversation_id) {
+        Ok(c) => c,
+        Err(response) => return response,
+    };
+    let prepare = move || add_user_message(conversation.id, parent_id, &prompt_text).map(|_| (prompt_text, ReplyTarget::NewMessage));
+    stream_prompt("prompt", prepare, conversation.id, keep_partial, model_manager.into_inner(), queue.into_inner())
+}
+
+#[get("/api/prompt/regenerate/stream")]
+async fn regenerate_prompt_stream(query_params: web::Query<RegenerateQuery>, model_manager: web::Data<ModelManager>, queue: web::Data<InferenceQueue>) -> HttpResponse {
+    let keep_partial = query_params.keep_partial.unwrap_or(true);
+    let conversation = match resolve_conversation(query_params.conversation_id) {
+        Ok(c) => c,
+        Err(response) => return response,
+    };
+    stream_prompt("regenerate", move || prepare_regenerate(conversation.id), conversation.id, keep_partial, model_manager.into_inner(), queue.into_inner())
+}
+
+#[get("/api/prompt/continue/stream")]
+async fn continue_prompt_stream(query_params: web::Query<RegenerateQuery>, model_manager: web::Data<ModelManager>, queue: web::Data<InferenceQueue>) -> HttpResponse {
+    let keep_partial = query_params.keep_partial.unwrap_or(true);
+    let conversation = match resolve_conversation(query_params.conversation_id) {
+        Ok(c) => c,
+        Err(response) => return response,
+    };
+    stream_prompt("continue", move || prepare_continue(conversation.id), conversation.id, keep_partial, model_manager.into_inner(), queue.into_inner())
+}
+
+#[get("/api/prompt/impersonate/stream")]
+async fn impersonate_prompt_stream(query_params: web::Query<ConversationQuery>, model_manager: web::Data<ModelManager>, queue: web::Data<InferenceQueue>) -> HttpResponse {
+    let conversation = match resolve_conversation(query_params.conversation_id) {
+        Ok(c) => c,
+        Err(response) => return response,
+    };
+    stream_prompt("impersonate", move || prepare_impersonate(conversation.id), conversation.id, true, model_manager.into_inner(), queue.into_inner())
+}
+
+//              OpenAI compatible API
+
+fn openai_error(e: std::io::Error) -> HttpResponse {
+    println!("Failed to generate completion: {}", e);
+    HttpResponse::InternalServerError().json(ErrorResponse::new(&e.to_string(), "server_error"))
+}
+
+fn openai_queue_full() -> HttpResponse {
+    HttpResponse::ServiceUnavailable()
+        .insert_header(("Retry-After", "10"))
+        .json(ErrorResponse::new("Too many generations are waiting in queue, try again later", "server_error"))
+}
+
+fn sse_data(data: &str) -> web::Bytes {
+    web::Bytes::from(format!("data: {}\n\n", data))
+}
+
+fn sse_response(rx: tokio::sync::mpsc::UnboundedReceiver<web::Bytes>) -> HttpResponse {
+    let stream = futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|event| (Ok::<_, actix_web::Error>(event), rx))
+    });
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(stream)
+}
+
+#[get("/v1/models")]
+async fn openai_models() -> HttpResponse {
+    match openai::models() {
+        Ok(v) => HttpResponse::Ok().json(v),
+        Err(e) => openai_error(e),
+    }
+}
+
+#[post("/v1/chat/completions")]
+async fn openai_chat_completions(received: web::Json<ChatCompletionRequest>, model_manager: web::Data<ModelManager>, queue: web::Data<InferenceQueue>) -> HttpResponse {
+    let request = received.into_inner();
+    let id = openai::completion_id("chatcmpl");
+    let created = openai::created_timestamp();
+    if request.stream.unwrap_or(false) {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<web::Bytes>();
+        let model_manager = model_manager.into_inner();
+        let submitted = queue.submit("chat_completion", move |cancel: CancelFlag| {
+            let model = openai::current_model_id();
+            let chunk = |delta: openai::ChatDelta, finish_reason: Option<&'static str>, usage: Option<openai::Usage>| {
+                let chunk = openai::ChatCompletionChunk {
+                    id: id.clone(),
+                    object: "chat.completion.chunk",
+                    created,
+                    model: model.clone(),
+                    choices: vec![openai::ChatChunkChoice { index: 0, delta, finish_reason }],
+                    usage,
+                };
+                sse_data(&serde_json::to_string(&chunk).unwrap_or_default())
+            };
+            let _ = tx.send(chunk(openai::ChatDelta { role: Some("assistant"), content: None }, None, None));
+            let result = openai::chat_completion(request, &model_manager, &cancel, |token| {
+                if tx.send(chunk(openai::ChatDelta { role: None, content: Some(token.to_string()) }, None, None)).is_err() {
+                    cancel.store(true, Ordering::Relaxed);
+                }
+            });
+            match result {
+                Ok((generation, _)) => {
+                    let usage = openai::Usage::from_generation(&generation);
+                    let _ = tx.send(chunk(openai::ChatDelta { role: None, content: None }, Some(openai::finish_reason(&generation)), Some(usage)));
+                },
+                Err(e) => {
+                    println!("Failed to generate completion: {}", e);
+                    let _ = tx.send(sse_data(&serde_json::to_string(&ErrorResponse::new(&e.to_string(), "server_error")).unwrap_or_default()));
+                }
+            }
+            let _ = tx.send(sse_data("[DONE]"));
+        });
+        return match submitted {
+            Ok(_) => sse_response(rx),
+            Err(_) => openai_queue_full(),
+        };
+    }
+    let ticket = match queue.submit("chat_completion", move |cancel: CancelFlag| openai::chat_completion(request, &model_manager, &cancel, |_| {})) {
+        Ok(t) => t,
+        Err(_) => return openai_queue_full(),
+    };
+    match ticket.result.await {
+        Ok(Ok((generation, model))) => HttpResponse::Ok().json(openai::ChatCompletionResponse {
+            id,
+            object: "chat.completion",
+            created,
+            model,
+            choices: vec![openai::ChatChoice {
+                index: 0,
+                finish_reason: openai::finish_reason(&generation),
+                message: openai::ChatResponseMessage { role: "assistant", content: generation.text.clone() },
+            }],
+            usage: openai::Usage::from_generation(&generation),
+        }),
+        Ok(Err(e)) => openai_error(e),
+        Err(e) => openai_error(io_error(e)),
+    }
+}
+
+#[post("/v1/completions")]
+async fn openai_completions(received: web::Json<CompletionRequest>, model_manager: web::Data<ModelManager>, queue: web::Data<InferenceQueue>) -> HttpResponse {
+    let request = received.into_inner();
+    let id = openai::completion_id("cmpl");
+    let created = openai::created_timestamp();
+    if request.stream.unwrap_or(false) {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<web::Bytes>();
+        let model_manager = model_manager.into_inner();
+        let submitted = queue.submit("completion", move |cancel: CancelFlag| {
+            let model = openai::current_model_id();
+            let chunk = |text: String, finish_reason: Option<&'static str>, usage: Option<openai::Usage>| {
+                let chunk = openai::CompletionResponse {
+                    id: id.clone(),
+                    object: "text_completion",
+                    created,
+                    model: model.clone(),
+                    choices: vec![openai::CompletionChoice { text, index: 0, logprobs: None, finish_reason }],
+                    usage,
+                };
+                sse_data(&serde_json::to_string(&chunk).unwrap_or_default())
+            };
+            let result = openai::text_completion(request, &model_manager, &cancel, |token| {
+                if tx.send(chunk(token.to_string(), None, None)).is_err() {
+                    cancel.store(true, Ordering::Relaxed);
+                }
+            });
+            match result {
+                Ok((generation, _)) => {
+                    let usage = openai::Usage::from_generation(&generation);
+                    let _ = tx.send(chunk(String::new(), Some(openai::finish_reason(&generation)), Some(usage)));
+                },
+                Err(e) => {
+                    println!("Failed to generate completion: {}", e);
+                    let _ = tx.send(sse_data(&serde_json::to_string(&ErrorResponse::new(&e.to_string(), "server_error")).unwrap_or_default()));
+                }
+            }
+            let _ = tx.send(sse_data("[DONE]"));
+        });
+        return match submitted {
+            Ok(_) => sse_response(rx),
+            Err(_) => openai_queue_full(),
+        };
+    }
+    let ticket = match queue.submit("completion", move |cancel: CancelFlag| openai::text_completion(request, &model_manager, &cancel, |_| {})) {
+        Ok(t) => t,
+        Err(_) => return openai_queue_full(),
+    };
+    match ticket.result.await {
+        Ok(Ok((generation, model))) => HttpResponse::Ok().json(openai::CompletionResponse {
+            id,
+            object: "text_completion",
+            created,
+            model,
+            choices: vec![openai::CompletionChoice {
+                text: generation.text.clone(),
+                index: 0,
+                logprobs: None,
+                finish_reason: Some(openai::finish_reason(&generation)),
+            }],
+            usage: Some(openai::Usage::from_generation(&generation)),
+        }),
+        Ok(Err(e)) => openai_error(e),
+        Err(e) => openai_error(io_error(e)),
+    }
+}
+
+//              Config
+
+#[get("/api/config")]
+async fn config() -> HttpResponse {
+    let config = match Database::get_config() {
+        Ok(v) => v,
+        Err(e) => {
+            println!("Failed to get config: {}", e);
+            return HttpResponse::InternalServerError().body("Error while getting config, check logs for more information");
+        }
+    };
+    let config_json = serde_json::to_string(&config).unwrap_or(String::from("Error serializing config as JSON"));
+    HttpResponse::Ok().body(config_json)
+}
+
+#[put("/api/config")]
+async fn config_post(received: web::Json<ConfigModify>, model_manager: web::Data<ModelManager>) -> HttpResponse {
+    match Database::change_config(received.into_inner()) {
+        Ok(_) => {
+            // reloads the model only if path, device, gpu layers or context size changed
+            ModelManager::reload_in_background(model_manager.into_inner());
+            HttpResponse::Ok().body("Config updated!")
+        },
+        Err(rusqlite::Error::InvalidParameterName(e)) => HttpResponse::BadRequest().body(e),
+        Err(e) => {
+            println!("Failed to update config: {}", e);
+            HttpResponse::InternalServerError().body("Error while updating config, check logs for more information")
+        }
+    }
+}
+
+#[get("/api/config/model")]
+async fn model_status(model_manager: web::Data<ModelManager>) -> HttpResponse {
+    let status_json = serde_json::to_string(&model_manager.status()).unwrap_or(String::from("Error serializing model status as JSON"));
+    HttpResponse::Ok().body(status_json)
+}
+
+#[get("/api/queue")]
+async fn queue_status(queue: web::Data<InferenceQueue>) -> HttpResponse {
+    let status_json = serde_json::to_string(&queue.status()).unwrap_or(String::from("Error serializing queue status as JSON"));
+    HttpResponse::Ok().body(status_json)
+}
+
+// state of a generation, with its result when it was sent without waiting for it
+#[get("/api/queue/{id}")]
+async fn queue_job(id: web::Path<u64>, queue: web::Data<InferenceQueue>) -> HttpResponse {
+    match queue.job_status(*id) {
+        Some(v) => HttpResponse::Ok().json(v),
+        None => HttpResponse::NotFound().body(format!("Generation {} is not queued, running or finished recently", id)),
+    }
+}
+
+#[get("/api/config/model-info")]
+async fn model_info() -> HttpResponse {
+    let model_path = match Database::get_config() {
+        Ok(c) => c.llm_model_path,
+        Err(e) => {
+            println!("Failed to get config: {}", e);
+            return HttpResponse::InternalServerError().body("Error while getting config, check logs for more information");
+        }
+    };
+    let info = match web::block(move || gguf::cached_gguf_info(&model_path)).await {
+        Ok(Ok(v)) => v,
+        Ok(Err(e)) => {
+            println!("Failed to read gguf metadata: {}", e);
+            return HttpResponse::BadRequest().body(format!("Error while reading metadata of the model: {}", e));
+        }
+        Err(e) => {
+            println!("Failed to read gguf metadata: {}", e);
+            return HttpResponse::InternalServerError().body("Error while reading metadata of the model, check logs for more information");
+        }
+    };
+    let info_json = serde_json::to_string(&info).unwrap_or(String::from("Error serializing model info as JSON"));
+    HttpResponse::Ok().body(info_json)
+}
+
+//              Prompt templates
+
+#[get("/api/promptTemplates")]
+async fn prompt_templates() -> HttpResponse {
+    let templates: Vec<PromptTemplateData> = match Database::get_prompt_templates() {
+        Ok(v) => v,
+        Err(e) => {
+            println!("Failed to get prompt templates: {}", e);
+            return HttpResponse::InternalServerError().body("Error while getting prompt templates, check logs for more information");
+        }
+    };
+    let templates_json = serde_json::to_string(&templates).unwrap_or(String::from("Error serializing prompt templates as JSON"));
+    HttpResponse::Ok().body(templates_json)
+}
+
+#[post("/api/promptTemplates")]
+async fn prompt_templates_post(received: web::Json<PromptTemplateView>) -> HttpResponse {
+    match Database::insert_prompt_template(received.into_inner()) {
+        Ok(id) => HttpResponse::Ok().json(CreatedId { id }),
+        Err(rusqlite::Error::InvalidParameterName(e)) => HttpResponse::BadRequest().body(e),
+        Err(e) => {
+            println!("Failed to add prompt template: {}", e);
+            HttpResponse::InternalServerError().body("Error while adding prompt template, check logs for more information")
+        }
+    }
+}
+
+#[get("/api/promptTemplates/{id}")]
+async fn prompt_templates_id(id: web::Path<i32>) -> HttpResponse {
+    let template: PromptTemplateData = match Database::get_prompt_template(*id) {
+        Ok(v) => v,
+        Err(rusqlite::Error::QueryReturnedNoRows) => return HttpResponse::NotFound().body(format!("Prompt template with id {} not found", id)),
+        Err(e) => {
+            println!("Failed to get prompt template at id {}: {}", id, e);
+            return HttpResponse::InternalServerError().body(format!("Error while getting prompt template at id {}, check logs for more information", id));
+        }
+    };
+    let template_json = serde_json::to_string(&template).unwrap_or(String::from("Error serializing prompt template as JSON"));
+    HttpResponse::Ok().body(template_json)
+}
+
+#[put("/api/promptTemplates/{id}")]
+async fn prompt_templates_put(id: web::Path<i32>, received: web::Json<PromptTemplateView>) -> HttpResponse {
+    match Database::edit_prompt_template(*id, received.into_inner()) {
+        Ok(_) => HttpResponse::Ok().body(format!("Prompt template edited at id {}!", id)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => HttpResponse::NotFound().body(format!("Prompt template with id {} not found", id)),
+        Err(rusqlite::Error::InvalidParameterName(e)) => HttpResponse::BadRequest().body(e),
+        Err(e) => {
+            println!("Failed to edit prompt template at id {}: {}", id, e);
+            HttpResponse::InternalServerError().body(format!("Error while editing prompt template at id {}, check logs for more information", id))
+        }
+    }
+}
+
+#[delete("/api/promptTemplates/{id}")]
+async fn prompt_templates_delete(id: web::Path<i32>) -> HttpResponse {
+    match Database::delete_prompt_template(*id) {
+        Ok(_) => HttpResponse::Ok().body(format!("Prompt template deleted at id {}!", id)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => HttpResponse::NotFound().body(format!("Prompt template with id {} not found", id)),
+        Err(rusqlite::Error::InvalidParameterName(e)) => HttpResponse::BadRequest().body(e),
+        Err(e) => {
+            println!("Failed to delete prompt template at id {}: {}", id, e);
+            HttpResponse::InternalServerError().body(format!("Error while deleting prompt template at id {}, check logs for more information", id))
+        }
+    }
+}
+
+//
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+
+    let port: u16 = 3000;
+    let hostname: &str = "0.0.0.0";
+
+    match Database::new() {
+        Ok(_) => { }
+        Err(e) => eprintln!("⚠️ Failed to connect to sqlite database: {}\n", e),
+    }
+
+    match LongTermMem::connect() {
+        Ok(_) => { }
+        Err(e) => eprintln!("⚠️ Failed to connect to tantivy: {}\n", e),
+    }
+
+    match DialogueTuning::create() {
+        Ok(_) => { }
+        Err(e) => eprintln!("⚠️ Failed to create dialogue tuning table in sqlite database: {}\n", e),
+    }
+
+    match EmbeddingStore::create() {
+        Ok(_) => { }
+        Err(e) => eprintln!("⚠️ Failed to create memory embeddings table in sqlite database: {}\n", e),
+    }
+
+    match ConsolidatedMessages::create() {
+        Ok(_) => { }
+        Err(e) => eprintln!("⚠️ Failed to create consolidated messages table in sqlite database: {}\n", e),
+    }
+
+    match Facts::create() {
+        Ok(_) => { }
+        Err(e) => eprintln!("⚠️ Failed to create facts table in sqlite database: {}\n", e),
+    }
+
+    let model_manager = web::Data::new(ModelManager::new());
+    ModelManager::reload_in_background(model_manager.clone().into_inner());
+    let queue = web::Data::from(InferenceQueue::start(QUEUE_CAPACITY));
 
     println!("AI Companion v1 successfully launched! 🚀\n");
 
     println!("Listening on:\n  -> http://{}:{}/", hostname, port);
     println!("  -> http://localhost:{}/\n", port);
     println!("https://github.com/Hukasx0/ai-companion\n   By Hubert \"Hukasx0\" Kasperek\n");
-    HttpServer::new(|| {
+    HttpServer::new(move || {
         App::new()
+            .app_data(model_manager.clone())
+            .app_data(queue.clone())
             .service(index)
             .service(js)
             .service(js2)
@@ -507,6 +2031,10 @@ async fn main() -> std::io::Result<()> {
             .service(message_id)
             .service(message_put)
             .service(message_delete)
+            .service(message_alternatives)
+            .service(message_alternative_select)
+            .service(message_alternative_delete)
+            .service(message_fork)
             .service(message_post)
             .service(companion)
             .service(companion_edit_data)
@@ -514,16 +2042,70 @@ async fn main() -> std::io::Result<()> {
             .service(companion_character_json)
             .service(get_companion_character_json)
             .service(companion_avatar)
+            .service(companions)
+            .service(companions_post)
+            .service(companions_active)
+            .service(companions_id)
+            .service(companions_put)
+            .service(companions_delete)
+            .service(companions_set_active)
+            .service(companions_character_json)
+            .service(companions_avatar)
+            .service(companions_avatar_post)
+            .service(conversations)
+            .service(conversations_post)
+            .service(conversations_id)
+            .service(conversations_put)
+            .service(conversations_delete)
+            .service(conversations_branches)
+            .service(conversations_branch_select)
             .service(user)
             .service(user_put)
             .service(add_memory_long_term_message)
+            .service(long_term_entries)
+            .service(search_long_term)
+            .service(export_long_term)
+            .service(import_long_term)
+            .service(add_long_term_documents)
+            .service(long_term_documents)
+            .service(delete_long_term_document)
+            .service(backfill_long_term_embeddings)
+            .service(consolidate_long_term)
+            .service(update_long_term_entry)
+            .service(delete_long_term_entry)
             .service(erase_long_term)
             .service(add_tuning_message)
             .service(erase_tuning_message)
+            .service(facts_get)
+            .service(facts_post)
+            .service(facts_put)
+            .service(facts_delete)
+            .service(erase_facts)
             .service(prompt_message)
             .service(regenerate_prompt)
+            .service(continue_prompt)
+            .service(impersonate_prompt)
+            .service(prompt_preview)
+            .service(cancel_prompt)
+            .service(prompt_message_stream)
+            .service(regenerate_prompt_stream)
+            .service(continue_prompt_stream)
+            .service(stateless_generate)
+            .service(impersonate_prompt_stream)
             .service(config)
             .service(config_post)
+            .service(model_status)
+            .service(model_info)
+            .service(queue_status)
+            .service(queue_job)
+            .service(prompt_templates)
+            .service(prompt_templates_post)
+            .service(prompt_templates_id)
+            .service(prompt_templates_put)
+            .service(prompt_templates_delete)
+            .service(openai_models)
+            .service(openai_chat_completions)
+            .service(openai_completions)
     })
     .bind((hostname, port))?
     .run()
@@ -0,0 +1,191 @@
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use serde::Serialize;
+
+use crate::database::{Database, ConfigView, Device};
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+pub enum ModelState {
+    NotLoaded,
+    Loading,
+    Loaded,
+    Failed,
+}
+
+#[derive(Clone, PartialEq)]
+struct ModelKey {
+    llm_model_path: String,
+    device: Device,
+    gpu_layers: usize,
+    context_size: usize,
+}
+
+impl ModelKey {
+    fn from_config(config: &ConfigView) -> Self {
+        ModelKey {
+            llm_model_path: config.llm_model_path.clone(),
+            device: config.device.clone(),
+            gpu_layers: config.gpu_layers,
+            context_size: config.context_size,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ModelStatusView {
+    pub state: ModelState,
+    pub llm_model_path: Option<String>,
+    pub device: Option<Device>,
+    pub gpu_layers: Option<usize>,
+    pub load_time_ms: Option<u128>,
+    pub error: Option<String>,
+}
+
+struct LoadedModel {
+    state: ModelState,
+    key: Option<ModelKey>,
+    model: Option<Arc<dyn llm::Model>>,
+    // config from the latest request that used the model, without sampler overrides
+    config: Option<ConfigView>,
+    load_time_ms: Option<u128>,
+    error: Option<String>,
+}
+
+// keeps the gguf model in memory between prompts,
+// the model is loaded again only when path, device, gpu layers or context size in config change
+pub struct ModelManager {
+    loaded: Mutex<LoadedModel>,
+    load_lock: Mutex<()>,
+}
+
+impl ModelManager {
+    pub fn new() -> Self {
+        ModelManager {
+            loaded: Mutex::new(LoadedModel {
+                state: ModelState::NotLoaded,
+                key: None,
+                model: None,
+                config: None,
+                load_time_ms: None,
+                error: None,
+            }),
+            load_lock: Mutex::new(()),
+        }
+    }
+
+    pub fn get(&self) -> Result<Arc<dyn llm::Model>, std::io::Error> {
+        let config: ConfigView = match Database::get_config() {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("Error while getting config: {}", e);
+                return Err(std::io::Error::other("Error while getting config"));
+            }
+        };
+        self.get_for_config(&config)
+    }
+
+    pub fn get_for_config(&self, config: &ConfigView) -> Result<Arc<dyn llm::Model>, std::io::Error> {
+        let key = ModelKey::from_config(config);
+        if let Some(model) = self.cached(&key, config) {
+            return Ok(model);
+        }
+        // only one thread loads at a time, others wait and then reuse the loaded model
+        let _guard = self.load_lock.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(model) = self.cached(&key, config) {
+            return Ok(model);
+        }
+        {
+            let mut loaded = self.lock_loaded();
+            loaded.state = ModelState::Loading;
+            loaded.key = Some(key.clone());
+            loaded.model = None;
+            loaded.config = None;
+            loaded.load_time_ms = None;
+            loaded.error = None;
+        }
+        println!("Loading llm model from \"{}\"...", key.llm_model_path);
+        let start = Instant::now();
+        let llama = llm::load(
+            std::path::Path::new(&key.llm_model_path),
+            llm::TokenizerSource::Embedded,
+            model_parameters(config),
+            llm::load_progress_callback_stdout,
+        );
+        let mut loaded = self.lock_loaded();
+        match llama {
+            Ok(llama) => {
+                let model: Arc<dyn llm::Model> = Arc::from(llama);
+                loaded.state = ModelState::Loaded;
+                loaded.model = Some(model.clone());
+                loaded.config = Some(config.clone());
+                loaded.load_time_ms = Some(start.elapsed().as_millis());
+                println!("Model loaded in {} ms", start.elapsed().as_millis());
+                Ok(model)
+            }
+            Err(e) => {
+                loaded.state = ModelState::Failed;
+                loaded.error = Some(e.to_string());
+                Err(std::io::Error::other(format!("Failed to load llm model: {}", e)))
+            }
+        }
+    }
+
+    // model that is already loaded and the config it was last used with, config is not read from database
+    pub fn get_loaded(&self) -> Option<(Arc<dyn llm::Model>, ConfigView)> {
+        let loaded = self.lock_loaded();
+        if loaded.state != ModelState::Loaded {
+            return None;
+        }
+        match (&loaded.model, &loaded.config) {
+            (Some(model), Some(config)) => Some((model.clone(), config.clone())),
+            _ => None,
+        }
+    }
+
+    // loads the model in the background if settings in config differ from the loaded ones
+    pub fn reload_in_background(manager: Arc<ModelManager>) {
+        std::thread::spawn(move || {
+            if let Err(e) = manager.get() {
+                eprintln!("⚠️ {}\n", e);
+            }
+        });
+    }
+
+    pub fn status(&self) -> ModelStatusView {
+        let loaded = self.lock_loaded();
+        ModelStatusView {
+            state: loaded.state,
+            llm_model_path: loaded.key.as_ref().map(|k| k.llm_model_path.clone()),
+            device: loaded.key.as_ref().map(|k| k.device.clone()),
+            gpu_layers: loaded.key.as_ref().map(|k| k.gpu_layers),
+            load_time_ms: loaded.load_time_ms,
+            error: loaded.error.clone(),
+        }
+    }
+
+    fn cached(&self, key: &ModelKey, config: &ConfigView) -> Option<Arc<dyn llm::Model>> {
+        let mut loaded = self.lock_loaded();
+        if loaded.state == ModelState::Loaded && loaded.key.as_ref() == Some(key) {
+            loaded.config = Some(config.clone());
+            return loaded.model.clone();
+        }
+        None
+    }
+
+    fn lock_loaded(&self) -> std::sync::MutexGuard<'_, LoadedModel> {
+        self.loaded.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn model_parameters(config: &ConfigView) -> llm::ModelParameters {
+    let mut params = llm::ModelParameters::default();
+    if config.device == Device::GPU || config.device == Device::Metal {
+        params.use_gpu = true;
+        params.gpu_layers = Some(config.gpu_layers);
+    } else {
+        params.use_gpu = false;
+        params.gpu_layers = None;
+    }
+    params.context_size = config.context_size;
+    params
+}
@@ -0,0 +1,375 @@
+use std::sync::atomic::AtomicBool;
+use serde::{Serialize, Deserialize};
+use rand::Rng;
+use rand::distributions::Alphanumeric;
+
+use crate::database::{Database, ConfigView};
+use crate::llm::{generate, template_format, Generation};
+use crate::prompt_template::TemplateFormat;
+use crate::model_manager::ModelManager;
+
+//              Requests
+
+#[derive(Deserialize)]
+pub struct ContentPart {
+    #[serde(rename = "type")]
+    pub part_type: String,
+    pub text: Option<String>,
+}
+
+// content can be a string or a list of content parts, only text parts are used
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum ChatContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+impl ChatContent {
+    pub fn text(&self) -> String {
+        match self {
+            ChatContent::Text(t) => t.clone(),
+            ChatContent::Parts(parts) => parts.iter()
+                .filter(|p| p.part_type == "text")
+                .filter_map(|p| p.text.clone())
+                .collect::<Vec<String>>()
+                .join("\n"),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: Option<ChatContent>,
+    pub name: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl OneOrMany {
+    pub fn into_vec(self) -> Vec<String> {
+        match self {
+            OneOrMany::One(s) => vec![s],
+            OneOrMany::Many(v) => v,
+        }
+    }
+}
+
+// "model" and other unknown fields are ignored, there is only one loaded model
+#[derive(Deserialize)]
+pub struct ChatCompletionRequest {
+    pub messages: Vec<ChatMessage>,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_tokens: Option<usize>,
+    pub stream: Option<bool>,
+    pub stop: Option<OneOrMany>,
+    pub seed: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct CompletionRequest {
+    pub prompt: OneOrMany,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_tokens: Option<usize>,
+    pub stream: Option<bool>,
+    pub stop: Option<OneOrMany>,
+    pub seed: Option<i64>,
+}
+
+//              Responses
+
+#[derive(Serialize)]
+pub struct Usage {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub total_tokens: usize,
+}
+
+impl Usage {
+    pub fn from_generation(generation: &Generation) -> Self {
+        Usage {
+            prompt_tokens: generation.prompt_tokens,
+            completion_tokens: generation.completion_tokens,
+            total_tokens: generation.prompt_tokens + generation.completion_tokens,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ChatResponseMessage {
+    pub role: &'static str,
+    pub content: String,
+}
+
+#[derive(Serialize)]
+pub struct ChatChoice {
+    pub index: usize,
+    pub message: ChatResponseMessage,
+    pub finish_reason: &'static str,
+}
+
+#[derive(Serialize)]
+pub struct ChatCompletionResponse {
+    pub id: String,
+    pub object: &'static str,
+    pub created: i64,
+    pub model: String,
+    pub choices: Vec<ChatChoice>,
+    pub usage: Usage,
+}
+
+#[derive(Serialize)]
+pub struct ChatDelta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ChatChunkChoice {
+    pub index: usize,
+    pub delta: ChatDelta,
+    pub finish_reason: Option<&'static str>,
+}
+
+#[derive(Serialize)]
+pub struct ChatCompletionChunk {
+    pub id: String,
+    pub object: &'static str,
+    pub created: i64,
+    pub model: String,
+    pub choices: Vec<ChatChunkChoice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+}
+
+#[derive(Serialize)]
+pub struct CompletionChoice {
+    pub text: String,
+    pub index: usize,
+    pub logprobs: Option<()>,
+    pub finish_reason: Option<&'static str>,
+}
+
+#[derive(Serialize)]
+pub struct CompletionResponse {
+    pub id: String,
+    pub object: &'static str,
+    pub created: i64,
+    pub model: String,
+    pub choices: Vec<CompletionChoice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+}
+
+#[derive(Serialize)]
+pub struct ModelObject {
+    pub id: String,
+    pub object: &'static str,
+    pub created: i64,
+    pub owned_by: &'static str,
+}
+
+#[derive(Serialize)]
+pub struct ModelList {
+    pub object: &'static str,
+    pub data: Vec<ModelObject>,
+}
+
+#[derive(Serialize)]
+pub struct ErrorDetail {
+    pub message: String,
+    #[serde(rename = "type")]
+    pub error_type: &'static str,
+}
+
+#[derive(Serialize)]
+pub struct ErrorResponse {
+    pub error: ErrorDetail,
+}
+
+impl ErrorResponse {
+    pub fn new(message: &str, error_type: &'static str) -> Self {
+        ErrorResponse {
+            error: ErrorDetail { message: message.to_string(), error_type },
+        }
+    }
+}
+
+//              Generation
+
+pub fn completion_id(prefix: &str) -> String {
+    let suffix: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(24)
+        .map(char::from)
+        .collect();
+    format!("{}-{}", prefix, suffix)
+}
+
+pub fn created_timestamp() -> i64 {
+    chrono::Utc::now().timestamp()
+}
+
+pub fn finish_reason(generation: &Generation) -> &'static str {
+    if generation.reached_token_limit { "length" } else { "stop" }
+}
+
+// name of the loaded gguf file is used as model id, "model" field in requests is ignored
+pub fn model_id(config: &ConfigView) -> String {
+    std::path::Path::new(&config.llm_model_path)
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or(String::from("ai-companion"))
+}
+
+pub fn current_model_id() -> String {
+    match Database::get_config() {
+        Ok(config) => model_id(&config),
+        Err(_) => String::from("ai-companion"),
+    }
+}
+
+pub fn models() -> Result<ModelList, std::io::Error> {
+    let config = get_config()?;
+    let created = std::fs::metadata(&config.llm_model_path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    Ok(ModelList {
+        object: "list",
+        data: vec![ModelObject {
+            id: model_id(&config),
+            object: "model",
+            created,
+            owned_by: "ai-companion",
+        }],
+    })
+}
+
+// name of the first message with given role that has one, or the default name
+fn speaker_name<'a>(messages: &'a [ChatMessage], role: &str, default: &'a str) -> &'a str {
+    messages.iter()
+        .filter(|m| m.role == role)
+        .filter_map(|m| m.name.as_deref())
+        .map(str::trim)
+        .find(|n| !n.is_empty())
+        .unwrap_or(default)
+}
+
+// user and assistant names from "name" fields of messages, "User" and "Assistant" if not given
+pub fn chat_names(messages: &[ChatMessage]) -> (&str, &str) {
+    (speaker_name(messages, "user", "User"), speaker_name(messages, "assistant", "Assistant"))
+}
+
+// messages are formatted with the prompt template from config, "system" messages become the system block
+pub fn chat_prompt(template: &TemplateFormat, messages: &[ChatMessage]) -> String {
+    let (user_name, companion_name) = chat_names(messages);
+    let system: Vec<String> = messages.iter()
+        .filter(|m| m.role == "system" || m.role == "developer")
+        .map(|m| m.content.as_ref().map(|c| c.text()).unwrap_or_default())
+        .collect();
+    let mut prompt = String::new();
+    if !system.is_empty() {
+        prompt += &template.render_system(&system.join("\n"), user_name, companion_name);
+    }
+    for message in messages.iter().filter(|m| m.role == "user" || m.role == "assistant") {
+        let text = message.content.as_ref().map(|c| c.text()).unwrap_or_default();
+        let is_ai = message.role == "assistant";
+        let name = match message.name.as_deref().map(str::trim) {
+            Some(name) if !name.is_empty() => name,
+            _ if is_ai => companion_name,
+            _ => user_name,
+        };
+        let (user, companion) = if is_ai { (user_name, name) } else { (name, companion_name) };
+        prompt += &template.render_message(is_ai, &text, user, companion);
+    }
+    prompt += &template.generation_prefix(user_name, companion_name);
+    prompt
+}
+
+// stop sequences made of names are only used when the template writes names in turns,
+// otherwise text like "User:" in a normal answer would end generation
+fn chat_stop_sequences(template: &TemplateFormat, messages: &[ChatMessage], stop: Option<OneOrMany>) -> Vec<String> {
+    let mut stop_sequences = if template.uses_names() {
+        let (user_name, companion_name) = chat_names(messages);
+        template.stop_sequences(user_name, companion_name)
+    } else {
+        template.marker_stop_sequences()
+    };
+    if let Some(stop) = stop {
+        stop_sequences.extend(stop.into_vec().into_iter().filter(|s| !s.is_empty()));
+    }
+    stop_sequences
+}
+
+fn get_template(config: &ConfigView) -> Result<TemplateFormat, std::io::Error> {
+    match template_format(config) {
+        Ok(template) => Ok(template),
+        Err(e) => {
+            eprintln!("Error while getting prompt template: {}", e);
+            Err(std::io::Error::other("Error while getting prompt template"))
+        }
+    }
+}
+
+fn get_config() -> Result<ConfigView, std::io::Error> {
+    match Database::get_config() {
+        Ok(config) => Ok(config),
+        Err(e) => {
+            eprintln!("Error while getting config: {}", e);
+            Err(std::io::Error::other("Error while getting config"))
+        }
+    }
+}
+
+// sampler settings from request override the ones from config
+pub fn apply_overrides(config: &mut ConfigView, temperature: Option<f32>, top_p: Option<f32>, max_tokens: Option<usize>, seed: Option<i64>) {
+    if let Some(t) = temperature {
+        config.temperature = t;
+    }
+    if let Some(p) = top_p {
+        config.top_p = p;
+    }
+    if let Some(m) = max_tokens {
+        config.max_new_tokens = m;
+    }
+    if let Some(s) = seed {
+        config.seed = s;
+    }
+}
+
+pub fn chat_completion(request: ChatCompletionRequest, model_manager: &ModelManager, cancel: &AtomicBool, on_token: impl FnMut(&str)) -> Result<(Generation, String), std::io::Error> {
+    let mut config = get_config()?;
+    let llama = model_manager.get_for_config(&config)?;
+    apply_overrides(&mut config, request.temperature, request.top_p, request.max_tokens, request.seed);
+    let template = get_template(&config)?;
+    let prompt_text = chat_prompt(&template, &request.messages);
+    let stop_sequences = chat_stop_sequences(&template, &request.messages, request.stop);
+    let generation = generate(llama.as_ref(), &config, &prompt_text, &stop_sequences, cancel, on_token)?;
+    Ok((generation, model_id(&config)))
+}
+
+pub fn text_completion(request: CompletionRequest, model_manager: &ModelManager, cancel: &AtomicBool, on_token: impl FnMut(&str)) -> Result<(Generation, String), std::io::Error> {
+    let mut config = get_config()?;
+    let llama = model_manager.get_for_config(&config)?;
+    apply_overrides(&mut config, request.temperature, request.top_p, request.max_tokens, request.seed);
+    let prompt_text = request.prompt.into_vec().join("\n");
+    let stop_sequences: Vec<String> = request.stop.map(|s| s.into_vec()).unwrap_or_default()
+        .into_iter()
+        .filter(|s| !s.is_empty())
+        .collect();
+    let generation = generate(llama.as_ref(), &config, &prompt_text, &stop_sequences, cancel, on_token)?;
+    Ok((generation, model_id(&config)))
+}
@@ -0,0 +1,301 @@
+use serde::{Serialize, Deserialize};
+
+// parts of a prompt template, "{{user}}" and "{{char}}" are replaced with names in every part
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TemplateFormat {
+    // "{{system}}" is replaced with persona of the companion or with system messages
+    pub system_block: String,
+    // system block of companion prompts with its own layout, made of "{{user_persona}}", "{{char_persona}}", "{{roleplay}}",
+    // "{{known_facts}}", "{{example_dialogue}}" and "{{tuned_dialogue}}". when empty, system_block gets all of them in "{{system}}"
+    #[serde(default)]
+    pub companion_block: String,
+    // "{{message}}" is replaced with content of the message, "{{time}}" with a note of the current date
+    // when the last message asks for it, turns without "{{time}}" get the note at the beginning of the message
+    pub user_turn: String,
+    pub ai_turn: String,
+    // "{{memory}}" is replaced with a long-term memory entry
+    pub memory_entry: String,
+    pub stop_sequences: Vec<String>,
+}
+
+// parts of the system block of a companion prompt, names are already substituted
+pub struct SystemParts<'a> {
+    pub user_persona: &'a str,
+    pub companion_persona: &'a str,
+    pub roleplay: bool,
+    // empty when nothing is known
+    pub known_facts: &'a str,
+    pub example_dialogue: &'a str,
+    pub tuned_dialogue: &'a str,
+}
+
+const ROLEPLAY_NOTE: &str = "gestures and other non-verbal actions are written between asterisks (for example, *waves hello* or *moves closer*)";
+
+// replaces every "{{key}}" in one pass, so placeholders inside the values are left as they are
+fn fill(text: &str, values: &[(&str, &str)]) -> String {
+    let mut filled = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        filled.push_str(&rest[..start]);
+        let tail = &rest[start + 2..];
+        match values.iter().find(|(key, _)| tail.starts_with(key) && tail[key.len()..].starts_with("}}")) {
+            Some((key, value)) => {
+                filled.push_str(value);
+                rest = &tail[key.len() + 2..];
+            }
+            None => {
+                filled.push_str("{{");
+                rest = tail;
+            }
+        }
+    }
+    filled.push_str(rest);
+    filled
+}
+
+impl TemplateFormat {
+    fn with_names(text: &str, user_name: &str, companion_name: &str) -> String {
+        text.replace("{{user}}", user_name).replace("{{char}}", companion_name)
+    }
+
+    pub fn render_system(&self, system: &str, user_name: &str, companion_name: &str) -> String {
+        TemplateFormat::with_names(&self.system_block, user_name, companion_name).replace("{{system}}", system)
+    }
+
+    pub fn render_companion_system(&self, parts: &SystemParts, user_name: &str, companion_name: &str) -> String {
+        let roleplay = if parts.roleplay { ROLEPLAY_NOTE } else { "" };
+        let known_facts = if parts.known_facts.is_empty() { String::new() } else { format!("\n{}", parts.known_facts) };
+        let system = format!("Text transcript of a conversation between {} and {}. {}\n{}'s Persona: {}\n{}'s Persona: {}{}\n{}\n{}",
+            user_name, companion_name, roleplay, user_name, parts.user_persona, companion_name, parts.companion_persona, known_facts, parts.example_dialogue, parts.tuned_dialogue);
+        let block = if self.companion_block.is_empty() { &self.system_block } else { &self.companion_block };
+        fill(block, &[
+            ("user", user_name),
+            ("char", companion_name),
+            ("system", &system),
+            ("user_persona", parts.user_persona),
+            ("char_persona", parts.companion_persona),
+            ("roleplay", roleplay),
+            ("known_facts", &known_facts),
+            ("example_dialogue", parts.example_dialogue),
+            ("tuned_dialogue", parts.tuned_dialogue),
+        ])
+    }
+
+    pub fn render_message(&self, ai: bool, message: &str, user_name: &str, companion_name: &str) -> String {
+        let turn = if ai { &self.ai_turn } else { &self.user_turn };
+        TemplateFormat::with_names(turn, user_name, companion_name).replace("{{time}}", "").replace("{{message}}", message)
+    }
+
+    // message with a note of the current date, for messages that ask about time
+    pub fn render_message_at(&self, ai: bool, message: &str, date: &str, user_name: &str, companion_name: &str) -> String {
+        let turn = TemplateFormat::with_names(if ai { &self.ai_turn } else { &self.user_turn }, user_name, companion_name);
+        if turn.contains("{{time}}") {
+            turn.replace("{{time}}", &format!("\n* it's currently {} *\n", date)).replace("{{message}}", message)
+        } else {
+            turn.replace("{{message}}", &format!("* it's currently {} *\n{}", date, message))
+        }
+    }
+
+    pub fn render_memory(&self, memory: &str, user_name: &str, companion_name: &str) -> String {
+        TemplateFormat::with_names(&self.memory_entry, user_name, companion_name).replace("{{memory}}", memory)
+    }
+
+    fn turn_prefix(turn: &str, user_name: &str, companion_name: &str) -> String {
+        let prefix = turn.split("{{message}}").next().unwrap_or("");
+        TemplateFormat::with_names(prefix, user_name, companion_name).replace("{{time}}", "")
+    }
+
+    // beginning of the ai turn, the model generates the rest of it
+    pub fn generation_prefix(&self, user_name: &str, companion_name: &str) -> String {
+        TemplateFormat::turn_prefix(&self.ai_turn, user_name, companion_name)
+    }
+
+    // beginning of the user turn, used when the model writes as the user
+    pub fn user_prefix(&self, user_name: &str, companion_name: &str) -> String {
+        TemplateFormat::turn_prefix(&self.user_turn, user_name, companion_name)
+    }
+
+    pub fn stop_sequences(&self, user_name: &str, companion_name: &str) -> Vec<String> {
+        self.stop_sequences.iter()
+            .map(|s| TemplateFormat::with_names(s, user_name, companion_name))
+            .filter(|s| !s.is_empty())
+            .collect()
+    }
+
+    // true if turns are written as "name: message", then names can be used as stop sequences
+    pub fn uses_names(&self) -> bool {
+        [&self.user_turn, &self.ai_turn].iter()
+            .any(|turn| turn.contains("{{user}}") || turn.contains("{{char}}"))
+    }
+
+    // stop sequences without the ones made of names, for templates with role markers instead of names
+    pub fn marker_stop_sequences(&self) -> Vec<String> {
+        self.stop_sequences.iter()
+            .filter(|s| !s.contains("{{user}}") && !s.contains("{{char}}"))
+            .filter(|s| !s.is_empty())
+            .cloned()
+            .collect()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.user_turn.contains("{{message}}") || !self.ai_turn.contains("{{message}}") {
+            return Err(String::from("user_turn and ai_turn must contain {{message}}"));
+        }
+        Ok(())
+    }
+}
+
+pub struct Preset {
+    pub name: &'static str,
+    pub system_block: &'static str,
+    pub companion_block: &'static str,
+    pub user_turn: &'static str,
+    pub ai_turn: &'static str,
+    pub memory_entry: &'static str,
+    pub stop_sequences: &'static [&'static str],
+}
+
+impl Preset {
+    pub fn format(&self) -> TemplateFormat {
+        TemplateFormat {
+            system_block: self.system_block.to_string(),
+            companion_block: self.companion_block.to_string(),
+            user_turn: self.user_turn.to_string(),
+            ai_turn: self.ai_turn.to_string(),
+            memory_entry: self.memory_entry.to_string(),
+            stop_sequences: self.stop_sequences.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+}
+
+// built-in templates, stored in database with the same name as their PromptTemplate value
+pub const PRESETS: &[Preset] = &[
+    Preset {
+        name: "Default",
+        system_block: "{{system}}\n<START>\n",
+        companion_block: "Text transcript of a conversation between {{user}} and {{char}}. {{roleplay}}\n{{user}}'s Persona: {{user_persona}}\n{{char}}'s Persona: {{char_persona}}{{known_facts}}\n<START>\n{{example_dialogue}}\n<START>\n{{tuned_dialogue}}\n<START>\n",
+        user_turn: "{{time}}{{user}}: {{message}}\n",
+        ai_turn: "{{time}}{{char}}: {{message}}\n",
+        memory_entry: "{{memory}}\n",
+        stop_sequences: &["\n{{user}}:", "{{user}}:", "{{char}}:", "[/INST]", "<</SYS>>", "[s]", "<|user|>"],
+    },
+    Preset {
+        name: "Llama2",
+        system_block: "<<SYS>>\n{{system}}\n",
+        companion_block: "<<SYS>>\nYou are {{char}}, {{char_persona}}\nyou are talking with {{user}}, {{user}} is {{user_persona}}{{known_facts}}\n{{roleplay}}\n[INST]\n{{example_dialogue}}\n{{tuned_dialogue}}\n[/INST]",
+        user_turn: "[INST]{{time}}{{user}}: {{message}}\n",
+        ai_turn: "{{time}}{{char}}: {{message}}\n[/INST]\n",
+        memory_entry: "[INST]{{memory}}\n[/INST]\n",
+        stop_sequences: &["\n{{user}}:", "{{user}}:", "{{char}}:", "[/INST]", "<</SYS>>", "[s]", "<|user|>"],
+    },
+    Preset {
+        name: "Mistral",
+        system_block: "<s>[INST]{{system}}[/INST]\n",
+        companion_block: "<s>[INST]Text transcript of a conversation between {{user}} and {{char}}. {{roleplay}}\n{{user}}'s Persona: {{user_persona}}\n{{char}}'s Persona: {{char_persona}}{{known_facts}}[/INST]\n<s>[INST]\n{{example_dialogue}}[/INST]\n<s>[INST]\n{{tuned_dialogue}}\n[/INST]\n",
+        user_turn: "<s>[INST]{{time}}{{user}}: {{message}}\n",
+        ai_turn: "{{time}}{{char}}: {{message}}\n[/INST]\n",
+        memory_entry: "<s>[INST]{{memory}}\n[/INST]\n",
+        stop_sequences: &["\n{{user}}:", "{{user}}:", "{{char}}:", "[/INST]", "<</SYS>>", "[s]", "<|user|>"],
+    },
+    Preset {
+        name: "ChatML",
+        system_block: "<|im_start|>system\n{{system}}<|im_end|>\n",
+        companion_block: "",
+        user_turn: "<|im_start|>user\n{{message}}<|im_end|>\n",
+        ai_turn: "<|im_start|>assistant\n{{message}}<|im_end|>\n",
+        memory_entry: "<|im_start|>system\n{{memory}}<|im_end|>\n",
+        stop_sequences: &["<|im_end|>", "<|im_start|>", "\n{{user}}:", "{{user}}:", "{{char}}:"],
+    },
+    Preset {
+        name: "Llama3",
+        system_block: "<|start_header_id|>system<|end_header_id|>\n\n{{system}}<|eot_id|>",
+        companion_block: "",
+        user_turn: "<|start_header_id|>user<|end_header_id|>\n\n{{message}}<|eot_id|>",
+        ai_turn: "<|start_header_id|>assistant<|end_header_id|>\n\n{{message}}<|eot_id|>",
+        memory_entry: "<|start_header_id|>system<|end_header_id|>\n\n{{memory}}<|eot_id|>",
+        stop_sequences: &["<|eot_id|>", "<|start_header_id|>", "<|end_of_text|>", "\n{{user}}:", "{{user}}:", "{{char}}:"],
+    },
+    Preset {
+        name: "Alpaca",
+        system_block: "{{system}}\n\n",
+        companion_block: "",
+        user_turn: "### Instruction:\n{{message}}\n\n",
+        ai_turn: "### Response:\n{{message}}\n\n",
+        memory_entry: "{{memory}}\n\n",
+        stop_sequences: &["### Instruction:", "### Response:", "### Input:", "\n{{user}}:", "{{user}}:", "{{char}}:"],
+    },
+    Preset {
+        name: "Vicuna",
+        system_block: "{{system}}\n\n",
+        companion_block: "",
+        user_turn: "USER: {{message}}\n",
+        ai_turn: "ASSISTANT: {{message}}</s>\n",
+        memory_entry: "{{memory}}\n\n",
+        stop_sequences: &["</s>", "USER:", "ASSISTANT:", "\n{{user}}:", "{{user}}:", "{{char}}:"],
+    },
+    Preset {
+        name: "Zephyr",
+        system_block: "<|system|>\n{{system}}</s>\n",
+        companion_block: "",
+        user_turn: "<|user|>\n{{message}}</s>\n",
+        ai_turn: "<|assistant|>\n{{message}}</s>\n",
+        memory_entry: "<|system|>\n{{memory}}</s>\n",
+        stop_sequences: &["</s>", "<|user|>", "<|system|>", "<|assistant|>", "\n{{user}}:", "{{user}}:", "{{char}}:"],
+    },
+    // gemma has no system role, system text is sent as a user turn
+    Preset {
+        name: "Gemma",
+        system_block: "<start_of_turn>user\n{{system}}<end_of_turn>\n",
+        companion_block: "",
+        user_turn: "<start_of_turn>user\n{{message}}<end_of_turn>\n",
+        ai_turn: "<start_of_turn>model\n{{message}}<end_of_turn>\n",
+        memory_entry: "<start_of_turn>user\n{{memory}}<end_of_turn>\n",
+        stop_sequences: &["<end_of_turn>", "<start_of_turn>", "\n{{user}}:", "{{user}}:", "{{char}}:"],
+    },
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format_with_turns(user_turn: &str, ai_turn: &str) -> TemplateFormat {
+        TemplateFormat {
+            system_block: "{{system}}\n".to_string(),
+            companion_block: String::new(),
+            user_turn: user_turn.to_string(),
+            ai_turn: ai_turn.to_string(),
+            memory_entry: "{{memory}}\n".to_string(),
+            stop_sequences: vec!["</s>".to_string(), "{{user}}:".to_string(), String::new()],
+        }
+    }
+
+    #[test]
+    fn all_presets_are_valid() {
+        for preset in PRESETS {
+            assert!(preset.format().validate().is_ok(), "{} is not valid", preset.name);
+        }
+    }
+
+    #[test]
+    fn turns_without_message_placeholder_are_rejected() {
+        assert!(format_with_turns("{{user}}: {{message}}\n", "{{char}}: {{message}}\n").validate().is_ok());
+        assert!(format_with_turns("{{user}}:\n", "{{char}}: {{message}}\n").validate().is_err());
+        assert!(format_with_turns("{{user}}: {{message}}\n", "{{char}}: {{msg}}\n").validate().is_err());
+        assert!(format_with_turns("", "").validate().is_err());
+    }
+
+    #[test]
+    fn name_stop_sequences_only_for_templates_with_names_in_turns() {
+        let named = format_with_turns("{{user}}: {{message}}\n", "{{char}}: {{message}}\n");
+        assert!(named.uses_names());
+        assert_eq!(named.stop_sequences("Ann", "Bot"), vec!["</s>", "Ann:"]);
+        let markers = format_with_turns("<|user|>\n{{message}}</s>\n", "<|assistant|>\n{{message}}</s>\n");
+        assert!(!markers.uses_names());
+        assert_eq!(markers.marker_stop_sequences(), vec!["</s>"]);
+    }
+
+    #[test]
+    fn placeholders_in_values_are_not_replaced() {
+        assert_eq!(fill("{{a}} and {{b}} and {{c}}", &[("a", "{{b}}"), ("b", "x")]), "{{b}} and x and {{c}}");
+    }
+}
@@ -0,0 +1,123 @@
+use std::sync::atomic::AtomicBool;
+use serde::{Serialize, Deserialize};
+
+use crate::context::{assemble, ContextReport, PromptParts};
+use crate::database::NewMessage;
+use crate::llm::{count_tokens, generate, InferenceStatsView};
+use crate::model_manager::ModelManager;
+use crate::openai::apply_overrides;
+use crate::prompt_template::{TemplateFormat, PRESETS};
+
+// generation from a history sent by the client, nothing is read from or written to
+// the database or long-term memory, only the loaded model and its config are used
+
+#[derive(Deserialize)]
+pub struct StatelessCompanion {
+    pub name: String,
+    pub persona: String,
+    pub example_dialogue: Option<String>,
+    pub roleplay: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct StatelessUser {
+    pub name: String,
+    pub persona: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct GenerateRequest {
+    pub companion: StatelessCompanion,
+    pub user: StatelessUser,
+    // oldest first, the response is written by the companion after the last message
+    pub messages: Vec<NewMessage>,
+    // used in place of long-term memory entries, most relevant first
+    pub memories: Option<Vec<String>>,
+    // name of a built-in template, ignored if template is set
+    pub prompt_template: Option<String>,
+    pub template: Option<TemplateFormat>,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_tokens: Option<usize>,
+    pub seed: Option<i64>,
+    pub stop: Option<Vec<String>>,
+}
+
+#[derive(Serialize)]
+pub struct GenerateResponse {
+    pub content: String,
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub reached_token_limit: bool,
+    pub cancelled: bool,
+    pub stats: Option<InferenceStatsView>,
+    pub context: ContextReport,
+}
+
+fn invalid_input(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, message)
+}
+
+fn template_format(request: &GenerateRequest) -> Result<TemplateFormat, std::io::Error> {
+    if let Some(template) = &request.template {
+        template.validate().map_err(|e| invalid_input(&e))?;
+        return Ok(template.clone());
+    }
+    let name = request.prompt_template.as_deref().unwrap_or("Default");
+    match PRESETS.iter().find(|p| p.name.eq_ignore_ascii_case(name)) {
+        Some(preset) => Ok(preset.format()),
+        None => Err(invalid_input(&format!("Unknown prompt template \"{}\"", name))),
+    }
+}
+
+// NotFound if no model is loaded yet, the model is not loaded from here because that would need config from database
+pub fn generate_stateless(request: GenerateRequest, model_manager: &ModelManager, cancel: &AtomicBool, on_token: impl FnMut(&str)) -> Result<GenerateResponse, std::io::Error> {
+    if request.companion.name.trim().is_empty() || request.user.name.trim().is_empty() {
+        return Err(invalid_input("Names of the companion and the user can't be empty"));
+    }
+    let template = template_format(&request)?;
+    let (llama, mut config) = match model_manager.get_loaded() {
+        Some(loaded) => loaded,
+        None => return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "Model is not loaded")),
+    };
+    apply_overrides(&mut config, request.temperature, request.top_p, request.max_tokens, request.seed);
+    let user_name = request.user.name.clone();
+    let companion_name = request.companion.name.clone();
+    let with_names = |text: &str| text.replace("{{char}}", &companion_name).replace("{{user}}", &user_name);
+    let parts = PromptParts {
+        user_name: user_name.clone(),
+        companion_name: companion_name.clone(),
+        user_persona: with_names(request.user.persona.as_deref().unwrap_or("")),
+        companion_persona: with_names(&request.companion.persona),
+        roleplay: request.companion.roleplay.unwrap_or(false),
+        known_facts: String::new(),
+        example_dialogue: with_names(request.companion.example_dialogue.as_deref().unwrap_or("")),
+        tuned_dialogue: String::new(),
+        memories: request.memories.iter().flatten().map(|m| with_names(m.trim_end())).collect(),
+        messages: request.messages.iter().map(|m| (m.ai, m.content.clone())).collect(),
+        current_date: None,
+        reply_as_user: false,
+        prefill: String::new(),
+    };
+    let assembled = assemble(&template, parts, config.context_size, config.response_budget, |text| count_tokens(llama.as_ref(), text));
+    let mut stop_sequences = assembled.stop_sequences;
+    if let Some(stop) = request.stop {
+        stop_sequences.extend(stop.into_iter().filter(|s| !s.is_empty()));
+    }
+    let generation = generate(llama.as_ref(), &config, &assembled.text, &stop_sequences, cancel, on_token)?;
+    let content = generation.text
+        .split(&format!("\n{}: ", &companion_name))
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_string();
+    Ok(GenerateResponse {
+        content,
+        prompt_tokens: generation.prompt_tokens,
+        completion_tokens: generation.completion_tokens,
+        reached_token_limit: generation.reached_token_limit,
+        cancelled: generation.cancelled,
+        stats: generation.stats.map(InferenceStatsView::from),
+        context: assembled.report,
+    })
+}
@@ -0,0 +1,129 @@
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, TimeZone, Weekday};
+
+const WEEKDAYS: [(&str, Weekday); 7] = [
+    ("monday", Weekday::Mon),
+    ("tuesday", Weekday::Tue),
+    ("wednesday", Weekday::Wed),
+    ("thursday", Weekday::Thu),
+    ("friday", Weekday::Fri),
+    ("saturday", Weekday::Sat),
+    ("sunday", Weekday::Sun),
+];
+
+// small numbers only, larger ones would overflow date arithmetic
+fn number(word: &str) -> Option<i64> {
+    let words = ["one", "two", "three", "four", "five", "six", "seven", "eight", "nine", "ten"];
+    match word {
+        "a" | "an" => Some(1),
+        _ => word.parse().ok().filter(|n| (1..=1000).contains(n)).or_else(|| words.iter().position(|w| *w == word).map(|i| i as i64 + 1)),
+    }
+}
+
+fn start_of(date: NaiveDate) -> Option<DateTime<Local>> {
+    Local.from_local_datetime(&date.and_hms_opt(0, 0, 0)?).earliest()
+}
+
+fn days(from: NaiveDate, count: i64) -> Option<(DateTime<Local>, DateTime<Local>)> {
+    Some((start_of(from)?, start_of(from + Duration::days(count))?))
+}
+
+fn week_start(date: NaiveDate) -> NaiveDate {
+    date - Duration::days(date.weekday().num_days_from_monday() as i64)
+}
+
+// first day of the month that is `back` months before the month of date
+fn month_start(date: NaiveDate, back: i64) -> Option<NaiveDate> {
+    let months = date.year() as i64 * 12 + date.month0() as i64 - back;
+    NaiveDate::from_ymd_opt((months / 12) as i32, (months % 12) as u32 + 1, 1)
+}
+
+fn months(date: NaiveDate, back: i64) -> Option<(DateTime<Local>, DateTime<Local>)> {
+    Some((start_of(month_start(date, back)?)?, start_of(month_start(date, back - 1)?)?))
+}
+
+// start (inclusive) and end (exclusive) of the time the text asks about, for example
+// "yesterday", "last week", "on monday" or "3 days ago". None if there is no such expression
+pub fn date_range(text: &str, now: DateTime<Local>) -> Option<(DateTime<Local>, DateTime<Local>)> {
+    let lowercase = text.to_lowercase();
+    let words: Vec<&str> = lowercase.split(|c: char| !c.is_alphanumeric()).filter(|w| !w.is_empty()).collect();
+    let today = now.date_naive();
+    for (i, word) in words.iter().enumerate() {
+        let next = words.get(i + 1).copied().unwrap_or("");
+        let ago = words.get(i + 2).copied() == Some("ago");
+        let weekday = WEEKDAYS.iter().find(|(name, _)| *name == next).map(|(_, day)| *day);
+        let range = match (*word, next) {
+            ("today", _) | ("tonight", _) => days(today, 1),
+            ("this", "morning" | "afternoon" | "evening") => days(today, 1),
+            ("yesterday", _) => days(today - Duration::days(1), 1),
+            ("last", "night") => days(today - Duration::days(1), 2),
+            ("this", "week") => Some((start_of(week_start(today))?, start_of(today + Duration::days(1))?)),
+            ("last", "week") => days(week_start(today) - Duration::days(7), 7),
+            ("this", "month") => months(today, 0),
+            ("last", "month") => months(today, 1),
+            ("this", "year") => Some((start_of(NaiveDate::from_ymd_opt(today.year(), 1, 1)?)?, start_of(NaiveDate::from_ymd_opt(today.year() + 1, 1, 1)?)?)),
+            ("last", "year") => Some((start_of(NaiveDate::from_ymd_opt(today.year() - 1, 1, 1)?)?, start_of(NaiveDate::from_ymd_opt(today.year(), 1, 1)?)?)),
+            ("recently" | "lately", _) => days(today - Duration::days(6), 7),
+            // the latest past day with this name, a week ago if it's today
+            ("last" | "on", _) if weekday.is_some() => {
+                let back = (today.weekday().num_days_from_monday() as i64 - weekday?.num_days_from_monday() as i64).rem_euclid(7);
+                days(today - Duration::days(if back == 0 { 7 } else { back }), 1)
+            }
+            (n, "day" | "days") if ago => number(n).and_then(|n| days(today - Duration::days(n), 1)),
+            (n, "week" | "weeks") if ago => number(n).and_then(|n| days(week_start(today) - Duration::days(7 * n), 7)),
+            (n, "month" | "months") if ago => number(n).and_then(|n| months(today, n)),
+            _ => None,
+        };
+        if range.is_some() {
+            return range;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Monday
+    fn now() -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap()
+    }
+
+    fn range(text: &str) -> Option<(NaiveDate, NaiveDate)> {
+        date_range(text, now()).map(|(start, end)| (start.date_naive(), end.date_naive()))
+    }
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    #[test]
+    fn weekday_of_today_means_a_week_ago() {
+        assert_eq!(range("what did we do last Monday?"), Some((date(2023, 12, 25), date(2023, 12, 26))));
+        assert_eq!(range("on monday"), Some((date(2023, 12, 25), date(2023, 12, 26))));
+    }
+
+    #[test]
+    fn weekday_before_today() {
+        assert_eq!(range("last sunday"), Some((date(2023, 12, 31), date(2024, 1, 1))));
+        assert_eq!(range("on tuesday"), Some((date(2023, 12, 26), date(2023, 12, 27))));
+    }
+
+    #[test]
+    fn relative_days_weeks_and_months() {
+        assert_eq!(range("yesterday"), Some((date(2023, 12, 31), date(2024, 1, 1))));
+        assert_eq!(range("3 days ago"), Some((date(2023, 12, 29), date(2023, 12, 30))));
+        assert_eq!(range("last week"), Some((date(2023, 12, 25), date(2024, 1, 1))));
+        assert_eq!(range("two months ago"), Some((date(2023, 11, 1), date(2023, 12, 1))));
+        assert_eq!(range("last month"), Some((date(2023, 12, 1), date(2024, 1, 1))));
+        assert_eq!(range("this year"), Some((date(2024, 1, 1), date(2025, 1, 1))));
+    }
+
+    #[test]
+    fn text_without_time_expression() {
+        assert_eq!(range("I like the last song"), None);
+        assert_eq!(range("monday"), None);
+        assert_eq!(range("100000 days ago"), None);
+        assert_eq!(range(""), None);
+    }
+}